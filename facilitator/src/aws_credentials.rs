@@ -7,14 +7,54 @@ use rusoto_core::{
     },
 };
 use rusoto_sts::WebIdentityProvider;
-use std::{boxed::Box, time::Duration};
+use std::{boxed::Box, sync::Arc, time::Duration};
 use tokio::runtime::{Builder, Runtime};
 
+use crate::credentials::CredentialSource;
+
 /// Constructs a basic runtime suitable for use in our single threaded context
 pub(crate) fn basic_runtime() -> Result<Runtime> {
     Ok(Builder::new().basic_scheduler().enable_all().build()?)
 }
 
+/// Provides AWS credentials from a CredentialSource, if one is provided and it
+/// has AWS credentials, and from a DefaultCredentialsProvider otherwise.
+#[derive(Clone)]
+pub(crate) struct CredentialSourceProvider {
+    source: Option<Arc<dyn CredentialSource>>,
+    default_provider: DefaultCredentialsProvider,
+}
+
+impl CredentialSourceProvider {
+    pub(crate) fn new(
+        source: Option<Arc<dyn CredentialSource>>,
+    ) -> Result<CredentialSourceProvider, CredentialsError> {
+        Ok(CredentialSourceProvider {
+            source,
+            default_provider: DefaultCredentialsProvider::new()?,
+        })
+    }
+}
+
+#[async_trait]
+impl ProvideAwsCredentials for CredentialSourceProvider {
+    async fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        if let Some(source) = &self.source {
+            match source.aws_credentials() {
+                Ok(Some(credentials)) => return Ok(credentials),
+                Ok(None) => {}
+                Err(err) => {
+                    return Err(CredentialsError::new(format!(
+                        "failed to get credentials from {:?}: {:?}",
+                        source, err
+                    )))
+                }
+            }
+        }
+        self.default_provider.credentials().await
+    }
+}
+
 // ------------- Everything below here was copied from rusoto/credential/src/lib.rs in the rusoto repo ---------------------------
 // -------------------------------------------------------------------------------------------------------------------------------
 
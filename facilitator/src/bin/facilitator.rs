@@ -8,7 +8,7 @@ use ring::signature::{
     EcdsaKeyPair, KeyPair, UnparsedPublicKey, ECDSA_P256_SHA256_ASN1,
     ECDSA_P256_SHA256_ASN1_SIGNING,
 };
-use std::{collections::HashMap, fs, fs::File, io::Read, str::FromStr};
+use std::{collections::HashMap, fs, fs::File, io::Read, str::FromStr, time::Duration};
 use uuid::Uuid;
 
 use facilitator::{
@@ -20,10 +20,13 @@ use facilitator::{
         SpecificManifest,
     },
     sample::{generate_ingestion_sample, SampleOutput},
-    task::{AggregationTask, AwsSqsTaskQueue, GcpPubSubTaskQueue, IntakeBatchTask, TaskQueue},
+    task::{
+        AggregationTask, AwsSqsTaskQueue, AwsSqsTaskQueueOptions, GcpPubSubTaskQueue,
+        IntakeBatchTask, TaskQueue,
+    },
     transport::{
         GCSTransport, LocalFileTransport, S3Transport, SignableTransport, Transport,
-        VerifiableAndDecryptableTransport, VerifiableTransport,
+        TransportTimeouts, VerifiableAndDecryptableTransport, VerifiableTransport,
     },
     BatchSigningKey, DATE_FORMAT,
 };
@@ -69,6 +72,8 @@ trait AppArgumentAdder {
 
     fn add_gcp_service_account_key_file_argument(self: Self) -> Self;
 
+    fn add_gcs_timeout_arguments(self: Self) -> Self;
+
     fn add_task_queue_arguments(self: Self) -> Self;
 }
 
@@ -314,6 +319,34 @@ impl<'a, 'b> AppArgumentAdder for App<'a, 'b> {
         )
     }
 
+    fn add_gcs_timeout_arguments(self: App<'a, 'b>) -> App<'a, 'b> {
+        self.arg(
+            Arg::with_name("gcs-connect-timeout-seconds")
+                .long("gcs-connect-timeout-seconds")
+                .env("GCS_CONNECT_TIMEOUT_SECONDS")
+                .value_name("SECONDS")
+                .validator(num_validator::<u64>)
+                .help("How long requests to GCS wait to connect")
+                .long_help(
+                    "How long each request to GCS waits to connect before \
+                    failing. Defaults to 10 seconds.",
+                ),
+        )
+        .arg(
+            Arg::with_name("gcs-read-timeout-seconds")
+                .long("gcs-read-timeout-seconds")
+                .env("GCS_READ_TIMEOUT_SECONDS")
+                .value_name("SECONDS")
+                .validator(num_validator::<u64>)
+                .help("How long requests to GCS wait for each read")
+                .long_help(
+                    "How long each request to GCS waits for each read of its \
+                    response, including the chunks of uploads, before failing. \
+                    Defaults to 10 seconds.",
+                ),
+        )
+    }
+
     fn add_task_queue_arguments(self: App<'a, 'b>) -> App<'a, 'b> {
         self.arg(
             Arg::with_name("task-queue-kind")
@@ -391,6 +424,7 @@ fn main() -> Result<(), anyhow::Error> {
             SubCommand::with_name("generate-ingestion-sample")
                 .about("Generate sample data files")
                 .add_gcp_service_account_key_file_argument()
+                .add_gcs_timeout_arguments()
                 .add_storage_arguments(Entity::Peer, InOut::Output)
                 .add_storage_arguments(Entity::Own, InOut::Output)
                 .arg(
@@ -500,6 +534,7 @@ fn main() -> Result<(), anyhow::Error> {
                 .add_instance_name_argument()
                 .add_is_first_argument()
                 .add_gcp_service_account_key_file_argument()
+                .add_gcs_timeout_arguments()
                 .arg(
                     Arg::with_name("aggregation-id")
                         .long("aggregation-id")
@@ -538,6 +573,7 @@ fn main() -> Result<(), anyhow::Error> {
                 .add_instance_name_argument()
                 .add_is_first_argument()
                 .add_gcp_service_account_key_file_argument()
+                .add_gcs_timeout_arguments()
                 .arg(
                     Arg::with_name("aggregation-id")
                         .long("aggregation-id")
@@ -657,6 +693,7 @@ fn main() -> Result<(), anyhow::Error> {
                 .add_instance_name_argument()
                 .add_is_first_argument()
                 .add_gcp_service_account_key_file_argument()
+                .add_gcs_timeout_arguments()
                 .add_packet_decryption_key_argument()
                 .add_batch_public_key_arguments(Entity::Ingestor)
                 .add_batch_signing_key_arguments()
@@ -673,6 +710,7 @@ fn main() -> Result<(), anyhow::Error> {
                 .add_instance_name_argument()
                 .add_is_first_argument()
                 .add_gcp_service_account_key_file_argument()
+                .add_gcs_timeout_arguments()
                 .add_manifest_base_url_argument(Entity::Ingestor)
                 .add_storage_arguments(Entity::Ingestor, InOut::Input)
                 .add_batch_public_key_arguments(Entity::Ingestor)
@@ -1268,11 +1306,39 @@ fn transport_for_path(
             path,
             identity,
             key_file_reader,
+            None,
+            gcs_timeouts(matches)?,
         )?)),
         StoragePath::LocalPath(path) => Ok(Box::new(LocalFileTransport::new(path))),
     }
 }
 
+/// Returns the timeouts for requests to GCS set with --gcs-connect-timeout-seconds
+/// or --gcs-read-timeout-seconds, or None if neither was set.
+fn gcs_timeouts(matches: &ArgMatches) -> Result<Option<TransportTimeouts>> {
+    let seconds = |name: &str| -> Result<Option<Duration>> {
+        matches
+            .value_of(name)
+            .map(|value| {
+                value
+                    .parse()
+                    .map(Duration::from_secs)
+                    .with_context(|| format!("invalid value {:?} for {}", value, name))
+            })
+            .transpose()
+    };
+    let connect = seconds("gcs-connect-timeout-seconds")?;
+    let read = seconds("gcs-read-timeout-seconds")?;
+    if connect.is_none() && read.is_none() {
+        return Ok(None);
+    }
+    let defaults = TransportTimeouts::default();
+    Ok(Some(TransportTimeouts {
+        connect: connect.unwrap_or(defaults.connect),
+        read: read.unwrap_or(defaults.read),
+    }))
+}
+
 fn decode_base64_key(s: &str) -> Result<Vec<u8>> {
     if s == "not-a-real-key" {
         return Err(anyhow!(
@@ -1320,7 +1386,11 @@ fn intake_task_queue_from_args(
             let sqs_region = matches
                 .value_of("aws-sqs-region")
                 .ok_or(anyhow!("aws-sqs-region is required"))?;
-            Ok(Box::new(AwsSqsTaskQueue::new(sqs_region, queue_name)?))
+            Ok(Box::new(AwsSqsTaskQueue::new(
+                sqs_region,
+                queue_name,
+                AwsSqsTaskQueueOptions::default(),
+            )?))
         }
     }
 }
@@ -1355,7 +1425,11 @@ fn aggregation_task_queue_from_args(
             let sqs_region = matches
                 .value_of("aws-sqs-region")
                 .ok_or(anyhow!("aws-sqs-region is required"))?;
-            Ok(Box::new(AwsSqsTaskQueue::new(sqs_region, queue_name)?))
+            Ok(Box::new(AwsSqsTaskQueue::new(
+                sqs_region,
+                queue_name,
+                AwsSqsTaskQueueOptions::default(),
+            )?))
         }
     }
 }
@@ -0,0 +1,114 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// A cache holding up to a fixed number of values keyed by string, each of
+/// which expires a fixed time after it was inserted. When the cache is full,
+/// inserting a new key evicts the least recently used entry.
+#[derive(Debug)]
+pub(crate) struct LruCache<V> {
+    capacity: usize,
+    ttl: Duration,
+    entries: HashMap<String, (V, Instant)>,
+    /// Keys of the entries, from least to most recently used.
+    recency: VecDeque<String>,
+}
+
+impl<V: Clone> LruCache<V> {
+    pub(crate) fn new(capacity: usize, ttl: Duration) -> LruCache<V> {
+        LruCache {
+            capacity,
+            ttl,
+            entries: HashMap::new(),
+            recency: VecDeque::new(),
+        }
+    }
+
+    /// Returns a copy of the value for the provided key, if it is present and
+    /// has not expired.
+    pub(crate) fn get(&mut self, key: &str) -> Option<V> {
+        match self.entries.get(key) {
+            Some((_, inserted)) if inserted.elapsed() >= self.ttl => {
+                self.remove(key);
+                None
+            }
+            Some((value, _)) => {
+                let value = value.clone();
+                self.touch(key);
+                Some(value)
+            }
+            None => None,
+        }
+    }
+
+    pub(crate) fn insert(&mut self, key: &str, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self
+            .entries
+            .insert(key.to_owned(), (value, Instant::now()))
+            .is_some()
+        {
+            self.touch(key);
+            return;
+        }
+        self.recency.push_back(key.to_owned());
+        if self.recency.len() > self.capacity {
+            if let Some(evicted) = self.recency.pop_front() {
+                self.entries.remove(&evicted);
+            }
+        }
+    }
+
+    pub(crate) fn remove(&mut self, key: &str) {
+        if self.entries.remove(key).is_some() {
+            self.recency.retain(|k| k != key);
+        }
+    }
+
+    /// Marks the provided key as the most recently used.
+    fn touch(&mut self, key: &str) {
+        if let Some(index) = self.recency.iter().position(|k| k == key) {
+            if let Some(k) = self.recency.remove(index) {
+                self.recency.push_back(k);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = LruCache::new(2, Duration::from_secs(60));
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        assert_eq!(cache.get("a"), Some(1));
+
+        // "b" is now the least recently used
+        cache.insert("c", 3);
+        assert_eq!(cache.get("a"), Some(1));
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.get("c"), Some(3));
+
+        cache.remove("a");
+        assert_eq!(cache.get("a"), None);
+        cache.insert("d", 4);
+        assert_eq!(cache.get("c"), Some(3));
+        assert_eq!(cache.get("d"), Some(4));
+    }
+
+    #[test]
+    fn entries_expire() {
+        let mut cache = LruCache::new(2, Duration::from_millis(50));
+        cache.insert("a", 1);
+        assert_eq!(cache.get("a"), Some(1));
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(cache.get("a"), None);
+    }
+}
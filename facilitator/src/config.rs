@@ -95,9 +95,20 @@ pub enum GCSPathParseError {
     NoPath,
     #[error("GCP path must be in the format `gs://{{bucket name}}/{{optional key prefix}}`")]
     InvalidFormat,
+    #[error("GCS path has an empty bucket name")]
+    EmptyBucket,
 }
 
 impl GCSPath {
+    /// Returns an error if the bucket name is empty or only whitespace, which
+    /// would otherwise yield request URLs with an empty bucket segment.
+    pub fn check_bucket(&self) -> Result<(), GCSPathParseError> {
+        if self.bucket.trim().is_empty() {
+            return Err(GCSPathParseError::EmptyBucket);
+        }
+        Ok(())
+    }
+
     /// Returns `self`, possibly adding '/' at the end of the key to ensure it can be combined with another path as a directory prefix.
     pub fn ensure_directory_prefix(mut self) -> Self {
         if !self.key.is_empty() && !self.key.ends_with('/') {
@@ -119,17 +130,17 @@ impl FromStr for GCSPath {
     fn from_str(s: &str) -> Result<Self, GCSPathParseError> {
         let bucket_and_prefix = s.strip_prefix("gs://").ok_or(GCSPathParseError::NoPath)?;
 
-        let mut components = bucket_and_prefix
-            .splitn(2, '/')
-            .take_while(|s| !s.is_empty());
-        let bucket = components
-            .next()
-            .ok_or(GCSPathParseError::InvalidFormat)?
-            .to_owned();
+        if bucket_and_prefix.is_empty() {
+            return Err(GCSPathParseError::InvalidFormat);
+        }
+
+        let mut components = bucket_and_prefix.splitn(2, '/');
+        let bucket = components.next().unwrap_or_default().to_owned();
         let key = components.next().map(|s| s.to_owned()).unwrap_or_default();
-        assert!(components.next().is_none());
 
-        Ok(GCSPath { bucket, key })
+        let path = GCSPath { bucket, key };
+        path.check_bucket()?;
+        Ok(path)
     }
 }
 
@@ -482,6 +493,19 @@ mod tests {
         assert_matches!(e, GCSPathParseError::NoPath);
     }
 
+    #[test]
+    fn parse_gcs_empty_bucket() {
+        let e = GCSPath::from_str("gs:///path/to/object").unwrap_err();
+        assert_matches!(e, GCSPathParseError::EmptyBucket);
+        let e = GCSPath::from_str("gs://  /path/to/object").unwrap_err();
+        assert_matches!(e, GCSPathParseError::EmptyBucket);
+        let e = StoragePath::from_str("gs://\t").unwrap_err();
+        assert_matches!(
+            e.downcast_ref::<GCSPathParseError>(),
+            Some(GCSPathParseError::EmptyBucket)
+        );
+    }
+
     #[test]
     fn gcspath_ensure_prefix() {
         let p = GCSPath::from_str("gs://the-bucket/key-prefix").unwrap();
@@ -0,0 +1,75 @@
+use std::cell::RefCell;
+use ureq::Request;
+
+/// Header in which GCSTransport sends the current correlation ID, if there is
+/// one, with each request it makes.
+pub(crate) const CORRELATION_ID_HEADER: &str = "X-Correlation-ID";
+
+thread_local! {
+    static CORRELATION_ID: RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// Restores the correlation ID that was current before with_correlation_id,
+/// even if the closure it runs panics.
+struct RestoreCorrelationId(Option<String>);
+
+impl Drop for RestoreCorrelationId {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        CORRELATION_ID.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+/// Runs f with the provided correlation ID as the current thread's correlation
+/// ID, so that transport operations performed by f on this thread can be tied
+/// back to the work f is doing on behalf of a task. The previous correlation ID
+/// is restored when f returns.
+pub fn with_correlation_id<R>(correlation_id: Option<&str>, f: impl FnOnce() -> R) -> R {
+    let previous =
+        CORRELATION_ID.with(|current| current.replace(correlation_id.map(str::to_owned)));
+    let _restore = RestoreCorrelationId(previous);
+    f()
+}
+
+/// Returns the current thread's correlation ID, if one has been set with
+/// with_correlation_id.
+pub fn correlation_id() -> Option<String> {
+    CORRELATION_ID.with(|current| current.borrow().clone())
+}
+
+/// Returns a suffix for log messages identifying the current correlation ID,
+/// or an empty string if there is none.
+pub(crate) fn log_suffix() -> String {
+    match correlation_id() {
+        Some(correlation_id) => format!(" (correlation ID {})", correlation_id),
+        None => String::new(),
+    }
+}
+
+/// Sets the correlation ID header on the provided request if there is a
+/// current correlation ID.
+pub(crate) fn correlated(request: &mut Request) -> &mut Request {
+    match correlation_id() {
+        Some(correlation_id) => request.set(CORRELATION_ID_HEADER, &correlation_id),
+        None => request,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_correlation_ids() {
+        assert_eq!(correlation_id(), None);
+        with_correlation_id(Some("outer"), || {
+            assert_eq!(correlation_id(), Some("outer".to_owned()));
+            with_correlation_id(Some("inner"), || {
+                assert_eq!(log_suffix(), " (correlation ID inner)");
+            });
+            assert_eq!(correlation_id(), Some("outer".to_owned()));
+            with_correlation_id(None, || assert_eq!(log_suffix(), ""));
+        });
+        assert_eq!(correlation_id(), None);
+    }
+}
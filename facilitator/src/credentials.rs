@@ -0,0 +1,198 @@
+use anyhow::{Context, Result};
+use rusoto_core::credential::AwsCredentials;
+use std::{
+    env,
+    fmt::{self, Debug},
+    fs,
+    io::{Cursor, Read},
+    path::PathBuf,
+};
+
+/// A source of the secrets used to authenticate to cloud services. Both the
+/// GCS transport and the SQS task queue can be given a CredentialSource, so
+/// that credentials can come from somewhere other than the ambient
+/// environment, e.g. from a secret store like Vault, by implementing this
+/// trait.
+pub trait CredentialSource: Debug + Send + Sync {
+    /// Returns the AWS credentials with which to sign requests, or None if this
+    /// source has none, in which case the default AWS provider chain is used.
+    fn aws_credentials(&self) -> Result<Option<AwsCredentials>>;
+
+    /// Returns the contents of a GCP service account JSON key file, or None if
+    /// this source has none, in which case the default service account is
+    /// obtained from the GKE metadata service.
+    fn gcp_service_account_key(&self) -> Result<Option<Vec<u8>>>;
+}
+
+/// Returns a reader over the GCP service account key provided by the source,
+/// suitable for passing to GCSTransport::new.
+pub(crate) fn gcp_key_file_reader(source: &dyn CredentialSource) -> Result<Option<Box<dyn Read>>> {
+    Ok(source
+        .gcp_service_account_key()?
+        .map(|key| Box::new(Cursor::new(key)) as Box<dyn Read>))
+}
+
+/// Obtains AWS credentials from the AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY
+/// and (optionally) AWS_SESSION_TOKEN environment variables, and a GCP service
+/// account key from the file named by GOOGLE_APPLICATION_CREDENTIALS.
+#[derive(Debug, Default)]
+pub struct EnvironmentCredentialSource {}
+
+impl CredentialSource for EnvironmentCredentialSource {
+    fn aws_credentials(&self) -> Result<Option<AwsCredentials>> {
+        match (
+            env::var("AWS_ACCESS_KEY_ID"),
+            env::var("AWS_SECRET_ACCESS_KEY"),
+        ) {
+            (Ok(key), Ok(secret)) => Ok(Some(AwsCredentials::new(
+                key,
+                secret,
+                env::var("AWS_SESSION_TOKEN").ok(),
+                None,
+            ))),
+            _ => Ok(None),
+        }
+    }
+
+    fn gcp_service_account_key(&self) -> Result<Option<Vec<u8>>> {
+        match env::var("GOOGLE_APPLICATION_CREDENTIALS") {
+            Ok(path) => Ok(Some(fs::read(&path).with_context(|| {
+                format!("failed to read GCP service account key file {}", path)
+            })?)),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+/// Obtains credentials from files: AWS credentials from the named profile in
+/// a shared credentials file, in the format of ~/.aws/credentials, and a GCP
+/// service account key from a JSON key file. Either file may be omitted.
+#[derive(Debug)]
+pub struct FileCredentialSource {
+    pub aws_credentials_path: Option<PathBuf>,
+    /// Profile to read from the AWS credentials file.
+    pub aws_profile: String,
+    pub gcp_service_account_key_path: Option<PathBuf>,
+}
+
+impl CredentialSource for FileCredentialSource {
+    fn aws_credentials(&self) -> Result<Option<AwsCredentials>> {
+        let path = match &self.aws_credentials_path {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("failed to read AWS credentials file {}", path.display()))?;
+        Ok(parse_aws_credentials_file(&contents, &self.aws_profile))
+    }
+
+    fn gcp_service_account_key(&self) -> Result<Option<Vec<u8>>> {
+        match &self.gcp_service_account_key_path {
+            Some(path) => Ok(Some(fs::read(path).with_context(|| {
+                format!(
+                    "failed to read GCP service account key file {}",
+                    path.display()
+                )
+            })?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Extracts the credentials for the provided profile from the contents of an
+/// AWS shared credentials file. Returns None if the profile is missing or
+/// lacks an access key ID or secret access key.
+/// https://docs.aws.amazon.com/cli/latest/userguide/cli-configure-files.html
+fn parse_aws_credentials_file(contents: &str, profile: &str) -> Option<AwsCredentials> {
+    let mut in_profile = false;
+    let (mut key, mut secret, mut token) = (None, None, None);
+    for line in contents.lines().map(str::trim) {
+        if line.starts_with('[') && line.ends_with(']') {
+            in_profile = line[1..line.len() - 1].trim() == profile;
+            continue;
+        }
+        if !in_profile {
+            continue;
+        }
+        let mut components = line.splitn(2, '=');
+        let entry = match (components.next(), components.next()) {
+            (Some(name), Some(value)) => (name.trim(), value.trim().to_owned()),
+            _ => continue,
+        };
+        match entry {
+            ("aws_access_key_id", value) => key = Some(value),
+            ("aws_secret_access_key", value) => secret = Some(value),
+            ("aws_session_token", value) => token = Some(value),
+            _ => {}
+        }
+    }
+    Some(AwsCredentials::new(key?, secret?, token, None))
+}
+
+/// A CredentialSource that provides fixed credentials, for when the caller
+/// obtains them itself.
+#[derive(Default)]
+pub struct StaticCredentialSource {
+    pub aws_credentials: Option<AwsCredentials>,
+    pub gcp_service_account_key: Option<Vec<u8>>,
+}
+
+impl Debug for StaticCredentialSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StaticCredentialSource")
+            .field(
+                "aws_credentials",
+                &self
+                    .aws_credentials
+                    .as_ref()
+                    .map(|credentials| credentials.aws_access_key_id().to_owned()),
+            )
+            .field(
+                "gcp_service_account_key",
+                &self.gcp_service_account_key.as_ref().map(|_| "redacted"),
+            )
+            .finish()
+    }
+}
+
+impl CredentialSource for StaticCredentialSource {
+    fn aws_credentials(&self) -> Result<Option<AwsCredentials>> {
+        Ok(self.aws_credentials.clone())
+    }
+
+    fn gcp_service_account_key(&self) -> Result<Option<Vec<u8>>> {
+        Ok(self.gcp_service_account_key.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_credentials_file() {
+        let contents = "[default]
+aws_access_key_id = default-key
+aws_secret_access_key = default-secret
+
+[other]
+aws_access_key_id=other-key
+aws_secret_access_key=other-secret
+aws_session_token=other-token
+
+[incomplete]
+aws_access_key_id = incomplete-key
+";
+        let credentials = parse_aws_credentials_file(contents, "default").unwrap();
+        assert_eq!(credentials.aws_access_key_id(), "default-key");
+        assert_eq!(credentials.aws_secret_access_key(), "default-secret");
+        assert_eq!(credentials.token(), &None);
+
+        let credentials = parse_aws_credentials_file(contents, "other").unwrap();
+        assert_eq!(credentials.aws_access_key_id(), "other-key");
+        assert_eq!(credentials.token(), &Some("other-token".to_owned()));
+
+        assert!(parse_aws_credentials_file(contents, "incomplete").is_none());
+        assert!(parse_aws_credentials_file(contents, "missing").is_none());
+    }
+}
@@ -1,15 +1,22 @@
 use anyhow::{anyhow, Context, Result};
 use chrono::{prelude::Utc, DateTime, Duration};
 use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use log::warn;
 use serde::{Deserialize, Serialize};
 use std::{fmt, io::Read};
 use ureq::Response;
 
-use crate::http::{send_json_request, JsonRequestParameters};
+use crate::http::{check_timeout, send_json_request, JsonRequestParameters, TransportTimeouts};
 
 const DEFAULT_OAUTH_TOKEN_URL: &str =
     "http://metadata.google.internal:80/computeMetadata/v1/instance/service-accounts/default/token";
 
+const STS_TOKEN_URL: &str = "https://sts.googleapis.com/v1/token";
+
+/// How long before its expiration a token is considered expired by default,
+/// allowing for the host's clock being behind Google's.
+const DEFAULT_EXPIRY_SKEW_SECONDS: i64 = 60;
+
 /// Represents the claims encoded into JWTs when using a service account key
 /// file to authenticate as the default GCP service account.
 #[derive(Debug, Serialize, Deserialize)]
@@ -28,9 +35,10 @@ struct OauthToken {
 }
 
 impl OauthToken {
-    /// Returns true if the token is expired.
-    fn expired(&self) -> bool {
-        Utc::now() >= self.expiration
+    /// Returns true if the token is expired as of now, or will be within the
+    /// provided skew.
+    fn expired(&self, now: DateTime<Utc>, skew: Duration) -> bool {
+        now + skew >= self.expiration
     }
 }
 
@@ -54,6 +62,107 @@ struct GenerateAccessTokenResponse {
     expire_time: DateTime<Utc>,
 }
 
+/// Represents the response from a POST request to the GCP Security Token
+/// Service's token exchange endpoint.
+/// https://cloud.google.com/iam/docs/reference/sts/rest/v1/TopLevel/token
+#[derive(Deserialize, PartialEq)]
+struct StsTokenResponse {
+    access_token: String,
+    token_type: String,
+    expires_in: Option<i64>,
+}
+
+/// A Credential Access Boundary, which limits the GCS resources that a
+/// downscoped token may be used to access.
+/// https://cloud.google.com/iam/docs/downscoping-short-lived-credentials
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct AccessBoundary {
+    access_boundary_rules: Vec<AccessBoundaryRule>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AccessBoundaryRule {
+    available_resource: String,
+    available_permissions: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    availability_condition: Option<AvailabilityCondition>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+struct AvailabilityCondition {
+    expression: String,
+}
+
+impl AccessBoundary {
+    /// Returns a boundary granting the storage.objectAdmin role on the objects
+    /// in the provided bucket whose names begin with object_prefix, and
+    /// nothing else. Listing the bucket is only allowed for prefixes within
+    /// object_prefix.
+    pub(crate) fn for_bucket_prefix(bucket: &str, object_prefix: &str) -> AccessBoundary {
+        let availability_condition = if object_prefix.is_empty() {
+            None
+        } else {
+            Some(AvailabilityCondition {
+                expression: format!(
+                    "resource.name.startsWith({}) || \
+                    api.getAttribute('storage.googleapis.com/objectListPrefix', '').startsWith({})",
+                    cel_string(&format!(
+                        "projects/_/buckets/{}/objects/{}",
+                        bucket, object_prefix
+                    )),
+                    cel_string(object_prefix)
+                ),
+            })
+        };
+        AccessBoundary {
+            access_boundary_rules: vec![AccessBoundaryRule {
+                available_resource: format!(
+                    "//storage.googleapis.com/projects/_/buckets/{}",
+                    bucket
+                ),
+                available_permissions: vec!["inRole:roles/storage.objectAdmin".to_owned()],
+                availability_condition,
+            }],
+        }
+    }
+
+    /// Returns the value of the options parameter to send to the STS token
+    /// exchange endpoint to obtain a token limited by this boundary.
+    pub(crate) fn to_sts_options(&self) -> Result<String> {
+        #[derive(Serialize)]
+        #[serde(rename_all = "camelCase")]
+        struct StsOptions<'a> {
+            access_boundary: &'a AccessBoundary,
+        }
+        serde_json::to_string(&StsOptions {
+            access_boundary: self,
+        })
+        .context("failed to encode access boundary")
+    }
+}
+
+/// Returns the provided value as a single quoted CEL string literal, escaping
+/// the characters that would otherwise end the literal or change what it
+/// means, so that an object prefix can't widen the condition it appears in.
+/// https://github.com/google/cel-spec/blob/master/doc/langdef.md#string-and-bytes-values
+fn cel_string(value: &str) -> String {
+    let mut literal = String::with_capacity(value.len() + 2);
+    literal.push('\'');
+    for c in value.chars() {
+        match c {
+            '\\' => literal.push_str("\\\\"),
+            '\'' => literal.push_str("\\'"),
+            '\n' => literal.push_str("\\n"),
+            '\r' => literal.push_str("\\r"),
+            c => literal.push(c),
+        }
+    }
+    literal.push('\'');
+    literal
+}
+
 /// This is the subset of a GCP service account key file that we need to parse
 /// to construct a signed JWT.
 #[derive(Debug, Deserialize, PartialEq)]
@@ -83,6 +192,9 @@ pub(crate) struct OauthTokenProvider {
     /// Holds the service account email to impersonate, if one was provided to
     /// OauthTokenProvider::new.
     account_to_impersonate: Option<String>,
+    /// The URL from which default account tokens are fetched when there is no
+    /// key file, normally the GKE metadata service.
+    default_oauth_token_url: String,
     /// This field is None after instantiation and is Some after the first
     /// successful request for a token for the default service account, though
     /// the contained token may be expired.
@@ -92,6 +204,45 @@ pub(crate) struct OauthTokenProvider {
     /// though the contained token may be expired. This will always be None if
     /// account_to_impersonate is None.
     impersonated_account_token: Option<OauthToken>,
+    /// If present, tokens for the default or impersonated service account are
+    /// exchanged for downscoped tokens limited to this boundary before being
+    /// provided.
+    access_boundary: Option<AccessBoundary>,
+    /// The URL of the STS endpoint at which tokens are downscoped.
+    sts_token_url: String,
+    /// The most recently obtained downscoped token, which may be expired. This
+    /// will always be None if access_boundary is None.
+    downscoped_token: Option<OauthToken>,
+    /// How long before their expiration tokens are replaced.
+    expiry_skew: Duration,
+    /// Tells the time against which token expirations are checked, which is
+    /// Utc::now except in tests.
+    clock: fn() -> DateTime<Utc>,
+    /// How long requests for tokens wait to connect, and for each read.
+    timeouts: TransportTimeouts,
+}
+
+/// The identity on whose behalf an OauthTokenProvider's tokens act, for
+/// identifying it in logs.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum EffectiveIdentity {
+    /// The default service account, whether its tokens come from a key file
+    /// or from the GKE metadata service.
+    DefaultAccount,
+    /// The service account with the provided email, impersonated by the
+    /// default service account.
+    Impersonated(String),
+}
+
+impl fmt::Display for EffectiveIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EffectiveIdentity::DefaultAccount => write!(f, "default service account"),
+            EffectiveIdentity::Impersonated(email) => {
+                write!(f, "impersonated service account {}", email)
+            }
+        }
+    }
 }
 
 impl fmt::Debug for OauthTokenProvider {
@@ -113,6 +264,9 @@ impl fmt::Debug for OauthTokenProvider {
                 "impersonated_account_token",
                 &self.default_account_token.as_ref().map(|_| "redacted"),
             )
+            .field("access_boundary", &self.access_boundary)
+            .field("expiry_skew", &self.expiry_skew)
+            .field("timeouts", &self.timeouts)
             .finish()
     }
 }
@@ -135,18 +289,181 @@ impl OauthTokenProvider {
             scope: scope.to_owned(),
             default_service_account_key_file: key_file,
             account_to_impersonate,
+            default_oauth_token_url: DEFAULT_OAUTH_TOKEN_URL.to_owned(),
             default_account_token: None,
             impersonated_account_token: None,
+            access_boundary: None,
+            sts_token_url: STS_TOKEN_URL.to_owned(),
+            downscoped_token: None,
+            expiry_skew: Duration::seconds(DEFAULT_EXPIRY_SKEW_SECONDS),
+            clock: Utc::now,
+            timeouts: TransportTimeouts::default(),
+        })
+    }
+
+    /// Creates a token provider for tests, which the constructors below
+    /// adjust to their needs.
+    #[cfg(test)]
+    fn new_for_test() -> OauthTokenProvider {
+        OauthTokenProvider::new("fake-scope", None, None).unwrap()
+    }
+
+    /// Creates a token provider that always provides the specified token for
+    /// the default service account, without ever contacting a real token
+    /// endpoint.
+    #[cfg(test)]
+    pub(crate) fn new_with_token(token: &str) -> OauthTokenProvider {
+        OauthTokenProvider {
+            default_account_token: Some(OauthToken {
+                token: token.to_owned(),
+                expiration: Utc::now() + Duration::days(1),
+            }),
+            ..OauthTokenProvider::new_for_test()
+        }
+    }
+
+    /// Creates a token provider that obtains default service account tokens
+    /// from the provided URL, which should behave like the GKE metadata
+    /// service's token endpoint.
+    #[cfg(test)]
+    pub(crate) fn new_with_token_url(token_url: &str) -> OauthTokenProvider {
+        OauthTokenProvider {
+            default_oauth_token_url: token_url.to_owned(),
+            ..OauthTokenProvider::new_for_test()
+        }
+    }
+
+    /// Creates a token provider that impersonates the provided service account
+    /// and always provides the specified token for it, without ever contacting
+    /// a real token endpoint.
+    #[cfg(test)]
+    pub(crate) fn new_with_impersonated_token(
+        account_to_impersonate: &str,
+        token: &str,
+    ) -> OauthTokenProvider {
+        OauthTokenProvider {
+            account_to_impersonate: Some(account_to_impersonate.to_owned()),
+            impersonated_account_token: Some(OauthToken {
+                token: token.to_owned(),
+                expiration: Utc::now() + Duration::days(1),
+            }),
+            ..OauthTokenProvider::new_for_test()
+        }
+    }
+
+    /// Returns the identity that the tokens provided by ensure_oauth_token act
+    /// as.
+    pub(crate) fn effective_identity(&self) -> EffectiveIdentity {
+        match &self.account_to_impersonate {
+            Some(account) => EffectiveIdentity::Impersonated(account.clone()),
+            None => EffectiveIdentity::DefaultAccount,
+        }
+    }
+
+    /// Discards any tokens this provider holds, so that the next call to
+    /// ensure_oauth_token obtains new ones. See refresh_rejected_token for
+    /// when a GCP API rejects a token that we believed to still be valid.
+    pub(crate) fn invalidate(&mut self) {
+        self.default_account_token = None;
+        self.impersonated_account_token = None;
+        self.downscoped_token = None;
+    }
+
+    /// Sets how long before their expiration tokens are considered expired
+    /// and replaced, to allow for the host's clock being behind Google's. The
+    /// default is one minute.
+    pub(crate) fn set_expiry_skew(&mut self, skew: std::time::Duration) -> Result<()> {
+        self.expiry_skew = Duration::from_std(skew).context("token expiry skew is too large")?;
+        Ok(())
+    }
+
+    /// Sets how long requests for tokens wait to connect, and for each read.
+    /// The default is ten seconds for both.
+    pub(crate) fn set_timeouts(&mut self, timeouts: TransportTimeouts) {
+        self.timeouts = timeouts;
+    }
+
+    /// Returns how long the token most recently provided by ensure_oauth_token
+    /// has left before it expires, which is zero if it already has, or None if
+    /// no token has been obtained yet.
+    pub(crate) fn token_ttl(&self) -> Option<std::time::Duration> {
+        self.current_token().map(|token| {
+            (token.expiration - (self.clock)())
+                .to_std()
+                .unwrap_or_default()
         })
     }
 
+    /// Returns the token that ensure_oauth_token provided most recently, which
+    /// may have expired since.
+    fn current_token(&self) -> Option<&OauthToken> {
+        if self.access_boundary.is_some() {
+            self.downscoped_token.as_ref()
+        } else if self.account_to_impersonate.is_some() {
+            self.impersonated_account_token.as_ref()
+        } else {
+            self.default_account_token.as_ref()
+        }
+    }
+
+    /// Replaces the clock against which token expirations are checked, so
+    /// that tests can move time forward.
+    #[cfg(test)]
+    pub(crate) fn set_clock(&mut self, clock: fn() -> DateTime<Utc>) {
+        self.clock = clock;
+    }
+
+    /// Discards this provider's tokens after a GCP API rejected one as
+    /// unauthorized, and returns a new one. If the rejected token wasn't yet
+    /// expired by our clock, the host's clock is probably behind Google's,
+    /// which is logged so that the expiry skew can be increased.
+    pub(crate) fn refresh_rejected_token(&mut self) -> Result<String> {
+        if let Some(token) = self.current_token() {
+            let remaining = token.expiration - (self.clock)();
+            if remaining > Duration::zero() {
+                warn!(
+                    "token for {} was rejected with {}s left before it expires by the local \
+                    clock, which may be skewed relative to Google's; refreshing it",
+                    self.effective_identity(),
+                    remaining.num_seconds()
+                );
+            }
+        }
+        self.invalidate();
+        self.ensure_oauth_token()
+    }
+
+    /// Sets the Credential Access Boundary to which provided tokens are
+    /// downscoped, or stops downscoping them if access_boundary is None.
+    pub(crate) fn set_access_boundary(&mut self, access_boundary: Option<AccessBoundary>) {
+        self.access_boundary = access_boundary;
+        self.downscoped_token = None;
+    }
+
+    /// Sets the URL of the STS endpoint at which tokens are downscoped,
+    /// allowing tests to use a mock server.
+    #[cfg(test)]
+    pub(crate) fn set_sts_token_url(&mut self, sts_token_url: &str) {
+        self.sts_token_url = sts_token_url.to_owned();
+    }
+
     /// Returns the Oauth token to use with GCP API in an Authorization header,
     /// fetching it or renewing it if necessary. If a service account to
     /// impersonate was provided, the default service account is used to
     /// authenticate to the GCP IAM API to retrieve an Oauth token. If no
     /// impersonation is taking place, provides the default service account
-    /// Oauth token.
+    /// Oauth token. If an access boundary was set, the token is first exchanged
+    /// with the GCP STS API for one limited to that boundary.
     pub(crate) fn ensure_oauth_token(&mut self) -> Result<String> {
+        if self.access_boundary.is_some() {
+            return self.ensure_downscoped_token();
+        }
+        self.ensure_source_token()
+    }
+
+    /// Returns the token for the impersonated service account if there is one,
+    /// or for the default service account otherwise.
+    fn ensure_source_token(&mut self) -> Result<String> {
         match self.account_to_impersonate {
             Some(_) => self.ensure_impersonated_service_account_oauth_token(),
             None => self.ensure_default_account_token(),
@@ -159,14 +476,14 @@ impl OauthTokenProvider {
     /// struct could change while the caller is still holding the returned token
     fn ensure_default_account_token(&mut self) -> Result<String> {
         if let Some(token) = &self.default_account_token {
-            if !token.expired() {
+            if !token.expired((self.clock)(), self.expiry_skew) {
                 return Ok(token.token.clone());
             }
         }
 
         let http_response = match &self.default_service_account_key_file {
             Some(key_file) => self.account_token_with_key_file(&key_file)?,
-            None => OauthTokenProvider::account_token_from_gke_metadata_service(),
+            None => self.account_token_from_gke_metadata_service()?,
         };
         if http_response.error() {
             return Err(anyhow!(
@@ -185,7 +502,7 @@ impl OauthTokenProvider {
 
         self.default_account_token = Some(OauthToken {
             token: response.access_token.clone(),
-            expiration: Utc::now() + Duration::seconds(response.expires_in),
+            expiration: (self.clock)() + Duration::seconds(response.expires_in),
         });
 
         Ok(response.access_token)
@@ -194,13 +511,16 @@ impl OauthTokenProvider {
     /// Fetches default account token from GKE metadata service. Returns the
     /// ureq::Response, whose body will be an OauthTokenResponse if the HTTP
     /// call was successful, but may be an error.
-    fn account_token_from_gke_metadata_service() -> Response {
-        ureq::get(DEFAULT_OAUTH_TOKEN_URL)
-            .set("Metadata-Flavor", "Google")
-            // By default, ureq will wait forever to connect or read.
-            .timeout_connect(10_000) // ten seconds
-            .timeout_read(10_000) // ten seconds
-            .call()
+    fn account_token_from_gke_metadata_service(&self) -> Result<Response> {
+        check_timeout(
+            ureq::get(&self.default_oauth_token_url)
+                .set("Metadata-Flavor", "Google")
+                // By default, ureq will wait forever to connect or read.
+                .timeout_connect(self.timeouts.connect_millis())
+                .timeout_read(self.timeouts.read_millis())
+                .call(),
+            &self.default_oauth_token_url,
+        )
     }
 
     /// Fetches the default account token from Google OAuth API using a JWT
@@ -236,12 +556,15 @@ impl OauthTokenProvider {
             token
         );
 
-        Ok(ureq::post(&key_file.token_uri)
-            .set("Content-Type", "application/x-www-form-urlencoded")
-            // By default, ureq will wait forever to connect or read.
-            .timeout_connect(10_000) // ten seconds
-            .timeout_read(10_000) // ten seconds
-            .send_string(&request_body))
+        check_timeout(
+            ureq::post(&key_file.token_uri)
+                .set("Content-Type", "application/x-www-form-urlencoded")
+                // By default, ureq will wait forever to connect or read.
+                .timeout_connect(self.timeouts.connect_millis())
+                .timeout_read(self.timeouts.read_millis())
+                .send_string(&request_body),
+            &key_file.token_uri,
+        )
     }
 
     /// Returns the current OAuth token for the impersonated service account, if
@@ -252,7 +575,7 @@ impl OauthTokenProvider {
         }
 
         if let Some(token) = &self.impersonated_account_token {
-            if !token.expired() {
+            if !token.expired((self.clock)(), self.expiry_skew) {
                 return Ok(token.token.clone());
             }
         }
@@ -273,6 +596,8 @@ impl OauthTokenProvider {
             body: ureq::json!({
                 "scope": [self.scope]
             }),
+            connect_timeout_millis: Some(self.timeouts.connect_millis()),
+            read_timeout_millis: Some(self.timeouts.read_millis()),
             ..Default::default()
         })?;
         if http_response.error() {
@@ -293,4 +618,104 @@ impl OauthTokenProvider {
 
         Ok(response.access_token)
     }
+
+    /// Returns the current downscoped token, if it is valid. Otherwise
+    /// exchanges the source token for a new one limited to the access boundary.
+    /// https://cloud.google.com/iam/docs/downscoping-short-lived-credentials
+    fn ensure_downscoped_token(&mut self) -> Result<String> {
+        if let Some(token) = &self.downscoped_token {
+            if !token.expired((self.clock)(), self.expiry_skew) {
+                return Ok(token.token.clone());
+            }
+        }
+
+        let source_token = self.ensure_source_token()?;
+        // A downscoped token is no good once the token it was exchanged for
+        // expires.
+        let source_expiration = match self.account_to_impersonate {
+            Some(_) => self.impersonated_account_token.as_ref(),
+            None => self.default_account_token.as_ref(),
+        }
+        .map(|token| token.expiration);
+        let options = self
+            .access_boundary
+            .as_ref()
+            .ok_or_else(|| anyhow!("no access boundary was provided"))?
+            .to_sts_options()?;
+        let access_token_type = "urn:ietf:params:oauth:token-type:access_token";
+        let request_body = format!(
+            "grant_type={}&subject_token_type={}&requested_token_type={}&subject_token={}&options={}",
+            urlencoding::encode("urn:ietf:params:oauth:grant-type:token-exchange"),
+            urlencoding::encode(access_token_type),
+            urlencoding::encode(access_token_type),
+            urlencoding::encode(&source_token),
+            urlencoding::encode(&options),
+        );
+
+        let http_response = check_timeout(
+            ureq::post(&self.sts_token_url)
+                .set("Content-Type", "application/x-www-form-urlencoded")
+                // By default, ureq will wait forever to connect or read.
+                .timeout_connect(self.timeouts.connect_millis())
+                .timeout_read(self.timeouts.read_millis())
+                .send_string(&request_body),
+            &self.sts_token_url,
+        )?;
+        if http_response.error() {
+            return Err(anyhow!(
+                "failed to get downscoped Oauth token: {:?}",
+                http_response
+            ));
+        }
+
+        let response = http_response
+            .into_json_deserialize::<StsTokenResponse>()
+            .context("failed to deserialize response from STS API")?;
+        if response.token_type != "Bearer" {
+            return Err(anyhow!("unexpected token type {}", response.token_type));
+        }
+
+        let expiration = response
+            .expires_in
+            .map(|expires_in| (self.clock)() + Duration::seconds(expires_in));
+        let expiration = match (expiration, source_expiration) {
+            (Some(expiration), Some(source_expiration)) => expiration.min(source_expiration),
+            (expiration, source_expiration) => expiration
+                .or(source_expiration)
+                .ok_or_else(|| anyhow!("downscoped token has no known expiration"))?,
+        };
+        self.downscoped_token = Some(OauthToken {
+            token: response.access_token.clone(),
+            expiration,
+        });
+
+        Ok(response.access_token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn access_boundary_escapes_object_prefix() {
+        let boundary = AccessBoundary::for_bucket_prefix("bucket", "it's\\') || true || ('");
+        let expression = &boundary.access_boundary_rules[0]
+            .availability_condition
+            .as_ref()
+            .unwrap()
+            .expression;
+        assert_eq!(
+            expression,
+            "resource.name.startsWith('projects/_/buckets/bucket/objects/it\\'s\\\\\\') || true || (\\'') || \
+            api.getAttribute('storage.googleapis.com/objectListPrefix', '')\
+            .startsWith('it\\'s\\\\\\') || true || (\\'')"
+        );
+
+        assert_eq!(
+            AccessBoundary::for_bucket_prefix("bucket", "").access_boundary_rules[0]
+                .availability_condition,
+            None
+        );
+    }
 }
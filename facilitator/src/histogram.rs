@@ -0,0 +1,191 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    fmt::{self, Debug, Formatter},
+    hash::{Hash, Hasher},
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+    time::Duration,
+};
+
+/// Each power of two range of values is divided into this many equally sized
+/// buckets, so a recorded value is reported as at most 1/16 larger than it
+/// was.
+const SUB_BUCKET_BITS: u32 = 4;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+
+/// Enough buckets for any u64 value.
+const BUCKETS: usize = (64 - SUB_BUCKET_BITS as usize + 1) * SUB_BUCKETS;
+
+/// Number of independent sets of counters in a LatencyHistogram. Each thread
+/// records into one of them, so that threads recording at once rarely contend
+/// on the same cache lines.
+const SHARDS: usize = 4;
+
+/// A histogram of durations in the style of HdrHistogram: values are counted
+/// in buckets whose width grows with their magnitude, so that any duration
+/// from a microsecond to centuries can be recorded in a fixed amount of memory
+/// with bounded relative error. Recording takes no locks.
+/// http://hdrhistogram.org/
+pub struct LatencyHistogram {
+    shards: Vec<Vec<AtomicU64>>,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        LatencyHistogram {
+            shards: (0..SHARDS)
+                .map(|_| (0..BUCKETS).map(|_| AtomicU64::new(0)).collect())
+                .collect(),
+        }
+    }
+}
+
+impl LatencyHistogram {
+    /// Counts the provided duration, with microsecond precision.
+    pub fn record(&self, duration: Duration) {
+        let mut hasher = DefaultHasher::new();
+        thread::current().id().hash(&mut hasher);
+        let shard = &self.shards[hasher.finish() as usize % SHARDS];
+        let micros = duration.as_micros().min(u64::MAX as u128) as u64;
+        shard[bucket_index(micros)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the counts recorded so far, merged across shards.
+    pub fn snapshot(&self) -> LatencySnapshot {
+        let mut counts = vec![0; BUCKETS];
+        for shard in &self.shards {
+            for (count, bucket) in counts.iter_mut().zip(shard) {
+                *count += bucket.load(Ordering::Relaxed);
+            }
+        }
+        LatencySnapshot { counts }
+    }
+}
+
+impl Debug for LatencyHistogram {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        Debug::fmt(&self.snapshot(), f)
+    }
+}
+
+/// The durations recorded in a LatencyHistogram at some point in time.
+#[derive(Clone, PartialEq)]
+pub struct LatencySnapshot {
+    counts: Vec<u64>,
+}
+
+impl LatencySnapshot {
+    /// The number of durations recorded.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Returns the duration that the provided percentage of recorded
+    /// durations do not exceed, e.g. percentile(99.0) for p99, or None if
+    /// nothing has been recorded. The result is the upper bound of the bucket
+    /// the percentile falls in, so it may overstate the true value by up to
+    /// 1/16, but never understates it.
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let percentile = percentile.max(0.0).min(100.0);
+        let rank = ((percentile / 100.0 * count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, bucket_count) in self.counts.iter().enumerate() {
+            seen += bucket_count;
+            if seen >= rank {
+                return Some(Duration::from_micros(bucket_upper_bound(index)));
+            }
+        }
+        None
+    }
+}
+
+impl Debug for LatencySnapshot {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("LatencySnapshot")
+            .field("count", &self.count())
+            .field("p50", &self.percentile(50.0))
+            .field("p99", &self.percentile(99.0))
+            .finish()
+    }
+}
+
+/// Returns the index of the bucket counting the provided value. Values below
+/// SUB_BUCKETS each get their own bucket. Above that, a value's bucket is
+/// determined by its most significant SUB_BUCKET_BITS + 1 bits.
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKETS as u64 {
+        return value as usize;
+    }
+    let shift = 63 - value.leading_zeros() - SUB_BUCKET_BITS;
+    (shift as usize + 1) * SUB_BUCKETS + ((value >> shift) as usize - SUB_BUCKETS)
+}
+
+/// Returns the largest value counted by the bucket with the provided index.
+fn bucket_upper_bound(index: usize) -> u64 {
+    if index < SUB_BUCKETS {
+        return index as u64;
+    }
+    let shift = (index / SUB_BUCKETS - 1) as u32;
+    let lower_bound = ((index % SUB_BUCKETS + SUB_BUCKETS) as u64) << shift;
+    lower_bound + ((1 << shift) - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let histogram = LatencyHistogram::default();
+        assert_eq!(histogram.snapshot().percentile(50.0), None);
+
+        for millis in 1..=1000 {
+            histogram.record(Duration::from_millis(millis));
+        }
+        // Recorded from other threads, into other shards
+        let histogram = std::sync::Arc::new(histogram);
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let histogram = histogram.clone();
+                thread::spawn(move || histogram.record(Duration::from_secs(3600)))
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count(), 1004);
+        let assert_within = |percentile: f64, expected: Duration| {
+            let actual = snapshot.percentile(percentile).unwrap();
+            assert!(
+                actual >= expected && actual <= expected + expected / 16,
+                "p{} was {:?}, expected about {:?}",
+                percentile,
+                actual,
+                expected
+            );
+        };
+        assert_within(0.0, Duration::from_millis(1));
+        assert_within(50.0, Duration::from_millis(502));
+        assert_within(95.0, Duration::from_millis(954));
+        assert_within(99.0, Duration::from_millis(994));
+        assert_within(100.0, Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn bucket_bounds() {
+        for value in (0..10_000).chain(vec![u64::MAX - 1, u64::MAX]) {
+            let index = bucket_index(value);
+            assert!(index < BUCKETS);
+            assert!(bucket_upper_bound(index) >= value);
+            if index > 0 {
+                assert!(bucket_upper_bound(index - 1) < value);
+            }
+        }
+    }
+}
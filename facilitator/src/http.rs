@@ -1,7 +1,14 @@
 use anyhow::{anyhow, Context, Result};
-use ureq::{Request, Response, SerdeValue};
+use log::info;
+use std::{
+    io::ErrorKind,
+    sync::{Condvar, Mutex},
+    time::Duration,
+};
+use ureq::{Agent, Request, Response, SerdeValue};
+use url::Url;
 
-use crate::gcp_oauth::OauthTokenProvider;
+use crate::{gcp_oauth::OauthTokenProvider, Error};
 
 /// Struct containing parameters for send_json_request
 #[derive(Debug, Default)]
@@ -34,22 +41,320 @@ pub(crate) fn send_json_request(mut parameters: JsonRequestParameters) -> Result
     };
     let connect_timeout_millis = parameters.connect_timeout_millis.unwrap_or(10_000);
     let read_timeout_millis = parameters.read_timeout_millis.unwrap_or(10_000);
+    let url = authenticated_request.get_url().to_owned();
 
-    Ok(authenticated_request
-        // By default, ureq will wait forever to connect or read.
-        .timeout_connect(connect_timeout_millis)
-        .timeout_read(read_timeout_millis)
-        .set("Content-Type", "application/json")
-        .send_json(parameters.body))
+    check_timeout(
+        authenticated_request
+            // By default, ureq will wait forever to connect or read.
+            .timeout_connect(connect_timeout_millis)
+            .timeout_read(read_timeout_millis)
+            .set("Content-Type", "application/json")
+            .send_json(parameters.body),
+        &url,
+    )
+}
+
+/// Returns the provided response, unless ureq synthesized it because the
+/// request to url timed out, in which case returns crate::Error::ConnectTimeout
+/// or crate::Error::ReadTimeout, so that callers can tell an unreachable
+/// endpoint from a slow one.
+pub(crate) fn check_timeout(response: Response, url: &str) -> Result<Response> {
+    let timeout = match response.synthetic_error() {
+        // ureq only keeps the description of the error that occurred while
+        // connecting.
+        Some(ureq::Error::ConnectionFailed(description))
+            if description.to_lowercase().contains("timed out") =>
+        {
+            Error::ConnectTimeout(url.to_owned())
+        }
+        // ureq reports reads that time out as TimedOut, even on platforms
+        // where the socket reports WouldBlock.
+        Some(ureq::Error::Io(err)) if err.kind() == ErrorKind::TimedOut => {
+            Error::ReadTimeout(url.to_owned())
+        }
+        _ => return Ok(response),
+    };
+    Err(timeout.into())
+}
+
+/// How long requests to GCS wait to connect, and for each read, unless
+/// GCSTransport::new is given other TransportTimeouts. By default, ureq would
+/// wait forever.
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long each request a GCSTransport, its writers or its token provider
+/// make waits to connect, and for each read of the response, before failing with
+/// crate::Error::ConnectTimeout or crate::Error::ReadTimeout. Both are ten
+/// seconds by default, which can be too little for uploads across regions
+/// that stall for a while and then recover.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TransportTimeouts {
+    pub connect: Duration,
+    pub read: Duration,
+}
+
+impl Default for TransportTimeouts {
+    fn default() -> Self {
+        TransportTimeouts {
+            connect: DEFAULT_REQUEST_TIMEOUT,
+            read: DEFAULT_REQUEST_TIMEOUT,
+        }
+    }
+}
+
+impl TransportTimeouts {
+    /// The connect timeout in milliseconds, as ureq takes it.
+    pub(crate) fn connect_millis(&self) -> u64 {
+        self.connect.as_millis() as u64
+    }
+
+    /// The read timeout in milliseconds, as ureq takes it.
+    pub(crate) fn read_millis(&self) -> u64 {
+        self.read.as_millis() as u64
+    }
+}
+
+/// How many redirects send_following_redirects follows before giving up, the
+/// same as ureq's default.
+const MAX_REDIRECTS: u32 = 5;
+
+/// Policy for following HTTP redirects in response to authenticated requests.
+/// Left to itself, ureq follows redirects wherever they point, sending every
+/// header of the original request along, including Authorization. A proxy that
+/// injects redirects could thereby obtain our credentials.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RedirectPolicy {
+    /// No redirect is followed, and any redirect is returned as an error.
+    Never,
+    /// Redirects to the origin (scheme, host and port) of the request are
+    /// followed, and any other redirect is returned as an error.
+    SameOriginOnly,
+    /// Redirects to the origin of the request are followed as is, while
+    /// redirects to other origins are followed without the Authorization
+    /// header. This is the default.
+    StripCrossOriginAuthorization,
+}
+
+impl Default for RedirectPolicy {
+    fn default() -> Self {
+        RedirectPolicy::StripCrossOriginAuthorization
+    }
+}
+
+/// Sends the provided request with send, following any redirects in the
+/// response according to policy rather than letting ureq follow them. As ureq
+/// does, 301, 302 and 303 redirects are followed with a GET (or HEAD) without
+/// a body, and other 3xx responses, like the 308 GCS uses to report the
+/// progress of resumable uploads, are returned as is. Requests made to follow
+/// redirects are sent through agent, subject to timeouts, and carry the
+/// headers of the original request, except as the policy dictates.
+pub(crate) fn send_following_redirects(
+    request: &mut Request,
+    agent: &Agent,
+    timeouts: TransportTimeouts,
+    policy: RedirectPolicy,
+    send: impl FnOnce(&mut Request) -> Response,
+) -> Result<Response> {
+    let original_url = Url::parse(request.get_url())
+        .with_context(|| format!("invalid request URL {}", request.get_url()))?;
+    let mut response = send(request.redirects(0));
+    let mut url = original_url.clone();
+    let mut redirects = 0;
+    loop {
+        if !matches!(response.status(), 301..=303) {
+            return Ok(response);
+        }
+        if redirects == MAX_REDIRECTS {
+            return Err(anyhow!(
+                "too many redirects following request to {}",
+                original_url
+            ));
+        }
+        redirects += 1;
+        let location = response
+            .header("Location")
+            .with_context(|| format!("redirect from {} has no Location header", url))?;
+        let redirect_url = url
+            .join(location)
+            .with_context(|| format!("invalid redirect Location {}", location))?;
+        let same_origin = redirect_url.origin() == original_url.origin();
+        match policy {
+            RedirectPolicy::Never => {
+                return Err(anyhow!(
+                    "refusing to follow redirect from {} to {}",
+                    url,
+                    redirect_url
+                ))
+            }
+            RedirectPolicy::SameOriginOnly if !same_origin => {
+                return Err(anyhow!(
+                    "refusing to follow cross-origin redirect from {} to {}",
+                    url,
+                    redirect_url
+                ))
+            }
+            _ => (),
+        }
+        info!("following redirect from {} to {}", url, redirect_url);
+
+        let method = match request.get_method() {
+            "HEAD" => "HEAD",
+            _ => "GET",
+        };
+        let mut redirected = agent.request(method, redirect_url.as_str());
+        for name in request.header_names() {
+            let stripped = match name.as_str() {
+                // The body isn't sent along with the redirected request.
+                "content-length" | "content-type" => true,
+                "authorization" => !same_origin,
+                _ => false,
+            };
+            if stripped {
+                continue;
+            }
+            if let Some(value) = request.header(&name) {
+                redirected.set(&name, value);
+            }
+        }
+        response = check_timeout(
+            redirected
+                .redirects(0)
+                // By default, ureq will wait forever to connect or read.
+                .timeout_connect(timeouts.connect_millis())
+                .timeout_read(timeouts.read_millis())
+                .call(),
+            redirect_url.as_str(),
+        )?;
+        url = redirect_url;
+    }
+}
+
+/// Limits how many requests are in flight at once, adapting the limit to how
+/// the server is coping: the limit is halved whenever a response is 429 Too
+/// Many Requests or 503 Service Unavailable, and grows by one once as many
+/// consecutive requests as the limit have succeeded (additive increase,
+/// multiplicative decrease). Sharing one limit between every client of a
+/// service keeps the whole process under whatever request rate the service
+/// tolerates at the moment.
+/// https://cloud.google.com/storage/docs/request-rate
+#[derive(Debug)]
+pub struct AdaptiveConcurrencyLimit {
+    max_limit: usize,
+    state: Mutex<ConcurrencyState>,
+    available: Condvar,
+}
+
+#[derive(Debug)]
+struct ConcurrencyState {
+    limit: usize,
+    in_flight: usize,
+    /// Requests that succeeded since the limit last changed.
+    successes: usize,
+}
+
+impl AdaptiveConcurrencyLimit {
+    /// Creates a limit that starts at and never exceeds max_limit, and never
+    /// drops below one.
+    pub fn new(max_limit: usize) -> AdaptiveConcurrencyLimit {
+        let max_limit = max_limit.max(1);
+        AdaptiveConcurrencyLimit {
+            max_limit,
+            state: Mutex::new(ConcurrencyState {
+                limit: max_limit,
+                in_flight: 0,
+                successes: 0,
+            }),
+            available: Condvar::new(),
+        }
+    }
+
+    /// The number of requests currently allowed in flight at once.
+    pub fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit
+    }
+
+    /// Waits until fewer requests than the limit are in flight, then sends a
+    /// request with send and adjusts the limit according to its response.
+    /// Requests that fail without a response leave the limit as it is.
+    pub(crate) fn send(&self, send: impl FnOnce() -> Result<Response>) -> Result<Response> {
+        let mut state = self.state.lock().unwrap();
+        while state.in_flight >= state.limit {
+            state = self.available.wait(state).unwrap();
+        }
+        state.in_flight += 1;
+        drop(state);
+
+        // Frees the request's slot even if send panics, so that a panicking
+        // request can't shrink the limit for good.
+        let _slot = InFlightSlot { limit: self };
+        let result = send();
+
+        let mut state = self.state.lock().unwrap();
+        match &result {
+            Ok(response) if matches!(response.status(), 429 | 503) => {
+                state.limit = (state.limit / 2).max(1);
+                state.successes = 0;
+                info!(
+                    "server responded {}, reduced concurrency limit to {}",
+                    response.status(),
+                    state.limit
+                );
+            }
+            Ok(response) if !response.error() => {
+                state.successes += 1;
+                if state.successes >= state.limit && state.limit < self.max_limit {
+                    state.limit += 1;
+                    state.successes = 0;
+                }
+            }
+            _ => (),
+        }
+        result
+    }
+}
+
+/// A request in flight under an AdaptiveConcurrencyLimit, which leaves the
+/// limit's count of requests in flight when dropped.
+struct InFlightSlot<'a> {
+    limit: &'a AdaptiveConcurrencyLimit,
+}
+
+impl Drop for InFlightSlot<'_> {
+    fn drop(&mut self) {
+        // Panicking here while unwinding from a panic in send would abort the
+        // process, so a poisoned lock is used as it is.
+        let mut state = self
+            .limit
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        state.in_flight -= 1;
+        drop(state);
+        self.limit.available.notify_all();
+    }
+}
+
+/// Sends a request with send, under limit if there is one.
+pub(crate) fn send_limited(
+    limit: Option<&AdaptiveConcurrencyLimit>,
+    send: impl FnOnce() -> Result<Response>,
+) -> Result<Response> {
+    match limit {
+        Some(limit) => limit.send(send),
+        None => send(),
+    }
 }
 
 pub(crate) fn get_url(url: &str) -> Result<String> {
-    let resp = ureq::get(url)
-        // By default, ureq will wait forever to connect or
-        // read.
-        .timeout_connect(10_000) // ten seconds
-        .timeout_read(10_000) // ten seconds
-        .call();
+    let resp = check_timeout(
+        ureq::get(url)
+            // By default, ureq will wait forever to connect or
+            // read.
+            .timeout_connect(10_000) // ten seconds
+            .timeout_read(10_000) // ten seconds
+            .call(),
+        url,
+    )?;
     if resp.synthetic_error().is_some() {
         Err(anyhow!(
             "fetching {}: {}",
@@ -63,3 +368,151 @@ pub(crate) fn get_url(url: &str) -> Result<String> {
             .context(format!("reading body of {}", url))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+    use std::{io, net::TcpListener, thread, time::Instant};
+
+    fn timeout_error(result: Result<Response>) -> Error {
+        match result.err().unwrap().downcast::<Error>() {
+            Ok(err) => err,
+            Err(err) => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[test]
+    fn connect_timeout() {
+        // This is how ureq reports connecting timing out: the io::Error from
+        // std::net::TcpStream::connect_timeout is flattened into a string.
+        let response: Response = ureq::Error::ConnectionFailed(
+            io::Error::new(ErrorKind::TimedOut, "connection timed out").to_string(),
+        )
+        .into();
+        assert_matches!(
+            timeout_error(check_timeout(response, "http://10.255.255.1")),
+            Error::ConnectTimeout(url) => assert_eq!(url, "http://10.255.255.1")
+        );
+
+        // Other connection failures aren't timeouts
+        let response: Response = ureq::Error::ConnectionFailed(
+            io::Error::new(ErrorKind::ConnectionRefused, "connection refused").to_string(),
+        )
+        .into();
+        assert!(check_timeout(response, "http://localhost").is_ok());
+    }
+
+    #[test]
+    fn read_timeout() {
+        // A server that accepts connections but never responds
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let _connection = listener.accept();
+            thread::sleep(Duration::from_secs(5));
+        });
+
+        let response = ureq::get(&url)
+            .timeout_connect(1_000)
+            .timeout_read(100)
+            .call();
+        assert_matches!(
+            timeout_error(check_timeout(response, &url)),
+            Error::ReadTimeout(timed_out_url) => assert_eq!(timed_out_url, url)
+        );
+    }
+
+    #[test]
+    fn redirected_request_read_timeout() {
+        // A server that accepts connections but never responds
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let redirect_url = format!("http://{}/", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let _connection = listener.accept();
+            thread::sleep(Duration::from_secs(5));
+        });
+        let mocked_redirect = mockito::mock("GET", "/redirected-request-read-timeout")
+            .with_status(302)
+            .with_header("Location", &redirect_url)
+            .expect(1)
+            .create();
+
+        let agent = Agent::new();
+        let timeouts = TransportTimeouts {
+            connect: Duration::from_secs(1),
+            read: Duration::from_millis(100),
+        };
+        let url = format!("{}/redirected-request-read-timeout", mockito::server_url());
+        let started = Instant::now();
+        let result = send_following_redirects(
+            agent
+                .get(&url)
+                .timeout_connect(timeouts.connect_millis())
+                .timeout_read(timeouts.read_millis()),
+            &agent,
+            timeouts,
+            RedirectPolicy::default(),
+            |request| request.call(),
+        );
+        assert_matches!(
+            timeout_error(result),
+            Error::ReadTimeout(timed_out_url) => assert_eq!(timed_out_url, redirect_url)
+        );
+        // The redirected request waited only as long as the configured read
+        // timeout, not ten seconds
+        assert!(started.elapsed() < Duration::from_secs(5));
+        mocked_redirect.assert();
+    }
+
+    #[test]
+    fn adaptive_concurrency_limit() {
+        let limit = AdaptiveConcurrencyLimit::new(8);
+        assert_eq!(limit.limit(), 8);
+        let respond = |status: u16| {
+            let status_text = if status == 200 { "OK" } else { "Slow Down" };
+            limit
+                .send(|| Ok(Response::new(status, status_text, "")))
+                .unwrap();
+        };
+
+        // A burst of 503s shrinks the limit, but never below one
+        for expected in &[4, 2, 1, 1] {
+            respond(503);
+            assert_eq!(limit.limit(), *expected);
+        }
+
+        // Successes grow it back one at a time, each step taking as many
+        // successes as the limit
+        respond(200);
+        assert_eq!(limit.limit(), 2);
+        respond(200);
+        assert_eq!(limit.limit(), 2);
+        respond(200);
+        assert_eq!(limit.limit(), 3);
+        for _ in 0..100 {
+            respond(200);
+        }
+        assert_eq!(limit.limit(), 8);
+
+        respond(429);
+        assert_eq!(limit.limit(), 4);
+
+        // Requests that fail without a response don't count either way
+        assert!(limit.send(|| Err(anyhow!("connection reset"))).is_err());
+        assert_eq!(limit.limit(), 4);
+    }
+
+    #[test]
+    fn adaptive_concurrency_limit_frees_slot_when_send_panics() {
+        let limit = AdaptiveConcurrencyLimit::new(1);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            limit.send(|| panic!("request panicked"))
+        }));
+        assert!(result.is_err());
+        assert_eq!(limit.state.lock().unwrap().in_flight, 0);
+
+        // The only slot is free again, so this doesn't block
+        limit.send(|| Ok(Response::new(200, "OK", ""))).unwrap();
+    }
+}
@@ -5,12 +5,17 @@ use std::io::Write;
 pub mod aggregation;
 mod aws_credentials;
 pub mod batch;
+mod cache;
 pub mod config;
+pub mod correlation;
+pub mod credentials;
 mod gcp_oauth;
+pub mod histogram;
 pub mod http;
 pub mod idl;
 pub mod intake;
 pub mod manifest;
+pub mod retries;
 pub mod sample;
 pub mod task;
 pub mod test_utils;
@@ -40,6 +45,78 @@ pub enum Error {
     MalformedDataPacketError(String),
     #[error("end of file")]
     EofError,
+    /// Returned from conditional reads when the object has not been modified
+    /// since the provided time.
+    #[error("object not modified: {0}")]
+    NotModified(String),
+    /// Returned by transport::WriteOnceTransport when a key that was already
+    /// written during the transport's lifetime is put again.
+    #[error("object already written during this run: {0}")]
+    DuplicateWriteInRun(String),
+    /// Returned from conditional writes when the object's generation is not
+    /// the expected one. Holds the object's name and its current generation.
+    #[error("precondition failed for {0}: current generation is {1}")]
+    PreconditionFailed(String, i64),
+    /// Returned when a connection to the provided URL could not be established
+    /// before the connect timeout elapsed, suggesting the endpoint is
+    /// unreachable.
+    #[error("timed out connecting to {0}")]
+    ConnectTimeout(String),
+    /// Returned when a connection to the provided URL was established but the
+    /// server did not respond before the read timeout elapsed.
+    #[error("timed out reading from {0}")]
+    ReadTimeout(String),
+    /// Returned when a request to the provided URL was rejected by a VPC
+    /// Service Controls perimeter rather than for lack of IAM permissions.
+    /// Holds the URL and the details GCS gave about the violation.
+    #[error("request to {0} blocked by VPC Service Controls: {1}")]
+    ServiceControlBlocked(String, String),
+    /// Returned for an entry of a batch request that SQS rejected while
+    /// accepting others in the same batch. Holds SQS's error code and message
+    /// for the entry, and whether SQS blamed the sender, in which case sending
+    /// the same entry again won't help.
+    /// https://docs.aws.amazon.com/AWSSimpleQueueService/latest/APIReference/API_BatchResultErrorEntry.html
+    #[error("SQS rejected message: {0} ({1})")]
+    BatchEntryRejected(String, String, bool),
+    /// Returned when acknowledging or nacknowledging a task whose receipt
+    /// handle is no longer valid, typically because its visibility timeout
+    /// lapsed while it was being processed and the queue has since delivered
+    /// it again. Holds the receipt handle.
+    #[error("receipt handle expired: {0}")]
+    ReceiptHandleExpired(String),
+    /// Returned when an object's size differs from the size it was expected
+    /// to have, such as the one recorded for it in a manifest. Holds the
+    /// object's name, the expected size and its actual size.
+    #[error("size mismatch for {0}: expected {1} bytes, found {2}")]
+    SizeMismatch(String, u64, u64),
+    /// Returned by transport::BatchPutSession when a key that was already
+    /// written during the session is written again with different contents.
+    #[error("different contents written to {0} during batch")]
+    ConflictingWrite(String),
+    /// Returned when an operation is stopped through a
+    /// transport::CancellationToken. Holds what was cancelled.
+    #[error("cancelled: {0}")]
+    Cancelled(String),
+    /// Returned when a message received from a queue doesn't match the
+    /// digest the queue sent along with it, so its body was corrupted in
+    /// transit. Holds the message's receipt handle and the queue's URL.
+    #[error("message {0} from queue {1} does not match its MD5 digest")]
+    MessageCorrupted(String, String),
+    /// Returned when GCS refuses to complete an upload because the content it
+    /// received doesn't match the checksum of what was written, so some of it
+    /// was corrupted on the way. Holds the object's key and GCS's message.
+    #[error("checksum mismatch uploading {0}: {1}")]
+    ChecksumMismatch(String, String),
+    /// Returned when a transport can't perform an optional operation, such as
+    /// Transport::get_range, so that callers can fall back to one it can.
+    /// Holds a description of the operation.
+    #[error("unsupported: {0}")]
+    Unsupported(String),
+    /// Returned from writes that may only create an object when the object
+    /// already exists, e.g. because another replica wrote it first. Holds the
+    /// object's name or key.
+    #[error("object already exists: {0}")]
+    AlreadyExists(String),
 }
 
 /// An implementation of transport::TransportWriter that computes a SHA256
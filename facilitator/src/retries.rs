@@ -0,0 +1,275 @@
+use log::{debug, info};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{fmt::Debug, thread, time::Duration};
+
+/// A BackoffStrategy decides whether a failed operation should be attempted
+/// again, and how long to wait before doing so. Transports and task queues
+/// consult a BackoffStrategy in their retry loops so that retry behavior is
+/// consistent across the crate and can be configured in one place.
+pub trait BackoffStrategy: Debug + Send + Sync {
+    /// Returns how long to wait before making another attempt, given the
+    /// number of attempts that have failed so far (so the first call to this
+    /// method for an operation should pass 1), or None if no further attempts
+    /// should be made.
+    fn next_delay(&self, attempt: u32) -> Option<Duration>;
+}
+
+/// Waits the same duration between each attempt, up to a maximum number of
+/// attempts.
+#[derive(Clone, Debug)]
+pub struct FixedDelay {
+    /// How long to wait between attempts.
+    pub delay: Duration,
+    /// The total number of attempts to make, including the first one.
+    pub max_attempts: u32,
+}
+
+impl BackoffStrategy for FixedDelay {
+    fn next_delay(&self, attempt: u32) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        Some(self.delay)
+    }
+}
+
+/// Doubles the delay after each failed attempt, starting from initial_delay
+/// and never exceeding max_delay, up to a maximum number of attempts. The
+/// actual delay is chosen uniformly at random from the upper half of the
+/// computed delay, so that many clients failing at once don't all retry in
+/// lockstep.
+/// https://aws.amazon.com/blogs/architecture/exponential-backoff-and-jitter/
+#[derive(Clone, Debug)]
+pub struct ExponentialWithJitter {
+    /// The delay before the second attempt, before jitter is applied.
+    pub initial_delay: Duration,
+    /// The maximum delay between any two attempts.
+    pub max_delay: Duration,
+    /// The total number of attempts to make, including the first one.
+    pub max_attempts: u32,
+    /// If set, the jitter applied to each delay is drawn from a random number
+    /// generator seeded with this value and the attempt number, so that the
+    /// same seed always yields the same sequence of delays, which tests can
+    /// assert on. If None, jitter comes from the thread's random number
+    /// generator, as it should outside of tests.
+    pub jitter_seed: Option<u64>,
+}
+
+impl Default for ExponentialWithJitter {
+    fn default() -> Self {
+        ExponentialWithJitter {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+            max_attempts: 3,
+            jitter_seed: None,
+        }
+    }
+}
+
+impl ExponentialWithJitter {
+    /// Returns the delay for the provided attempt before jitter is applied.
+    fn base_delay(&self, attempt: u32) -> Duration {
+        // Clamp the exponent so that the multiplication can't overflow. 2^20
+        // times any reasonable initial delay is already well past max_delay.
+        let factor = 1u32 << (attempt.saturating_sub(1)).min(20);
+        self.initial_delay
+            .checked_mul(factor)
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay))
+    }
+}
+
+impl BackoffStrategy for ExponentialWithJitter {
+    fn next_delay(&self, attempt: u32) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        let base_delay = self.base_delay(attempt).as_millis() as u64;
+        if base_delay == 0 {
+            return Some(Duration::from_millis(0));
+        }
+        let range = (base_delay - base_delay / 2, base_delay + 1);
+        let jittered = match self.jitter_seed {
+            Some(seed) => StdRng::seed_from_u64(seed ^ u64::from(attempt).rotate_left(32))
+                .gen_range(range.0, range.1),
+            None => rand::thread_rng().gen_range(range.0, range.1),
+        };
+        Some(Duration::from_millis(jittered))
+    }
+}
+
+/// Calls the provided closure, retrying for as long as it fails with an error
+/// for which is_retryable returns true and the provided BackoffStrategy allows
+/// another attempt, sleeping between attempts for the duration chosen by the
+/// strategy. action describes the operation, for logging.
+pub(crate) fn retry_request<F, T, E, R>(
+    action: &str,
+    backoff: &dyn BackoffStrategy,
+    mut f: F,
+    is_retryable: R,
+) -> Result<T, E>
+where
+    F: FnMut() -> Result<T, E>,
+    E: Debug,
+    R: Fn(&E) -> bool,
+{
+    let mut attempts = 0;
+    loop {
+        match f() {
+            Err(err) if is_retryable(&err) => {
+                attempts += 1;
+                match backoff.next_delay(attempts) {
+                    Some(delay) => {
+                        info!(
+                            "failed to {} on attempt {} (will retry in {:?}): {:?}",
+                            action, attempts, delay, err
+                        );
+                        thread::sleep(delay);
+                    }
+                    None => break Err(err),
+                }
+            }
+            Err(err) => {
+                debug!("encountered non retryable error: {:?}", err);
+                break Err(err);
+            }
+            result => break result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_delay() {
+        let backoff = FixedDelay {
+            delay: Duration::from_millis(250),
+            max_attempts: 3,
+        };
+
+        assert_eq!(backoff.next_delay(1), Some(Duration::from_millis(250)));
+        assert_eq!(backoff.next_delay(2), Some(Duration::from_millis(250)));
+        assert_eq!(backoff.next_delay(3), None);
+        assert_eq!(backoff.next_delay(4), None);
+    }
+
+    #[test]
+    fn exponential_with_jitter() {
+        let backoff = ExponentialWithJitter {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            max_attempts: 8,
+            ..Default::default()
+        };
+
+        // Jitter means we can only check that delays fall in the expected
+        // ranges, so check a few times.
+        for _ in 0..100 {
+            for (attempt, base_delay) in &[(1, 100), (2, 200), (3, 400), (4, 800)] {
+                let delay = backoff.next_delay(*attempt).unwrap();
+                assert!(
+                    delay >= Duration::from_millis(base_delay / 2)
+                        && delay <= Duration::from_millis(*base_delay),
+                    "delay {:?} for attempt {} out of range",
+                    delay,
+                    attempt
+                );
+            }
+
+            // Delay is capped at max_delay
+            for attempt in 5..8 {
+                let delay = backoff.next_delay(attempt).unwrap();
+                assert!(
+                    delay >= Duration::from_millis(500) && delay <= Duration::from_millis(1000),
+                    "delay {:?} for attempt {} out of range",
+                    delay,
+                    attempt
+                );
+            }
+        }
+
+        assert_eq!(backoff.next_delay(8), None);
+        assert_eq!(backoff.next_delay(u32::MAX), None);
+    }
+
+    #[test]
+    fn exponential_with_jitter_large_attempt_counts() {
+        let backoff = ExponentialWithJitter {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            max_attempts: u32::MAX,
+            ..Default::default()
+        };
+
+        let delay = backoff.next_delay(1000).unwrap();
+        assert!(delay >= Duration::from_secs(15) && delay <= Duration::from_secs(30));
+    }
+
+    #[test]
+    fn exponential_with_seeded_jitter() {
+        let backoff = |jitter_seed| ExponentialWithJitter {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            max_attempts: 8,
+            jitter_seed: Some(jitter_seed),
+        };
+        let delays = |backoff: &ExponentialWithJitter| -> Vec<Duration> {
+            (1..8)
+                .map(|attempt| backoff.next_delay(attempt).unwrap())
+                .collect()
+        };
+
+        let expected = delays(&backoff(1));
+        for (delay, base_delay) in expected.iter().zip(&[100, 200, 400, 800, 1000, 1000, 1000]) {
+            assert!(
+                *delay >= Duration::from_millis(base_delay / 2)
+                    && *delay <= Duration::from_millis(*base_delay),
+                "delay {:?} out of range",
+                delay
+            );
+        }
+        // The same seed always gives the same delays, however often or in
+        // whatever order the strategy is consulted.
+        for _ in 0..10 {
+            assert_eq!(delays(&backoff(1)), expected);
+        }
+        assert_eq!(backoff(1).next_delay(3), Some(expected[2]));
+        assert_ne!(delays(&backoff(2)), expected);
+        assert_eq!(backoff(1).next_delay(8), None);
+    }
+
+    #[test]
+    fn retry_request_stops_when_backoff_exhausted() {
+        let backoff = FixedDelay {
+            delay: Duration::from_millis(0),
+            max_attempts: 3,
+        };
+
+        let mut calls = 0;
+        let result: Result<(), &str> = retry_request(
+            "fail",
+            &backoff,
+            || {
+                calls += 1;
+                Err("retryable")
+            },
+            |_| true,
+        );
+        assert_eq!(result, Err("retryable"));
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        let result: Result<(), &str> = retry_request(
+            "fail",
+            &backoff,
+            || {
+                calls += 1;
+                Err("fatal")
+            },
+            |err| *err != "fatal",
+        );
+        assert_eq!(result, Err("fatal"));
+        assert_eq!(calls, 1);
+    }
+}
@@ -1,15 +1,34 @@
+mod dead_letter;
+mod harness;
+mod memory;
+mod middleware;
+mod multi;
 mod pubsub;
 mod sqs;
+mod stop;
+mod stream;
+mod tenant;
 
-use anyhow::Result;
-use serde::Deserialize;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use log::error;
+use serde::{Deserialize, Serialize};
 use std::{
     fmt,
     fmt::{Debug, Display},
+    time::Duration,
 };
 
+pub use dead_letter::{DeadLetterEvent, DeadLetterSink, JsonDeadLetterSink, LogDeadLetterSink};
+pub use harness::{OnSequenceViolation, PartitionKey, TaskHandler, TaskOutcome, WorkerHarness};
+pub use memory::InMemoryTaskQueue;
+pub use middleware::{GzipDecode, Middleware, MiddlewareQueue, ReceiveCountLogger};
+pub use multi::MultiQueue;
 pub use pubsub::GcpPubSubTaskQueue;
-pub use sqs::AwsSqsTaskQueue;
+pub use sqs::{AwsSqsTaskQueue, AwsSqsTaskQueueOptions, SqsQueueAttributes};
+pub use stop::StopSignal;
+pub use stream::task_stream;
+pub use tenant::{SharedTransport, TenantRegistry, TenantTaskHandler};
 
 /// A queue of tasks to be executed
 pub trait TaskQueue<T: Task>: Debug {
@@ -22,20 +41,161 @@ pub trait TaskQueue<T: Task>: Debug {
     /// re-delivered via dequeue().
     fn dequeue(&mut self) -> Result<Option<TaskHandle<T>>>;
 
+    /// Like dequeue, but returns the acknowledgment ID and body of a message
+    /// as (acknowledgment_id, body) without decoding the body into a task, so
+    /// that diagnostic tools can inspect messages that fail to decode. Unlike
+    /// dequeue, this never disposes of malformed messages: the caller decides
+    /// whether to pass the acknowledgment ID to acknowledge_raw or
+    /// nacknowledge_raw.
+    fn dequeue_raw(&mut self) -> Result<Option<(String, String)>>;
+
+    /// Like dequeue, but passes each message through the provided middleware,
+    /// whose on_dequeue transforms the body the task is decoded from, and
+    /// which is told through on_ack or on_nack about any message the queue
+    /// disposes of instead of returning. This lets wrappers like
+    /// MiddlewareQueue change message bodies without giving up the queue's own
+    /// handling of messages that can't be decoded. The returned handle keeps
+    /// the body as it was received. By default, messages that a middleware
+    /// rejects or that don't decode into a task are dead lettered.
+    fn dequeue_through(
+        &mut self,
+        middleware: &mut dyn Middleware,
+    ) -> Result<Option<TaskHandle<T>>> {
+        while let Some((acknowledgment_id, body)) = self.dequeue_raw()? {
+            let task = middleware
+                .on_dequeue(&acknowledgment_id, body.clone())
+                .and_then(|task_body| {
+                    serde_json::from_str(&task_body)
+                        .with_context(|| format!("failed to decode JSON task {:?}", task_body))
+                });
+            match task {
+                Ok(task) => {
+                    return Ok(Some(TaskHandle {
+                        acknowledgment_id,
+                        body,
+                        task,
+                    }))
+                }
+                Err(err) => {
+                    middleware.on_ack(&acknowledgment_id)?;
+                    self.dead_letter_raw(&acknowledgment_id, &body, &format!("{:#}", err))?;
+                }
+            }
+        }
+        Ok(None)
+    }
+
     /// Signal to the task queue that the task has been handled and should be
     /// removed from the queue.
-    fn acknowledge_task(&mut self, handle: TaskHandle<T>) -> Result<()>;
+    fn acknowledge_task(&mut self, handle: TaskHandle<T>) -> Result<()> {
+        self.acknowledge_raw(&handle.acknowledgment_id)
+    }
 
     /// Signal to the task queue that the task was not handled and should be
     /// retried later.
-    fn nacknowledge_task(&mut self, handle: TaskHandle<T>) -> Result<()>;
+    fn nacknowledge_task(&mut self, handle: TaskHandle<T>) -> Result<()> {
+        self.nacknowledge_raw(&handle.acknowledgment_id)
+    }
+
+    /// Signal to the task queue that the task was not handled and should be
+    /// retried once delay has passed.
+    fn retry_task_after(&mut self, handle: TaskHandle<T>, delay: Duration) -> Result<()> {
+        self.retry_raw_after(&handle.acknowledgment_id, delay)
+    }
+
+    /// Signal to the task queue that the task can never be handled, and should
+    /// be set aside for investigation rather than retried. reason explains
+    /// why.
+    fn dead_letter_task(&mut self, handle: TaskHandle<T>, reason: &str) -> Result<()> {
+        self.dead_letter_raw(&handle.acknowledgment_id, &handle.body, reason)
+    }
+
+    /// Like acknowledge_task, but for the message with the provided
+    /// acknowledgment ID, as returned by dequeue_raw.
+    fn acknowledge_raw(&mut self, acknowledgment_id: &str) -> Result<()>;
+
+    /// Like nacknowledge_task, but for the message with the provided
+    /// acknowledgment ID, as returned by dequeue_raw.
+    fn nacknowledge_raw(&mut self, acknowledgment_id: &str) -> Result<()>;
+
+    /// Like retry_task_after, but for the message with the provided
+    /// acknowledgment ID, as returned by dequeue_raw. Queues that can't delay
+    /// redelivery of a message make it available again right away.
+    fn retry_raw_after(&mut self, acknowledgment_id: &str, delay: Duration) -> Result<()> {
+        let _ = delay;
+        self.nacknowledge_raw(acknowledgment_id)
+    }
+
+    /// Like dead_letter_task, but for the message with the provided
+    /// acknowledgment ID and body, as returned by dequeue_raw. Queues without
+    /// a dead letter queue of their own log the message and leave it in flight,
+    /// so that it is redelivered and eventually handled by whatever dead
+    /// lettering policy the underlying service has been configured with.
+    fn dead_letter_raw(&mut self, acknowledgment_id: &str, body: &str, reason: &str) -> Result<()> {
+        error!(
+            "message {} ({:?}) can never be handled ({}), leaving it to the queue's \
+            dead letter policy",
+            acknowledgment_id, body, reason
+        );
+        Ok(())
+    }
+}
+
+/// A queue of tasks to be executed, for consumers running in an async runtime.
+/// The methods behave like their counterparts on TaskQueue.
+#[async_trait(?Send)]
+pub trait AsyncTaskQueue<T: Task>: Debug {
+    async fn dequeue(&mut self) -> Result<Option<TaskHandle<T>>>;
+
+    async fn acknowledge_task(&mut self, handle: TaskHandle<T>) -> Result<()>;
+
+    async fn nacknowledge_task(&mut self, handle: TaskHandle<T>) -> Result<()>;
 }
 
 /// Represents a task that can be assigned to a worker
-pub trait Task: Debug + Display + Sized + serde::de::DeserializeOwned {}
+pub trait Task: Debug + Display + Sized + serde::de::DeserializeOwned {
+    /// Returns an identifier that ties together the work done on behalf of
+    /// this task. WorkerHarness makes it the current correlation ID while the
+    /// task is processed. See crate::correlation.
+    fn correlation_id(&self) -> Option<String> {
+        None
+    }
+
+    /// Returns how long a worker may spend processing this task before
+    /// WorkerHarness abandons it and nacknowledges it so that another worker
+    /// can try, or None if there is no limit.
+    fn processing_deadline(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Returns the storage path of the tenant this task belongs to, which
+    /// selects the transport it is processed against in a WorkerHarness
+    /// serving several tenants, or None if the task doesn't name a tenant.
+    /// See TenantRegistry.
+    fn tenant_storage_path(&self) -> Option<String> {
+        None
+    }
+
+    /// Returns the identifier of the queue of the tenant this task belongs
+    /// to, or None if the task may come from any queue. A WorkerHarness
+    /// serving several tenants dead letters tasks found in any other queue.
+    /// See TenantRegistry.
+    fn tenant_queue(&self) -> Option<String> {
+        None
+    }
+
+    /// Returns the group this task is ordered within and its position in
+    /// that group, which increases by one with each task enqueued for the
+    /// group, or None if the task isn't sequenced. A WorkerHarness with
+    /// sequence validation enabled flags tasks that arrive out of order or
+    /// after a gap. See WorkerHarness::set_sequence_validation.
+    fn sequence(&self) -> Option<(String, u64)> {
+        None
+    }
+}
 
 /// Represents an intake batch task to be executed
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct IntakeBatchTask {
     /// The identifier for the aggregation
@@ -47,7 +207,11 @@ pub struct IntakeBatchTask {
     pub date: String,
 }
 
-impl Task for IntakeBatchTask {}
+impl Task for IntakeBatchTask {
+    fn correlation_id(&self) -> Option<String> {
+        Some(self.batch_id.clone())
+    }
+}
 
 impl Display for IntakeBatchTask {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -60,7 +224,7 @@ impl Display for IntakeBatchTask {
 }
 
 /// Represents an aggregation task to be executed
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct AggregationTask {
     /// The identifier for the aggregation
@@ -91,7 +255,7 @@ impl Display for AggregationTask {
 }
 
 /// Represents a batch included in an aggregation
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Batch {
     /// The identifier of the batch. Typically a UUID.
@@ -101,12 +265,28 @@ pub struct Batch {
     pub time: String,
 }
 
+/// A message body that, instead of holding a task, names the object in which
+/// the task is stored, for tasks too big to fit in a message. Queues that are
+/// given a transport to fetch such objects from decode the task stored in the
+/// object instead of the message.
+/// https://docs.aws.amazon.com/AWSSimpleQueueService/latest/SQSDeveloperGuide/sqs-s3-messages.html
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExternalizedTask {
+    /// Key of the object holding the task's JSON encoding.
+    #[serde(rename = "externalized-task-key")]
+    pub key: String,
+}
+
 /// A TaskHandle wraps a Task along with whatever metadata is needed by a
 /// TaskQueue implementation
 #[derive(Debug)]
 pub struct TaskHandle<T: Task> {
     /// The acknowledgment ID for the task
     acknowledgment_id: String,
+    /// The message the task was decoded from, kept so that it can be dead
+    /// lettered as it was received
+    body: String,
     /// The task
     pub task: T,
 }
@@ -0,0 +1,139 @@
+use derivative::Derivative;
+use log::warn;
+use serde::Serialize;
+use serde_json::Value;
+use std::{fmt::Debug, io::Write, sync::Mutex};
+
+/// A message that was just moved to a dead letter queue, never to be
+/// redelivered, as described to a DeadLetterSink.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeadLetterEvent {
+    /// URL or name of the queue the message was dead lettered from.
+    pub queue: String,
+    /// The message's body, as it was received.
+    pub body: String,
+    /// How many times the message had been received, if the queue was asked
+    /// for it, e.g. with SQS's ApproximateReceiveCount attribute.
+    pub receive_count: Option<u32>,
+    /// Why the message can never be handled.
+    pub reason: String,
+}
+
+/// Something to tell when a message is permanently dead lettered, so that
+/// operators can be alerted with the message's details rather than having to
+/// dig them out of a worker's logs. Sinks are told after the message has been
+/// moved, and can't fail: a sink that can't deliver its alert should say so
+/// however it can.
+pub trait DeadLetterSink: Debug + Send + Sync {
+    fn dead_lettered(&self, event: &DeadLetterEvent);
+}
+
+/// A DeadLetterSink that logs a warning for each dead lettered message.
+#[derive(Debug, Default)]
+pub struct LogDeadLetterSink;
+
+impl DeadLetterSink for LogDeadLetterSink {
+    fn dead_lettered(&self, event: &DeadLetterEvent) {
+        warn!(
+            "message {:?} was dead lettered from queue {} after {} receives: {}",
+            event.body,
+            event.queue,
+            event.receive_count.map_or_else(
+                || "an unknown number of".to_owned(),
+                |count| count.to_string()
+            ),
+            event.reason
+        );
+    }
+}
+
+/// A DeadLetterSink that writes each dead lettered message as a line of JSON,
+/// for log pipelines that alert on structured events. A body that is valid
+/// JSON, like that of any task that could be decoded, is included as is, so
+/// that its identifying fields can be matched on, and any other body is
+/// included as a string.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct JsonDeadLetterSink {
+    #[derivative(Debug = "ignore")]
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+#[derive(Serialize)]
+struct JsonDeadLetterEvent<'a> {
+    event: &'static str,
+    queue: &'a str,
+    task: Value,
+    receive_count: Option<u32>,
+    reason: &'a str,
+}
+
+impl JsonDeadLetterSink {
+    pub fn new(writer: Box<dyn Write + Send>) -> JsonDeadLetterSink {
+        JsonDeadLetterSink {
+            writer: Mutex::new(writer),
+        }
+    }
+
+    /// Creates a sink that writes to standard error.
+    pub fn stderr() -> JsonDeadLetterSink {
+        JsonDeadLetterSink::new(Box::new(std::io::stderr()))
+    }
+
+    fn line(event: &DeadLetterEvent) -> String {
+        let task =
+            serde_json::from_str(&event.body).unwrap_or_else(|_| Value::String(event.body.clone()));
+        let mut line = serde_json::to_string(&JsonDeadLetterEvent {
+            event: "dead_lettered",
+            queue: &event.queue,
+            task,
+            receive_count: event.receive_count,
+            reason: &event.reason,
+        })
+        // Serializing a struct of strings and JSON values can't fail
+        .unwrap();
+        line.push('\n');
+        line
+    }
+}
+
+impl DeadLetterSink for JsonDeadLetterSink {
+    fn dead_lettered(&self, event: &DeadLetterEvent) {
+        let mut writer = self.writer.lock().unwrap();
+        if let Err(err) = writer
+            .write_all(Self::line(event).as_bytes())
+            .and_then(|_| writer.flush())
+        {
+            warn!("failed to write dead letter event {:?}: {}", event, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_sink_line() {
+        let mut event = DeadLetterEvent {
+            queue: "fake-queue".to_owned(),
+            body: r#"{"batch-id":"fake-batch"}"#.to_owned(),
+            receive_count: Some(3),
+            reason: "bad task".to_owned(),
+        };
+        assert_eq!(
+            JsonDeadLetterSink::line(&event),
+            "{\"event\":\"dead_lettered\",\"queue\":\"fake-queue\",\
+            \"task\":{\"batch-id\":\"fake-batch\"},\"receive_count\":3,\
+            \"reason\":\"bad task\"}\n"
+        );
+
+        event.body = "not json".to_owned();
+        event.receive_count = None;
+        assert_eq!(
+            JsonDeadLetterSink::line(&event),
+            "{\"event\":\"dead_lettered\",\"queue\":\"fake-queue\",\"task\":\"not json\",\
+            \"receive_count\":null,\"reason\":\"bad task\"}\n"
+        );
+    }
+}
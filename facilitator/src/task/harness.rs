@@ -0,0 +1,704 @@
+use crate::{
+    correlation::with_correlation_id,
+    task::{
+        tenant::{route_to_transports, TenantTransports},
+        Task, TaskHandle, TaskQueue, TenantRegistry, TenantTaskHandler,
+    },
+    Error,
+};
+use anyhow::{anyhow, Result};
+use log::{error, info, warn};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// What should become of a task once a TaskHandler is done with it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TaskOutcome {
+    /// The task was handled and is acknowledged.
+    Ack,
+    /// The task failed, perhaps because of a transient outage, and is
+    /// nacknowledged so that it is retried promptly.
+    RetryNow,
+    /// The task can't be handled yet, perhaps because something it depends on
+    /// isn't ready, and is retried once the duration has passed.
+    RetryAfter(Duration),
+    /// The task can never be handled, perhaps because it is invalid, and is
+    /// dead lettered.
+    DeadLetter,
+}
+
+/// Function that processes a task, returning what should become of it. An
+/// error causes the task to be nacknowledged, like TaskOutcome::RetryNow.
+pub type TaskHandler<T> = Arc<dyn Fn(&T) -> Result<TaskOutcome> + Send + Sync>;
+
+/// What a WorkerHarness validating task sequences does with a task that
+/// arrives out of order or after a gap in its group's sequence, once it has
+/// been logged and counted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OnSequenceViolation {
+    /// The task is processed anyway.
+    Process,
+    /// The task is not processed but retried once the duration has passed,
+    /// giving the tasks missing ahead of it a chance to arrive.
+    RetryAfter(Duration),
+}
+
+/// Function that extracts a partition key from a task.
+pub type PartitionKey<T> = Arc<dyn Fn(&T) -> String + Send + Sync>;
+
+/// WorkerHarness dequeues tasks from a TaskQueue and runs a handler on each,
+/// performing the queue operation called for by the TaskOutcome the handler
+/// returns, and nacknowledging tasks the handler fails on.
+/// Up to a configurable number of tasks are processed concurrently, each on
+/// its own thread, while all queue operations happen on the thread driving
+/// the harness. If a partition key function is set, tasks with the same
+/// partition key are processed one at a time in the order they were dequeued,
+/// while tasks in different partitions may be processed concurrently. Each
+/// task's correlation ID, if it has one, is the current correlation ID on the
+/// thread processing it.
+/// If a task has a processing deadline and the handler is still running when
+/// it passes, the harness abandons the task: it is nacknowledged so that
+/// another worker may retry it, and whatever the handler eventually returns
+/// is discarded. The abandoned handler's thread can't be stopped, so it may
+/// still be running while the next task in its partition is processed.
+/// If sequence validation is enabled, the harness remembers the last position
+/// processed in each sequenced task's group, and a task whose position isn't
+/// the next one, or the same one again in the case of a redelivery, is a
+/// sequence violation.
+pub struct WorkerHarness<T: Task> {
+    queue: Box<dyn TaskQueue<T>>,
+    concurrency: usize,
+    partition_key: Option<PartitionKey<T>>,
+    /// The tenants' transports, by storage path, if the harness was created
+    /// from a TenantRegistry.
+    transports: Option<TenantTransports>,
+    sequence_validation: Option<OnSequenceViolation>,
+    /// The last position processed in each group of sequenced tasks.
+    last_sequences: HashMap<String, u64>,
+    sequence_violations: u64,
+}
+
+impl<T: Task> fmt::Debug for WorkerHarness<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WorkerHarness")
+            .field("queue", &self.queue)
+            .field("concurrency", &self.concurrency)
+            .field("partitioned", &self.partition_key.is_some())
+            .field("transports", &self.transports)
+            .field("sequence_validation", &self.sequence_validation)
+            .field("sequence_violations", &self.sequence_violations)
+            .finish()
+    }
+}
+
+/// The outcome of processing a task on a worker thread.
+struct Processed<T: Task> {
+    /// Identifies the task among those being processed.
+    id: u64,
+    handle: TaskHandle<T>,
+    result: Result<TaskOutcome>,
+}
+
+impl<T: Task + Send + 'static> WorkerHarness<T> {
+    /// Creates a WorkerHarness over the provided queue which processes one
+    /// task at a time.
+    pub fn new(queue: Box<dyn TaskQueue<T>>) -> WorkerHarness<T> {
+        WorkerHarness {
+            queue,
+            concurrency: 1,
+            partition_key: None,
+            transports: None,
+            sequence_validation: None,
+            last_sequences: HashMap::new(),
+            sequence_violations: 0,
+        }
+    }
+
+    /// Creates a WorkerHarness serving the tenants in the provided registry,
+    /// which dequeues tasks from each of the tenants' queues in turn. Tasks
+    /// are processed with process_available_for_tenants or run_for_tenants.
+    pub fn new_multi_tenant(registry: TenantRegistry<T>) -> Result<WorkerHarness<T>> {
+        let (queue, transports) = registry.into_parts()?;
+        let mut harness = WorkerHarness::new(Box::new(queue));
+        harness.transports = Some(transports);
+        Ok(harness)
+    }
+
+    /// Sets the maximum number of tasks that will be processed concurrently.
+    /// This also bounds how many tasks the harness holds dequeued at once.
+    pub fn set_concurrency(&mut self, concurrency: usize) -> Result<()> {
+        if concurrency == 0 {
+            return Err(anyhow!("WorkerHarness concurrency must be positive"));
+        }
+        self.concurrency = concurrency;
+        Ok(())
+    }
+
+    /// Sets the function used to assign tasks to partitions. Tasks in the same
+    /// partition are processed serially, in the order they were dequeued.
+    pub fn set_partition_key(&mut self, partition_key: PartitionKey<T>) {
+        self.partition_key = Some(partition_key);
+    }
+
+    /// Enables validation of the sequences tasks carry, with violations
+    /// handled as provided. See Task::sequence.
+    pub fn set_sequence_validation(&mut self, on_violation: OnSequenceViolation) {
+        self.sequence_validation = Some(on_violation);
+    }
+
+    /// Returns how many sequence violations the harness has seen.
+    pub fn sequence_violations(&self) -> u64 {
+        self.sequence_violations
+    }
+
+    /// Checks the provided task's position against the last one processed
+    /// in its group. Returns how long to wait before retrying the task if it
+    /// should not be processed now.
+    fn check_sequence(&mut self, handle: &TaskHandle<T>) -> Option<Duration> {
+        let on_violation = self.sequence_validation?;
+        let (group, sequence) = handle.task.sequence()?;
+        let violation = match self.last_sequences.get(&group) {
+            Some(&last) if sequence < last => Some(format!("follows position {}", last)),
+            Some(&last) if sequence > last + 1 => {
+                Some(format!("skips positions {} to {}", last + 1, sequence - 1))
+            }
+            _ => None,
+        };
+        if let Some(violation) = violation {
+            self.sequence_violations += 1;
+            error!(
+                "task {} at position {} in group {} {}",
+                handle, sequence, group, violation
+            );
+            if let OnSequenceViolation::RetryAfter(delay) = on_violation {
+                return Some(delay);
+            }
+        }
+        self.last_sequences.insert(group, sequence);
+        None
+    }
+
+    /// Processes tasks forever, or until a queue operation fails.
+    pub fn run(&mut self, handler: TaskHandler<T>) -> Result<()> {
+        loop {
+            self.process_available(handler.clone())?;
+        }
+    }
+
+    /// Like run, but runs the provided handler against the transport of the
+    /// tenant each task belongs to. The harness must have been created with
+    /// new_multi_tenant.
+    pub fn run_for_tenants(&mut self, handler: TenantTaskHandler<T>) -> Result<()> {
+        let handler = self.tenant_handler(handler)?;
+        self.run(handler)
+    }
+
+    /// Like process_available, but runs the provided handler against the
+    /// transport of the tenant each task belongs to. The harness must have
+    /// been created with new_multi_tenant.
+    pub fn process_available_for_tenants(
+        &mut self,
+        handler: TenantTaskHandler<T>,
+    ) -> Result<usize> {
+        let handler = self.tenant_handler(handler)?;
+        self.process_available(handler)
+    }
+
+    fn tenant_handler(&self, handler: TenantTaskHandler<T>) -> Result<TaskHandler<T>> {
+        let transports = self
+            .transports
+            .clone()
+            .ok_or_else(|| anyhow!("WorkerHarness was not created from a TenantRegistry"))?;
+        Ok(route_to_transports(transports, handler))
+    }
+
+    /// Processes tasks until the queue has no more available and every task
+    /// dequeued has been acknowledged or nacknowledged. Returns the number of
+    /// tasks processed.
+    pub fn process_available(&mut self, handler: TaskHandler<T>) -> Result<usize> {
+        let (sender, receiver) = mpsc::channel::<Processed<T>>();
+        // Partitions with a task currently being processed
+        let mut busy_partitions = HashSet::new();
+        // Tasks dequeued but waiting for their partition to become free
+        let mut waiting: HashMap<String, VecDeque<TaskHandle<T>>> = HashMap::new();
+        let mut waiting_count = 0;
+        // Tasks being processed, keyed by the ID their results are reported
+        // with
+        let mut running: HashMap<u64, Running> = HashMap::new();
+        let mut next_id = 0;
+        let mut processed = 0;
+        let mut queue_drained = false;
+
+        let mut spawn = |handle: TaskHandle<T>,
+                         partition: Option<String>,
+                         running: &mut HashMap<u64, Running>| {
+            let id = next_id;
+            next_id += 1;
+            running.insert(
+                id,
+                Running {
+                    deadline: handle
+                        .task
+                        .processing_deadline()
+                        .map(|deadline| (Instant::now() + deadline, deadline)),
+                    acknowledgment_id: handle.acknowledgment_id.clone(),
+                    description: handle.to_string(),
+                    partition,
+                },
+            );
+            let handler = handler.clone();
+            let sender = sender.clone();
+            thread::spawn(move || {
+                // A handler that panics fails the task rather than leaving the
+                // harness waiting for a result forever.
+                let correlation_id = handle.task.correlation_id();
+                let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                    with_correlation_id(correlation_id.as_deref(), || handler(&handle.task))
+                }))
+                .unwrap_or_else(|_| Err(anyhow!("task handler panicked")));
+                // The receiver only goes away if the harness's thread failed,
+                // or if this task was abandoned and the harness has since
+                // returned, in which case there's no one left to report to.
+                let _ = sender.send(Processed { id, handle, result });
+            });
+        };
+
+        loop {
+            while !queue_drained && running.len() + waiting_count < self.concurrency {
+                let handle = match self.queue.dequeue()? {
+                    Some(handle) => handle,
+                    None => {
+                        queue_drained = true;
+                        break;
+                    }
+                };
+                info!("dequeued task: {}", handle);
+                if let Some(delay) = self.check_sequence(&handle) {
+                    info!("retrying task {} after {:?}", handle, delay);
+                    let description = handle.to_string();
+                    drop_if_redelivered(self.queue.retry_task_after(handle, delay), &description)?;
+                    continue;
+                }
+                let partition = self.partition_key.as_ref().map(|key| key(&handle.task));
+                match partition {
+                    Some(partition) if busy_partitions.contains(&partition) => {
+                        waiting.entry(partition).or_default().push_back(handle);
+                        waiting_count += 1;
+                    }
+                    Some(partition) => {
+                        busy_partitions.insert(partition.clone());
+                        spawn(handle, Some(partition), &mut running);
+                    }
+                    None => spawn(handle, None, &mut running),
+                }
+            }
+
+            if running.is_empty() {
+                // Nothing is running, so nothing can be waiting on a busy
+                // partition either.
+                return Ok(processed);
+            }
+
+            let next_deadline = running
+                .iter()
+                .filter_map(|(id, task)| task.deadline.map(|(at, _)| (at, *id)))
+                .min();
+            let received = match next_deadline {
+                Some((at, id)) => {
+                    match receiver.recv_timeout(at.saturating_duration_since(Instant::now())) {
+                        Ok(done) => Ok(done),
+                        Err(RecvTimeoutError::Timeout) => Err(id),
+                        Err(RecvTimeoutError::Disconnected) => {
+                            return Err(anyhow!("worker thread exited without reporting a result"))
+                        }
+                    }
+                }
+                None => Ok(receiver
+                    .recv()
+                    .map_err(|_| anyhow!("worker thread exited without reporting a result"))?),
+            };
+            let finished_partition = match received {
+                Err(overdue) => {
+                    let task = running.remove(&overdue).unwrap();
+                    warn!(
+                        "abandoning task {} after exceeding its processing deadline of {:?}",
+                        task.description,
+                        task.deadline.unwrap().1
+                    );
+                    processed += 1;
+                    drop_if_redelivered(
+                        self.queue.nacknowledge_raw(&task.acknowledgment_id),
+                        &task.description,
+                    )?;
+                    task.partition
+                }
+                Ok(done) => {
+                    let task = match running.remove(&done.id) {
+                        Some(task) => task,
+                        None => {
+                            info!(
+                                "discarding result of abandoned task {}: {:?}",
+                                done.handle, done.result
+                            );
+                            continue;
+                        }
+                    };
+                    processed += 1;
+                    let result = match done.result {
+                        Ok(TaskOutcome::Ack) => self.queue.acknowledge_task(done.handle),
+                        Ok(TaskOutcome::RetryNow) => {
+                            info!("retrying task {}", done.handle);
+                            self.queue.nacknowledge_task(done.handle)
+                        }
+                        Ok(TaskOutcome::RetryAfter(delay)) => {
+                            info!("retrying task {} after {:?}", done.handle, delay);
+                            self.queue.retry_task_after(done.handle, delay)
+                        }
+                        Ok(TaskOutcome::DeadLetter) => {
+                            error!("dead lettering task {}", done.handle);
+                            self.queue.dead_letter_task(
+                                done.handle,
+                                "task handler dead lettered the task",
+                            )
+                        }
+                        Err(err) => {
+                            error!("error while processing task {}: {:?}", done.handle, err);
+                            self.queue.nacknowledge_task(done.handle)
+                        }
+                    };
+                    drop_if_redelivered(result, &task.description)?;
+                    task.partition
+                }
+            };
+
+            if let Some(partition) = finished_partition {
+                let next = waiting
+                    .get_mut(&partition)
+                    .and_then(|handles| handles.pop_front());
+                match next {
+                    Some(handle) => {
+                        waiting_count -= 1;
+                        spawn(handle, Some(partition), &mut running);
+                    }
+                    None => {
+                        waiting.remove(&partition);
+                        busy_partitions.remove(&partition);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Passes on the result of acknowledging or nacknowledging a task, unless it
+/// failed because the task's receipt handle expired. The queue has given up
+/// on our copy of the task and delivered it again, maybe to another worker,
+/// so there is nothing left for us to do with it.
+fn drop_if_redelivered(result: Result<()>, task: &str) -> Result<()> {
+    match result {
+        Err(err) if matches!(err.downcast_ref(), Some(Error::ReceiptHandleExpired(_))) => {
+            warn!(
+                "task {} was redelivered before we were done with it, dropping our copy: {:?}",
+                task, err
+            );
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// What the harness tracks about a task while it is being processed.
+struct Running {
+    /// When the task is abandoned, and the processing deadline that was
+    /// computed from.
+    deadline: Option<(Instant, Duration)>,
+    acknowledgment_id: String,
+    description: String,
+    partition: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::{InMemoryTaskQueue, IntakeBatchTask};
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+        time::Duration,
+    };
+
+    #[test]
+    fn serial_within_partition_concurrent_across_partitions() {
+        let mut queue = InMemoryTaskQueue::new();
+        for index in 0..3 {
+            for partition in &["a", "b"] {
+                queue
+                    .enqueue(&IntakeBatchTask {
+                        aggregation_id: partition.to_string(),
+                        batch_id: format!("{}{}", partition, index),
+                        date: "2020/10/31/20/29".to_owned(),
+                    })
+                    .unwrap();
+            }
+        }
+
+        let mut harness = WorkerHarness::new(Box::new(queue.clone()));
+        harness.set_concurrency(4).unwrap();
+        harness.set_partition_key(Arc::new(|task: &IntakeBatchTask| {
+            task.aggregation_id.clone()
+        }));
+
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let running_per_partition = Arc::new(Mutex::new(HashMap::<String, usize>::new()));
+        let processed = Arc::new(Mutex::new(Vec::new()));
+        let handler = {
+            let running = running.clone();
+            let max_running = max_running.clone();
+            let running_per_partition = running_per_partition.clone();
+            let processed = processed.clone();
+            Arc::new(move |task: &IntakeBatchTask| {
+                let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_running.fetch_max(now_running, Ordering::SeqCst);
+                {
+                    let mut per_partition = running_per_partition.lock().unwrap();
+                    let count = per_partition
+                        .entry(task.aggregation_id.clone())
+                        .or_default();
+                    *count += 1;
+                    assert_eq!(*count, 1, "partition processed concurrently");
+                }
+                thread::sleep(Duration::from_millis(50));
+                processed.lock().unwrap().push(task.batch_id.clone());
+                *running_per_partition
+                    .lock()
+                    .unwrap()
+                    .get_mut(&task.aggregation_id)
+                    .unwrap() -= 1;
+                running.fetch_sub(1, Ordering::SeqCst);
+                Ok(TaskOutcome::Ack)
+            })
+        };
+
+        assert_eq!(harness.process_available(handler).unwrap(), 6);
+
+        // Tasks in each partition were processed in order
+        let processed = processed.lock().unwrap();
+        for partition in &["a", "b"] {
+            let in_partition: Vec<String> = processed
+                .iter()
+                .filter(|batch_id| batch_id.starts_with(partition))
+                .cloned()
+                .collect();
+            let expected: Vec<String> = (0..3)
+                .map(|index| format!("{}{}", partition, index))
+                .collect();
+            assert_eq!(in_partition, expected);
+        }
+        // The two partitions were processed concurrently, but never more
+        // than one task per partition at a time
+        assert_eq!(max_running.load(Ordering::SeqCst), 2);
+        assert_eq!(queue.acknowledged_tasks().unwrap().len(), 6);
+        assert_eq!(queue.in_flight_count(), 0);
+    }
+
+    #[test]
+    fn failed_tasks_are_nacknowledged() {
+        let mut queue = InMemoryTaskQueue::new();
+        for batch_id in &["ok", "fail"] {
+            queue
+                .enqueue(&IntakeBatchTask {
+                    aggregation_id: "fake-aggregation".to_owned(),
+                    batch_id: batch_id.to_string(),
+                    date: "2020/10/31/20/29".to_owned(),
+                })
+                .unwrap();
+        }
+
+        let mut harness = WorkerHarness::new(Box::new(queue.clone()));
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let handler = {
+            let attempts = attempts.clone();
+            Arc::new(move |task: &IntakeBatchTask| {
+                // Fail only the first attempt, so that the harness stops
+                if task.batch_id == "fail" && attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                    return Err(anyhow!("failed"));
+                }
+                Ok(TaskOutcome::Ack)
+            })
+        };
+
+        // The nacknowledged task goes back on the queue and is retried
+        assert_eq!(harness.process_available(handler).unwrap(), 3);
+        assert_eq!(queue.acknowledged_tasks().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn outcomes_map_to_queue_operations() {
+        let mut queue = InMemoryTaskQueue::new();
+        for batch_id in &["ack", "retry-now", "retry-after", "dead-letter"] {
+            queue
+                .enqueue(&IntakeBatchTask {
+                    aggregation_id: "fake-aggregation".to_owned(),
+                    batch_id: batch_id.to_string(),
+                    date: "2020/10/31/20/29".to_owned(),
+                })
+                .unwrap();
+        }
+
+        let mut harness = WorkerHarness::new(Box::new(queue.clone()));
+        let retries = Arc::new(AtomicUsize::new(0));
+        let handler = {
+            let retries = retries.clone();
+            Arc::new(move |task: &IntakeBatchTask| {
+                Ok(match task.batch_id.as_str() {
+                    // Retried right away, then handled on the second attempt
+                    "retry-now" if retries.fetch_add(1, Ordering::SeqCst) == 0 => {
+                        TaskOutcome::RetryNow
+                    }
+                    "retry-after" => TaskOutcome::RetryAfter(Duration::from_secs(3600)),
+                    "dead-letter" => TaskOutcome::DeadLetter,
+                    _ => TaskOutcome::Ack,
+                })
+            })
+        };
+
+        assert_eq!(harness.process_available(handler).unwrap(), 5);
+        let acknowledged: Vec<String> = queue
+            .acknowledged_tasks()
+            .unwrap()
+            .into_iter()
+            .map(|task| task.batch_id)
+            .collect();
+        assert_eq!(acknowledged, vec!["ack", "retry-now"]);
+        assert_eq!(queue.delayed_count(), 1);
+        let dead_lettered = queue.dead_lettered_tasks().unwrap();
+        assert_eq!(dead_lettered.len(), 1);
+        assert_eq!(dead_lettered[0].0.batch_id, "dead-letter");
+        assert_eq!(queue.queued_count(), 0);
+        assert_eq!(queue.in_flight_count(), 0);
+    }
+
+    /// A task that takes as long to handle as it allows.
+    #[derive(Debug, serde::Deserialize, serde::Serialize)]
+    struct DeadlineTask {
+        name: String,
+        deadline_millis: u64,
+    }
+
+    impl Task for DeadlineTask {
+        fn processing_deadline(&self) -> Option<Duration> {
+            Some(Duration::from_millis(self.deadline_millis))
+        }
+    }
+
+    impl fmt::Display for DeadlineTask {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{}", self.name)
+        }
+    }
+
+    #[test]
+    fn overdue_tasks_are_nacknowledged() {
+        let mut queue = InMemoryTaskQueue::new();
+        queue
+            .enqueue(&DeadlineTask {
+                name: "slow".to_owned(),
+                deadline_millis: 50,
+            })
+            .unwrap();
+
+        let mut harness = WorkerHarness::new(Box::new(queue.clone()));
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let handler = {
+            let attempts = attempts.clone();
+            Arc::new(move |_: &DeadlineTask| {
+                // Only the first attempt overruns
+                if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                    thread::sleep(Duration::from_secs(2));
+                }
+                Ok(TaskOutcome::Ack)
+            })
+        };
+
+        let started = Instant::now();
+        // The abandoned attempt is nacknowledged and retried
+        assert_eq!(harness.process_available(handler).unwrap(), 2);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        assert_eq!(queue.acknowledged_tasks().unwrap().len(), 1);
+        assert_eq!(queue.in_flight_count(), 0);
+        assert_eq!(queue.queued_count(), 0);
+    }
+
+    /// A task at some position in a group's sequence.
+    #[derive(Debug, serde::Deserialize, serde::Serialize)]
+    struct SequencedTask {
+        group: String,
+        sequence: u64,
+    }
+
+    impl Task for SequencedTask {
+        fn sequence(&self) -> Option<(String, u64)> {
+            Some((self.group.clone(), self.sequence))
+        }
+    }
+
+    impl fmt::Display for SequencedTask {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{}/{}", self.group, self.sequence)
+        }
+    }
+
+    #[test]
+    fn sequence_gaps_are_detected() {
+        for &on_violation in &[
+            OnSequenceViolation::Process,
+            OnSequenceViolation::RetryAfter(Duration::from_secs(3600)),
+        ] {
+            let mut queue = InMemoryTaskQueue::new();
+            for &(group, sequence) in &[("a", 1), ("b", 7), ("a", 3), ("b", 8)] {
+                queue
+                    .enqueue(&SequencedTask {
+                        group: group.to_owned(),
+                        sequence,
+                    })
+                    .unwrap();
+            }
+
+            let mut harness = WorkerHarness::new(Box::new(queue.clone()));
+            harness.set_sequence_validation(on_violation);
+            let handler = Arc::new(|_: &SequencedTask| Ok(TaskOutcome::Ack));
+
+            let processed = harness.process_available(handler).unwrap();
+            assert_eq!(harness.sequence_violations(), 1);
+            let acknowledged: Vec<String> = queue
+                .acknowledged_tasks()
+                .unwrap()
+                .into_iter()
+                .map(|task: SequencedTask| task.to_string())
+                .collect();
+            if on_violation == OnSequenceViolation::Process {
+                assert_eq!(processed, 4);
+                assert_eq!(acknowledged, vec!["a/1", "b/7", "a/3", "b/8"]);
+                assert_eq!(queue.delayed_count(), 0);
+            } else {
+                // The task after the gap waits for the missing one
+                assert_eq!(processed, 3);
+                assert_eq!(acknowledged, vec!["a/1", "b/7", "b/8"]);
+                assert_eq!(queue.delayed_count(), 1);
+            }
+        }
+    }
+}
@@ -0,0 +1,344 @@
+use crate::task::{AsyncTaskQueue, Task, TaskHandle, TaskQueue};
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use ring::digest;
+use serde::Serialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Default)]
+struct Messages {
+    /// Messages available to be dequeued, as (acknowledgment ID, body) pairs.
+    queued: VecDeque<(String, String)>,
+    /// Bodies of messages that have been dequeued but neither acknowledged nor
+    /// nacknowledged, keyed by acknowledgment ID.
+    in_flight: HashMap<String, String>,
+    /// Bodies of acknowledged messages, in the order they were acknowledged.
+    acknowledged: Vec<String>,
+    /// Messages nacknowledged with a delay, as (available at, acknowledgment
+    /// ID, body), which are returned to the back of the queue once available.
+    delayed: Vec<(Instant, String, String)>,
+    /// Bodies of dead lettered messages along with the reasons they were dead
+    /// lettered, in the order they were dead lettered.
+    dead_lettered: Vec<(String, String)>,
+    /// If set, messages enqueued within this long of an earlier message with
+    /// the same deduplication ID are dropped.
+    deduplication_window: Option<Duration>,
+    /// When each deduplication ID was last accepted.
+    deduplication_ids: HashMap<String, Instant>,
+    next_id: u64,
+}
+
+impl Messages {
+    /// Returns delayed messages whose delay has passed to the queue.
+    fn requeue_delayed(&mut self) {
+        let now = Instant::now();
+        let (available, delayed) = self
+            .delayed
+            .drain(..)
+            .partition(|(available_at, _, _)| *available_at <= now);
+        self.delayed = delayed;
+        for (_, id, body) in available {
+            self.queued.push_back((id, body));
+        }
+    }
+
+    /// Returns true if a message with the provided deduplication ID was
+    /// accepted within the deduplication window, and otherwise records that
+    /// one has been accepted now.
+    fn is_duplicate(&mut self, deduplication_id: String) -> bool {
+        let window = match self.deduplication_window {
+            Some(window) => window,
+            None => return false,
+        };
+        let now = Instant::now();
+        self.deduplication_ids
+            .retain(|_, accepted_at| now.duration_since(*accepted_at) < window);
+        if self.deduplication_ids.contains_key(&deduplication_id) {
+            return true;
+        }
+        self.deduplication_ids.insert(deduplication_id, now);
+        false
+    }
+
+    fn remove_in_flight(&mut self, acknowledgment_id: &str) -> Result<String> {
+        self.in_flight
+            .remove(acknowledgment_id)
+            .ok_or_else(|| anyhow!("no in flight task {}", acknowledgment_id))
+    }
+}
+
+/// A task queue backed by memory. Like the queues backed by cloud services,
+/// tasks are stored as their JSON encoding and decoded when they are dequeued.
+/// Nacknowledged tasks are returned to the back of the queue. Clones of an
+/// InMemoryTaskQueue share the same messages, so a clone may be handed to code
+/// under test while the original is used to inspect the queue.
+#[derive(Debug)]
+pub struct InMemoryTaskQueue<T: Task> {
+    messages: Arc<Mutex<Messages>>,
+    phantom_task: PhantomData<*const T>,
+}
+
+impl<T: Task> Clone for InMemoryTaskQueue<T> {
+    fn clone(&self) -> Self {
+        InMemoryTaskQueue {
+            messages: self.messages.clone(),
+            phantom_task: PhantomData,
+        }
+    }
+}
+
+impl<T: Task> Default for InMemoryTaskQueue<T> {
+    fn default() -> Self {
+        InMemoryTaskQueue {
+            messages: Arc::new(Mutex::new(Messages::default())),
+            phantom_task: PhantomData,
+        }
+    }
+}
+
+impl<T: Task> InMemoryTaskQueue<T> {
+    pub fn new() -> InMemoryTaskQueue<T> {
+        InMemoryTaskQueue::default()
+    }
+
+    /// Emulates the deduplication done by SQS FIFO queues: once set, a message
+    /// enqueued within window of an earlier one with the same deduplication ID
+    /// is silently dropped. Messages enqueued without an explicit
+    /// deduplication ID are identified by the SHA-256 hash of their body, as
+    /// with content-based deduplication.
+    /// https://docs.aws.amazon.com/AWSSimpleQueueService/latest/SQSDeveloperGuide/using-messagededuplicationid-property.html
+    pub fn set_deduplication_window(&mut self, window: Duration) {
+        self.messages.lock().unwrap().deduplication_window = Some(window);
+    }
+
+    /// Adds a message with the provided body to the back of the queue.
+    pub fn enqueue_body(&mut self, body: &str) {
+        let deduplication_id = base64::encode(digest::digest(&digest::SHA256, body.as_bytes()));
+        self.enqueue_body_with_deduplication_id(body, &deduplication_id);
+    }
+
+    /// Like enqueue_body, but the message is deduplicated by the provided ID
+    /// rather than by its content.
+    pub fn enqueue_body_with_deduplication_id(&mut self, body: &str, deduplication_id: &str) {
+        let mut messages = self.messages.lock().unwrap();
+        if messages.is_duplicate(deduplication_id.to_owned()) {
+            return;
+        }
+        let id = format!("message-{}", messages.next_id);
+        messages.next_id += 1;
+        messages.queued.push_back((id, body.to_owned()));
+    }
+
+    /// Returns the number of tasks waiting to be dequeued.
+    pub fn queued_count(&self) -> usize {
+        self.messages.lock().unwrap().queued.len()
+    }
+
+    /// Returns the number of tasks that have been dequeued but neither
+    /// acknowledged nor nacknowledged.
+    pub fn in_flight_count(&self) -> usize {
+        self.messages.lock().unwrap().in_flight.len()
+    }
+
+    /// Returns the number of tasks that were nacknowledged with a delay that has
+    /// not yet passed.
+    pub fn delayed_count(&self) -> usize {
+        let mut messages = self.messages.lock().unwrap();
+        messages.requeue_delayed();
+        messages.delayed.len()
+    }
+
+    /// Returns the tasks that have been dead lettered along with the reasons
+    /// they were, in the order they were dead lettered.
+    pub fn dead_lettered_tasks(&self) -> Result<Vec<(T, String)>> {
+        self.messages
+            .lock()
+            .unwrap()
+            .dead_lettered
+            .iter()
+            .map(|(body, reason)| Ok((decode_task(body)?, reason.clone())))
+            .collect()
+    }
+
+    /// Returns the tasks that have been acknowledged, in the order they were
+    /// acknowledged.
+    pub fn acknowledged_tasks(&self) -> Result<Vec<T>> {
+        self.messages
+            .lock()
+            .unwrap()
+            .acknowledged
+            .iter()
+            .map(|body| decode_task(body))
+            .collect()
+    }
+}
+
+impl<T: Task + Serialize> InMemoryTaskQueue<T> {
+    /// Adds the provided task to the back of the queue.
+    pub fn enqueue(&mut self, task: &T) -> Result<()> {
+        let body = serde_json::to_string(task).context("failed to encode task")?;
+        self.enqueue_body(&body);
+        Ok(())
+    }
+
+    /// Like enqueue, but the task is deduplicated by the provided ID rather
+    /// than by its content.
+    pub fn enqueue_with_deduplication_id(
+        &mut self,
+        task: &T,
+        deduplication_id: &str,
+    ) -> Result<()> {
+        let body = serde_json::to_string(task).context("failed to encode task")?;
+        self.enqueue_body_with_deduplication_id(&body, deduplication_id);
+        Ok(())
+    }
+}
+
+fn decode_task<T: Task>(body: &str) -> Result<T> {
+    serde_json::from_str(body).context(format!("failed to decode JSON task {:?}", body))
+}
+
+impl<T: Task> TaskQueue<T> for InMemoryTaskQueue<T> {
+    fn dequeue(&mut self) -> Result<Option<TaskHandle<T>>> {
+        let (id, body) = match self.dequeue_raw()? {
+            Some(message) => message,
+            None => return Ok(None),
+        };
+        // As with SQS, a message that can't be decoded stays in flight.
+        Ok(Some(TaskHandle {
+            task: decode_task(&body)?,
+            acknowledgment_id: id,
+            body,
+        }))
+    }
+
+    fn dequeue_raw(&mut self) -> Result<Option<(String, String)>> {
+        let mut messages = self.messages.lock().unwrap();
+        messages.requeue_delayed();
+        let (id, body) = match messages.queued.pop_front() {
+            Some(message) => message,
+            None => return Ok(None),
+        };
+        messages.in_flight.insert(id.clone(), body.clone());
+        Ok(Some((id, body)))
+    }
+
+    fn acknowledge_raw(&mut self, acknowledgment_id: &str) -> Result<()> {
+        let mut messages = self.messages.lock().unwrap();
+        let body = messages.remove_in_flight(acknowledgment_id)?;
+        messages.acknowledged.push(body);
+        Ok(())
+    }
+
+    fn nacknowledge_raw(&mut self, acknowledgment_id: &str) -> Result<()> {
+        let mut messages = self.messages.lock().unwrap();
+        let body = messages.remove_in_flight(acknowledgment_id)?;
+        messages
+            .queued
+            .push_back((acknowledgment_id.to_owned(), body));
+        Ok(())
+    }
+
+    fn retry_raw_after(&mut self, acknowledgment_id: &str, delay: Duration) -> Result<()> {
+        let mut messages = self.messages.lock().unwrap();
+        let body = messages.remove_in_flight(acknowledgment_id)?;
+        messages
+            .delayed
+            .push((Instant::now() + delay, acknowledgment_id.to_owned(), body));
+        Ok(())
+    }
+
+    fn dead_letter_raw(
+        &mut self,
+        acknowledgment_id: &str,
+        _body: &str,
+        reason: &str,
+    ) -> Result<()> {
+        let mut messages = self.messages.lock().unwrap();
+        let body = messages.remove_in_flight(acknowledgment_id)?;
+        messages.dead_lettered.push((body, reason.to_owned()));
+        Ok(())
+    }
+}
+
+// None of InMemoryTaskQueue's operations block, so they can be used as they
+// are from an async runtime.
+#[async_trait(?Send)]
+impl<T: Task> AsyncTaskQueue<T> for InMemoryTaskQueue<T> {
+    async fn dequeue(&mut self) -> Result<Option<TaskHandle<T>>> {
+        TaskQueue::dequeue(self)
+    }
+
+    async fn acknowledge_task(&mut self, handle: TaskHandle<T>) -> Result<()> {
+        TaskQueue::acknowledge_task(self, handle)
+    }
+
+    async fn nacknowledge_task(&mut self, handle: TaskHandle<T>) -> Result<()> {
+        TaskQueue::nacknowledge_task(self, handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::IntakeBatchTask;
+
+    #[test]
+    fn dequeue_raw_returns_undecodable_body() {
+        let mut queue = InMemoryTaskQueue::<IntakeBatchTask>::new();
+        queue.enqueue_body("not a task");
+        queue.enqueue_body("also {not a task");
+
+        assert!(TaskQueue::dequeue(&mut queue).is_err());
+
+        let (acknowledgment_id, body) = queue.dequeue_raw().unwrap().unwrap();
+        assert_eq!(body, "also {not a task");
+        queue.acknowledge_raw(&acknowledgment_id).unwrap();
+        assert_eq!(queue.in_flight_count(), 1);
+        assert_eq!(queue.queued_count(), 0);
+        assert!(queue.dequeue_raw().unwrap().is_none());
+    }
+
+    #[test]
+    fn deduplication() {
+        let task = |batch_id: &str| IntakeBatchTask {
+            aggregation_id: "fake-aggregation".to_owned(),
+            batch_id: batch_id.to_owned(),
+            date: "2020/10/31/20/29".to_owned(),
+        };
+        let mut queue = InMemoryTaskQueue::<IntakeBatchTask>::new();
+        queue.set_deduplication_window(Duration::from_secs(300));
+
+        queue.enqueue(&task("batch-1")).unwrap();
+        queue.enqueue(&task("batch-1")).unwrap();
+        // Same content as batch-1, but explicitly distinct
+        queue
+            .enqueue_with_deduplication_id(&task("batch-1"), "retry")
+            .unwrap();
+        // Different content, but explicitly a duplicate
+        queue
+            .enqueue_with_deduplication_id(&task("batch-2"), "retry")
+            .unwrap();
+        queue.enqueue(&task("batch-3")).unwrap();
+
+        let mut dequeued = Vec::new();
+        while let Some(handle) = TaskQueue::dequeue(&mut queue).unwrap() {
+            dequeued.push(handle.task.batch_id.clone());
+            TaskQueue::acknowledge_task(&mut queue, handle).unwrap();
+        }
+        assert_eq!(dequeued, vec!["batch-1", "batch-1", "batch-3"]);
+
+        // Once the window has passed, the task is accepted again
+        let mut queue = InMemoryTaskQueue::<IntakeBatchTask>::new();
+        queue.set_deduplication_window(Duration::from_millis(1));
+        queue.enqueue(&task("batch-1")).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        queue.enqueue(&task("batch-1")).unwrap();
+        assert_eq!(queue.queued_count(), 2);
+    }
+}
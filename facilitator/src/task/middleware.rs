@@ -0,0 +1,355 @@
+use crate::{
+    cache::LruCache,
+    task::{Task, TaskHandle, TaskQueue},
+};
+use anyhow::{Context, Result};
+use log::{info, warn};
+use std::{collections::HashMap, fmt::Debug, io::Read, time::Duration};
+
+/// The first two bytes of any gzip stream.
+/// https://tools.ietf.org/html/rfc1952#section-2.3.1
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Behavior applied to every message of a queue wrapped in a MiddlewareQueue,
+/// such as decoding, validation or metrics, so that consumers of the queue
+/// don't each have to wire it up.
+pub trait Middleware: Debug {
+    /// Called with the body of each message dequeued, returning the body to
+    /// pass on to the next middleware, or to decode the task from if this is
+    /// the last one. Failing marks the message as one that can never be
+    /// handled.
+    fn on_dequeue(&mut self, acknowledgment_id: &str, body: String) -> Result<String>;
+
+    /// Called before the message with the provided acknowledgment ID is
+    /// acknowledged, or dead lettered, which removes it from the queue just
+    /// the same. Failing leaves the message in flight.
+    fn on_ack(&mut self, acknowledgment_id: &str) -> Result<()> {
+        let _ = acknowledgment_id;
+        Ok(())
+    }
+
+    /// Called before the message with the provided acknowledgment ID is
+    /// nacknowledged or retried later. Failing leaves the message in flight.
+    fn on_nack(&mut self, acknowledgment_id: &str) -> Result<()> {
+        let _ = acknowledgment_id;
+        Ok(())
+    }
+}
+
+/// A task queue that passes the messages of an underlying queue through a
+/// chain of middlewares. Dequeued messages go through the middlewares in the
+/// order they were added, while acknowledgments and nacknowledgments go
+/// through them in the opposite order, so that each middleware sees a message
+/// leave as it saw it arrive. Dequeue leaves decoding tasks to the underlying
+/// queue, which disposes of messages that a middleware rejects or that don't
+/// decode into a task as it would dispose of any other undecodable message.
+#[derive(Debug)]
+pub struct MiddlewareQueue<T: Task> {
+    queue: Box<dyn TaskQueue<T>>,
+    middlewares: Vec<Box<dyn Middleware>>,
+}
+
+impl<T: Task> MiddlewareQueue<T> {
+    /// Wraps the provided queue in an empty chain of middlewares, through
+    /// which messages pass unchanged.
+    pub fn new(queue: Box<dyn TaskQueue<T>>) -> MiddlewareQueue<T> {
+        MiddlewareQueue {
+            queue,
+            middlewares: Vec::new(),
+        }
+    }
+
+    /// Adds the provided middleware to the end of the chain.
+    pub fn with(mut self, middleware: impl Middleware + 'static) -> MiddlewareQueue<T> {
+        self.middlewares.push(Box::new(middleware));
+        self
+    }
+
+    /// Returns the chain of middlewares as a single middleware, followed by
+    /// then if provided.
+    fn chain<'a>(&'a mut self, then: Option<&'a mut dyn Middleware>) -> Chain<'a> {
+        Chain {
+            middlewares: &mut self.middlewares,
+            then,
+        }
+    }
+
+    fn before_ack(&mut self, acknowledgment_id: &str) -> Result<()> {
+        self.chain(None).on_ack(acknowledgment_id)
+    }
+
+    fn before_nack(&mut self, acknowledgment_id: &str) -> Result<()> {
+        self.chain(None).on_nack(acknowledgment_id)
+    }
+}
+
+/// A chain of middlewares, optionally followed by one more, acting as one.
+#[derive(Debug)]
+struct Chain<'a> {
+    middlewares: &'a mut [Box<dyn Middleware>],
+    then: Option<&'a mut dyn Middleware>,
+}
+
+impl Middleware for Chain<'_> {
+    fn on_dequeue(&mut self, acknowledgment_id: &str, mut body: String) -> Result<String> {
+        for middleware in self.middlewares.iter_mut() {
+            body = middleware.on_dequeue(acknowledgment_id, body)?;
+        }
+        match &mut self.then {
+            Some(then) => then.on_dequeue(acknowledgment_id, body),
+            None => Ok(body),
+        }
+    }
+
+    fn on_ack(&mut self, acknowledgment_id: &str) -> Result<()> {
+        if let Some(then) = &mut self.then {
+            then.on_ack(acknowledgment_id)?;
+        }
+        for middleware in self.middlewares.iter_mut().rev() {
+            middleware.on_ack(acknowledgment_id)?;
+        }
+        Ok(())
+    }
+
+    fn on_nack(&mut self, acknowledgment_id: &str) -> Result<()> {
+        if let Some(then) = &mut self.then {
+            then.on_nack(acknowledgment_id)?;
+        }
+        for middleware in self.middlewares.iter_mut().rev() {
+            middleware.on_nack(acknowledgment_id)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Task> TaskQueue<T> for MiddlewareQueue<T> {
+    fn dequeue(&mut self) -> Result<Option<TaskHandle<T>>> {
+        // The underlying queue decodes the task, so that messages the chain
+        // rejects or that don't decode are disposed of as it would dispose of
+        // them, and the handle keeps the body as it was received, so that it
+        // is dead lettered in a form the underlying queue's consumers know.
+        let mut chain = Chain {
+            middlewares: &mut self.middlewares,
+            then: None,
+        };
+        self.queue.dequeue_through(&mut chain)
+    }
+
+    fn dequeue_through(
+        &mut self,
+        middleware: &mut dyn Middleware,
+    ) -> Result<Option<TaskHandle<T>>> {
+        let mut chain = Chain {
+            middlewares: &mut self.middlewares,
+            then: Some(middleware),
+        };
+        self.queue.dequeue_through(&mut chain)
+    }
+
+    /// Like dequeue, but returns the body as the middlewares transformed it.
+    /// If a middleware rejects the message, it is left in flight and the
+    /// error is returned.
+    fn dequeue_raw(&mut self) -> Result<Option<(String, String)>> {
+        let (acknowledgment_id, body) = match self.queue.dequeue_raw()? {
+            Some(message) => message,
+            None => return Ok(None),
+        };
+        let body = self
+            .chain(None)
+            .on_dequeue(&acknowledgment_id, body)
+            .with_context(|| format!("middleware rejected message {}", acknowledgment_id))?;
+        Ok(Some((acknowledgment_id, body)))
+    }
+
+    fn acknowledge_raw(&mut self, acknowledgment_id: &str) -> Result<()> {
+        self.before_ack(acknowledgment_id)?;
+        self.queue.acknowledge_raw(acknowledgment_id)
+    }
+
+    fn nacknowledge_raw(&mut self, acknowledgment_id: &str) -> Result<()> {
+        self.before_nack(acknowledgment_id)?;
+        self.queue.nacknowledge_raw(acknowledgment_id)
+    }
+
+    fn retry_raw_after(&mut self, acknowledgment_id: &str, delay: Duration) -> Result<()> {
+        self.before_nack(acknowledgment_id)?;
+        self.queue.retry_raw_after(acknowledgment_id, delay)
+    }
+
+    fn dead_letter_raw(&mut self, acknowledgment_id: &str, body: &str, reason: &str) -> Result<()> {
+        self.before_ack(acknowledgment_id)?;
+        self.queue.dead_letter_raw(acknowledgment_id, body, reason)
+    }
+}
+
+/// Decompresses the bodies of messages whose producers gzipped them, to fit
+/// bigger tasks in a message, and then encoded them in base64, since message
+/// bodies are text. Bodies that aren't base64 encoded gzip streams, such as
+/// plain JSON tasks, are passed on unchanged, so that producers can start
+/// compressing their messages at their own pace.
+#[derive(Debug, Default)]
+pub struct GzipDecode;
+
+impl Middleware for GzipDecode {
+    fn on_dequeue(&mut self, acknowledgment_id: &str, body: String) -> Result<String> {
+        let compressed = match base64::decode(&body) {
+            Ok(compressed) if compressed.starts_with(&GZIP_MAGIC) => compressed,
+            _ => return Ok(body),
+        };
+        let mut decompressed = String::new();
+        libflate::gzip::Decoder::new(compressed.as_slice())
+            .and_then(|mut decoder| decoder.read_to_string(&mut decompressed))
+            .with_context(|| format!("failed to decompress message {}", acknowledgment_id))?;
+        Ok(decompressed)
+    }
+}
+
+/// Logs how many times this worker has received each message, warning about
+/// messages received more than once so that ones that keep being redelivered
+/// stand out. Messages are told apart by their bodies, since their
+/// acknowledgment IDs may change with every delivery. A message is forgotten
+/// once it is acknowledged, or ttl after it was last received, and no more
+/// than capacity messages are remembered at once.
+#[derive(Debug)]
+pub struct ReceiveCountLogger {
+    receive_counts: LruCache<u32>,
+    /// Bodies of the messages in flight, by acknowledgment ID.
+    in_flight: HashMap<String, String>,
+}
+
+impl ReceiveCountLogger {
+    pub fn new(capacity: usize, ttl: Duration) -> ReceiveCountLogger {
+        ReceiveCountLogger {
+            receive_counts: LruCache::new(capacity, ttl),
+            in_flight: HashMap::new(),
+        }
+    }
+}
+
+impl Middleware for ReceiveCountLogger {
+    fn on_dequeue(&mut self, acknowledgment_id: &str, body: String) -> Result<String> {
+        let receive_count = self.receive_counts.get(&body).unwrap_or(0) + 1;
+        self.receive_counts.insert(&body, receive_count);
+        if receive_count > 1 {
+            warn!(
+                "message {} received {} times by this worker",
+                acknowledgment_id, receive_count
+            );
+        } else {
+            info!("message {} received for the first time", acknowledgment_id);
+        }
+        self.in_flight
+            .insert(acknowledgment_id.to_owned(), body.clone());
+        Ok(body)
+    }
+
+    fn on_ack(&mut self, acknowledgment_id: &str) -> Result<()> {
+        if let Some(body) = self.in_flight.remove(acknowledgment_id) {
+            self.receive_counts.remove(&body);
+        }
+        Ok(())
+    }
+
+    fn on_nack(&mut self, acknowledgment_id: &str) -> Result<()> {
+        self.in_flight.remove(acknowledgment_id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::{InMemoryTaskQueue, IntakeBatchTask};
+    use std::{
+        io::Write,
+        sync::{Arc, Mutex},
+    };
+
+    /// Records the bodies it sees in a log shared with the test, prefixed with
+    /// its name.
+    #[derive(Debug)]
+    struct RecordingMiddleware {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Middleware for RecordingMiddleware {
+        fn on_dequeue(&mut self, _acknowledgment_id: &str, body: String) -> Result<String> {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("{} dequeued {}", self.name, body));
+            Ok(body)
+        }
+
+        fn on_ack(&mut self, _acknowledgment_id: &str) -> Result<()> {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("{} acknowledged", self.name));
+            Ok(())
+        }
+    }
+
+    fn intake_task() -> IntakeBatchTask {
+        IntakeBatchTask {
+            aggregation_id: "fake-aggregation".to_owned(),
+            batch_id: "fake-batch".to_owned(),
+            date: "2020/10/31/20/29".to_owned(),
+        }
+    }
+
+    fn gzipped(body: &str) -> String {
+        let mut gzip = libflate::gzip::Encoder::new(Vec::new()).unwrap();
+        gzip.write_all(body.as_bytes()).unwrap();
+        base64::encode(gzip.finish().into_result().unwrap())
+    }
+
+    #[test]
+    fn middlewares_run_in_order() {
+        let body = serde_json::to_string(&intake_task()).unwrap();
+        let mut inner = InMemoryTaskQueue::<IntakeBatchTask>::new();
+        inner.enqueue_body(&gzipped(&body));
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut queue = MiddlewareQueue::new(Box::new(inner.clone()))
+            .with(GzipDecode)
+            .with(RecordingMiddleware {
+                name: "first",
+                log: log.clone(),
+            })
+            .with(RecordingMiddleware {
+                name: "second",
+                log: log.clone(),
+            });
+
+        let handle = queue.dequeue().unwrap().unwrap();
+        assert_eq!(handle.task, intake_task());
+        queue.acknowledge_task(handle).unwrap();
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                format!("first dequeued {}", body),
+                format!("second dequeued {}", body),
+                "second acknowledged".to_owned(),
+                "first acknowledged".to_owned(),
+            ]
+        );
+        assert_eq!(inner.in_flight_count(), 0);
+    }
+
+    #[test]
+    fn rejected_message_is_dead_lettered() {
+        let mut inner = InMemoryTaskQueue::<IntakeBatchTask>::new();
+        // Has the gzip magic number, but isn't a gzip stream
+        let corrupt = base64::encode([0x1f, 0x8b, 0, 0]);
+        inner.enqueue_body(&corrupt);
+        let mut queue = MiddlewareQueue::new(Box::new(inner.clone()))
+            .with(GzipDecode)
+            .with(ReceiveCountLogger::new(10, Duration::from_secs(60)));
+
+        assert!(queue.dequeue().unwrap().is_none());
+        assert_eq!(inner.queued_count(), 0);
+        assert_eq!(inner.in_flight_count(), 0);
+    }
+}
@@ -0,0 +1,303 @@
+use crate::task::{Middleware, Task, TaskHandle, TaskQueue};
+use anyhow::{anyhow, Context, Result};
+use std::time::Duration;
+
+/// A task queue that consumes from several underlying queues in weighted
+/// round-robin order: each queue is asked for up to its weight in tasks in a
+/// row before moving on to the next one, and empty queues are skipped. The
+/// acknowledgment IDs of the handles it returns are tagged with the index of
+/// the queue the task came from, so that acknowledgments and
+/// nacknowledgments are routed back to that queue. Finding out that a queue
+/// is empty takes as long as that queue's dequeue waits for a task to arrive,
+/// so when every queue is empty, a dequeue takes the sum of their waits. Long
+/// polling queues like AwsSqsTaskQueue, which waits 20 seconds by default,
+/// should be given a short receive_wait_time before being wrapped in one.
+#[derive(Debug)]
+pub struct MultiQueue<T: Task> {
+    queues: Vec<WeightedQueue<T>>,
+    /// Index of the queue the next dequeue will try first.
+    current: usize,
+    /// How many tasks in a row have been dequeued from the current queue.
+    dequeued_from_current: u32,
+}
+
+#[derive(Debug)]
+struct WeightedQueue<T: Task> {
+    queue: Box<dyn TaskQueue<T>>,
+    weight: u32,
+}
+
+impl<T: Task> MultiQueue<T> {
+    /// Creates a MultiQueue that gives each of the provided queues the same
+    /// share of dequeues.
+    pub fn new(queues: Vec<Box<dyn TaskQueue<T>>>) -> Result<MultiQueue<T>> {
+        MultiQueue::new_weighted(queues.into_iter().map(|queue| (queue, 1)).collect())
+    }
+
+    /// Creates a MultiQueue over the provided (queue, weight) pairs. A queue
+    /// with weight n gets up to n dequeues in a row when it has tasks
+    /// available.
+    pub fn new_weighted(queues: Vec<(Box<dyn TaskQueue<T>>, u32)>) -> Result<MultiQueue<T>> {
+        if queues.is_empty() {
+            return Err(anyhow!("MultiQueue requires at least one queue"));
+        }
+        if queues.iter().any(|(_, weight)| *weight == 0) {
+            return Err(anyhow!("MultiQueue weights must be positive"));
+        }
+        Ok(MultiQueue {
+            queues: queues
+                .into_iter()
+                .map(|(queue, weight)| WeightedQueue { queue, weight })
+                .collect(),
+            current: 0,
+            dequeued_from_current: 0,
+        })
+    }
+
+    fn advance(&mut self) {
+        self.current = (self.current + 1) % self.queues.len();
+        self.dequeued_from_current = 0;
+    }
+
+    /// Dequeues from the queues in weighted round-robin order using the
+    /// provided function, which is passed each queue along with its index,
+    /// returning what it dequeued along with the index of the queue it came
+    /// from.
+    fn dequeue_next<R>(
+        &mut self,
+        mut dequeue: impl FnMut(&mut dyn TaskQueue<T>, usize) -> Result<Option<R>>,
+    ) -> Result<Option<(usize, R)>> {
+        // Try each queue at most once, starting from the current one.
+        for _ in 0..self.queues.len() {
+            let index = self.current;
+            match dequeue(self.queues[index].queue.as_mut(), index)? {
+                Some(dequeued) => {
+                    self.dequeued_from_current += 1;
+                    if self.dequeued_from_current >= self.queues[index].weight {
+                        self.advance();
+                    }
+                    return Ok(Some((index, dequeued)));
+                }
+                None => self.advance(),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Removes the tag added by dequeue from the acknowledgment ID, returning
+    /// the index of the queue the task came from and an acknowledgment ID
+    /// suitable for that queue.
+    fn untag<'a>(&self, acknowledgment_id: &'a str) -> Result<(usize, &'a str)> {
+        let mut components = acknowledgment_id.splitn(2, '/');
+        let index = components
+            .next()
+            .and_then(|index| index.parse::<usize>().ok())
+            .filter(|index| *index < self.queues.len())
+            .with_context(|| {
+                format!(
+                    "acknowledgment ID {} did not come from this MultiQueue",
+                    acknowledgment_id
+                )
+            })?;
+        Ok((index, components.next().unwrap_or_default()))
+    }
+}
+
+impl<T: Task> TaskQueue<T> for MultiQueue<T> {
+    fn dequeue(&mut self) -> Result<Option<TaskHandle<T>>> {
+        Ok(self
+            .dequeue_next(|queue, _| queue.dequeue())?
+            .map(|(index, handle)| TaskHandle {
+                acknowledgment_id: format!("{}/{}", index, handle.acknowledgment_id),
+                body: handle.body,
+                task: handle.task,
+            }))
+    }
+
+    fn dequeue_through(
+        &mut self,
+        middleware: &mut dyn Middleware,
+    ) -> Result<Option<TaskHandle<T>>> {
+        Ok(self
+            .dequeue_next(|queue, index| {
+                queue.dequeue_through(&mut Tagged {
+                    index,
+                    middleware: &mut *middleware,
+                })
+            })?
+            .map(|(index, handle)| TaskHandle {
+                acknowledgment_id: format!("{}/{}", index, handle.acknowledgment_id),
+                body: handle.body,
+                task: handle.task,
+            }))
+    }
+
+    fn dequeue_raw(&mut self) -> Result<Option<(String, String)>> {
+        Ok(self.dequeue_next(|queue, _| queue.dequeue_raw())?.map(
+            |(index, (acknowledgment_id, body))| (format!("{}/{}", index, acknowledgment_id), body),
+        ))
+    }
+
+    fn acknowledge_raw(&mut self, acknowledgment_id: &str) -> Result<()> {
+        let (index, acknowledgment_id) = self.untag(acknowledgment_id)?;
+        self.queues[index].queue.acknowledge_raw(acknowledgment_id)
+    }
+
+    fn nacknowledge_raw(&mut self, acknowledgment_id: &str) -> Result<()> {
+        let (index, acknowledgment_id) = self.untag(acknowledgment_id)?;
+        self.queues[index].queue.nacknowledge_raw(acknowledgment_id)
+    }
+
+    fn retry_raw_after(&mut self, acknowledgment_id: &str, delay: Duration) -> Result<()> {
+        let (index, acknowledgment_id) = self.untag(acknowledgment_id)?;
+        self.queues[index]
+            .queue
+            .retry_raw_after(acknowledgment_id, delay)
+    }
+
+    fn dead_letter_raw(&mut self, acknowledgment_id: &str, body: &str, reason: &str) -> Result<()> {
+        let (index, acknowledgment_id) = self.untag(acknowledgment_id)?;
+        self.queues[index]
+            .queue
+            .dead_letter_raw(acknowledgment_id, body, reason)
+    }
+}
+
+/// Passes the messages of the queue with the provided index on to a
+/// middleware under the tagged acknowledgment IDs MultiQueue hands out.
+#[derive(Debug)]
+struct Tagged<'a> {
+    index: usize,
+    middleware: &'a mut dyn Middleware,
+}
+
+impl Middleware for Tagged<'_> {
+    fn on_dequeue(&mut self, acknowledgment_id: &str, body: String) -> Result<String> {
+        self.middleware
+            .on_dequeue(&format!("{}/{}", self.index, acknowledgment_id), body)
+    }
+
+    fn on_ack(&mut self, acknowledgment_id: &str) -> Result<()> {
+        self.middleware
+            .on_ack(&format!("{}/{}", self.index, acknowledgment_id))
+    }
+
+    fn on_nack(&mut self, acknowledgment_id: &str) -> Result<()> {
+        self.middleware
+            .on_nack(&format!("{}/{}", self.index, acknowledgment_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::{InMemoryTaskQueue, IntakeBatchTask};
+
+    fn intake_task(batch_id: &str) -> IntakeBatchTask {
+        IntakeBatchTask {
+            aggregation_id: "fake-aggregation".to_owned(),
+            batch_id: batch_id.to_owned(),
+            date: "2020/10/31/20/29".to_owned(),
+        }
+    }
+
+    /// Creates queues named "a", "b", "c"... containing the provided number of
+    /// tasks, whose batch IDs are the queue name followed by the task's index.
+    fn queues(sizes: &[usize]) -> Vec<InMemoryTaskQueue<IntakeBatchTask>> {
+        sizes
+            .iter()
+            .enumerate()
+            .map(|(queue_index, size)| {
+                let mut queue = InMemoryTaskQueue::new();
+                for task_index in 0..*size {
+                    queue
+                        .enqueue(&intake_task(&format!(
+                            "{}{}",
+                            (b'a' + queue_index as u8) as char,
+                            task_index
+                        )))
+                        .unwrap();
+                }
+                queue
+            })
+            .collect()
+    }
+
+    fn dequeue_all(multi_queue: &mut MultiQueue<IntakeBatchTask>) -> Vec<String> {
+        let mut batch_ids = Vec::new();
+        while let Some(handle) = multi_queue.dequeue().unwrap() {
+            batch_ids.push(handle.task.batch_id.clone());
+            multi_queue.acknowledge_task(handle).unwrap();
+        }
+        batch_ids
+    }
+
+    #[test]
+    fn round_robin() {
+        let queues = queues(&[3, 1, 2]);
+        let mut multi_queue = MultiQueue::new(
+            queues
+                .iter()
+                .map(|queue| Box::new(queue.clone()) as Box<dyn TaskQueue<IntakeBatchTask>>)
+                .collect(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            dequeue_all(&mut multi_queue),
+            vec!["a0", "b0", "c0", "a1", "c1", "a2"]
+        );
+
+        // Each acknowledgment went back to the queue the task came from
+        for (queue, expected) in
+            queues
+                .iter()
+                .zip(&[vec!["a0", "a1", "a2"], vec!["b0"], vec!["c0", "c1"]])
+        {
+            let acknowledged: Vec<String> = queue
+                .acknowledged_tasks()
+                .unwrap()
+                .into_iter()
+                .map(|task| task.batch_id)
+                .collect();
+            assert_eq!(&acknowledged, expected);
+            assert_eq!(queue.queued_count(), 0);
+            assert_eq!(queue.in_flight_count(), 0);
+        }
+    }
+
+    #[test]
+    fn weighted_round_robin() {
+        let queues = queues(&[4, 4]);
+        let mut multi_queue = MultiQueue::new_weighted(vec![
+            (Box::new(queues[0].clone()), 1),
+            (Box::new(queues[1].clone()), 3),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            dequeue_all(&mut multi_queue),
+            vec!["a0", "b0", "b1", "b2", "a1", "b3", "a2", "a3"]
+        );
+    }
+
+    #[test]
+    fn nacknowledge_routing() {
+        let queues = queues(&[1, 1]);
+        let mut multi_queue = MultiQueue::new(vec![
+            Box::new(queues[0].clone()),
+            Box::new(queues[1].clone()),
+        ])
+        .unwrap();
+
+        let first = multi_queue.dequeue().unwrap().unwrap();
+        let second = multi_queue.dequeue().unwrap().unwrap();
+        multi_queue.nacknowledge_task(second).unwrap();
+        multi_queue.acknowledge_task(first).unwrap();
+
+        assert_eq!(queues[0].queued_count(), 0);
+        assert_eq!(queues[0].acknowledged_tasks().unwrap().len(), 1);
+        assert_eq!(queues[1].queued_count(), 1);
+        assert!(queues[1].acknowledged_tasks().unwrap().is_empty());
+    }
+}
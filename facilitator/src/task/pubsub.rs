@@ -7,10 +7,14 @@ use crate::{
 use anyhow::{anyhow, Context, Result};
 use log::info;
 use serde::Deserialize;
-use std::{io::Cursor, marker::PhantomData};
+use std::{marker::PhantomData, time::Duration};
 
 const PUBSUB_API_BASE_URL: &str = "https://pubsub.googleapis.com";
 
+/// The longest ack deadline Pub/Sub allows.
+/// https://cloud.google.com/pubsub/docs/reference/rest/v1/projects.subscriptions/modifyAckDeadline
+const MAX_ACK_DEADLINE_SECONDS: u64 = 600;
+
 /// Represents the response to a subscription.pull request. See API doc for
 /// discussion of fields.
 /// https://cloud.google.com/pubsub/docs/reference/rest/v1/projects.subscriptions/pull#response-body
@@ -79,11 +83,28 @@ impl<T: Task> GcpPubSubTaskQueue<T> {
 
 impl<T: Task> TaskQueue<T> for GcpPubSubTaskQueue<T> {
     fn dequeue(&mut self) -> Result<Option<TaskHandle<T>>> {
+        let (acknowledgment_id, task_json) = match self.dequeue_raw()? {
+            Some(message) => message,
+            None => return Ok(None),
+        };
+
+        let task: T = serde_json::from_str(&task_json)
+            .context(format!("failed to decode task {:?} from JSON", task_json))?;
+
+        let handle = TaskHandle {
+            task: task,
+            acknowledgment_id,
+            body: task_json,
+        };
+
+        Ok(Some(handle))
+    }
+
+    fn dequeue_raw(&mut self) -> Result<Option<(String, String)>> {
         info!(
             "pull task from {}/{} as {:?}",
             self.gcp_project_id, self.subscription_id, self.oauth_token_provider
         );
-
         // API reference: https://cloud.google.com/pubsub/docs/reference/rest/v1/projects.subscriptions/pull
         let url = format!(
             "{}/v1/projects/{}/subscriptions/{}:pull",
@@ -135,24 +156,18 @@ impl<T: Task> TaskQueue<T> for GcpPubSubTaskQueue<T> {
         let task_json = base64::decode(&received_messages[0].message.data)
             .context("failed to decode PubSub message")?;
 
-        let task: T = serde_json::from_reader(Cursor::new(&task_json))
-            .context(format!("failed to decode task {:?} from JSON", task_json))?;
-
-        let handle = TaskHandle {
-            task: task,
-            acknowledgment_id: received_messages[0].ack_id.clone(),
-        };
-
-        Ok(Some(handle))
+        // Messages that aren't UTF-8 can't be tasks anyway, but whatever
+        // they contain should still be visible to whoever is inspecting them.
+        Ok(Some((
+            received_messages[0].ack_id.clone(),
+            String::from_utf8_lossy(&task_json).into_owned(),
+        )))
     }
 
-    fn acknowledge_task(&mut self, handle: TaskHandle<T>) -> Result<()> {
+    fn acknowledge_raw(&mut self, acknowledgment_id: &str) -> Result<()> {
         info!(
             "acknowledging task {} in topic {}/{} as {:?}",
-            handle.acknowledgment_id,
-            self.gcp_project_id,
-            self.subscription_id,
-            self.oauth_token_provider
+            acknowledgment_id, self.gcp_project_id, self.subscription_id, self.oauth_token_provider
         );
 
         // API reference: https://cloud.google.com/pubsub/docs/reference/rest/v1/projects.subscriptions/acknowledge
@@ -165,14 +180,14 @@ impl<T: Task> TaskQueue<T> for GcpPubSubTaskQueue<T> {
             request: ureq::post(&url),
             token_provider: Some(&mut self.oauth_token_provider),
             body: ureq::json!({
-                "ackIds": [handle.acknowledgment_id]
+                "ackIds": [acknowledgment_id]
             }),
             ..Default::default()
         })?;
         if http_response.error() {
             return Err(anyhow!(
-                "failed to acknowledge task {:?}: {:?}",
-                handle,
+                "failed to acknowledge task {}: {:?}",
+                acknowledgment_id,
                 http_response
             ));
         }
@@ -180,15 +195,40 @@ impl<T: Task> TaskQueue<T> for GcpPubSubTaskQueue<T> {
         Ok(())
     }
 
-    fn nacknowledge_task(&mut self, handle: TaskHandle<T>) -> Result<()> {
+    fn nacknowledge_raw(&mut self, acknowledgment_id: &str) -> Result<()> {
         info!(
             "nacknowledging task {} in topic {}/{} as {:?}",
-            handle.acknowledgment_id,
+            acknowledgment_id, self.gcp_project_id, self.subscription_id, self.oauth_token_provider,
+        );
+
+        self.modify_ack_deadline(acknowledgment_id, 0)
+            .context("failed to nacknowledge task")
+    }
+
+    fn retry_raw_after(&mut self, acknowledgment_id: &str, delay: Duration) -> Result<()> {
+        info!(
+            "retrying task {} in topic {}/{} after {:?} as {:?}",
+            acknowledgment_id,
             self.gcp_project_id,
             self.subscription_id,
+            delay,
             self.oauth_token_provider,
         );
 
+        // The message is redelivered once its ack deadline passes, which can
+        // be put off by at most ten minutes.
+        self.modify_ack_deadline(
+            acknowledgment_id,
+            delay.as_secs().min(MAX_ACK_DEADLINE_SECONDS),
+        )
+        .context("failed to delay task")
+    }
+}
+
+impl<T: Task> GcpPubSubTaskQueue<T> {
+    /// Sets the ack deadline of the message with the provided acknowledgment
+    /// ID to the provided number of seconds from now.
+    fn modify_ack_deadline(&mut self, acknowledgment_id: &str, seconds: u64) -> Result<()> {
         // API reference: https://cloud.google.com/pubsub/docs/reference/rest/v1/projects.subscriptions/modifyAckDeadline
         let url = format!(
             "{}/v1/projects/{}/subscriptions/{}:modifyAckDeadline",
@@ -199,15 +239,15 @@ impl<T: Task> TaskQueue<T> for GcpPubSubTaskQueue<T> {
             request: ureq::post(&url),
             token_provider: Some(&mut self.oauth_token_provider),
             body: ureq::json!({
-                "ackIds": [handle.acknowledgment_id],
-                "ackDeadlineSeconds": 0,
+                "ackIds": [acknowledgment_id],
+                "ackDeadlineSeconds": seconds,
             }),
             ..Default::default()
         })?;
         if http_response.error() {
             return Err(anyhow!(
-                "failed to nacknowledge task {:?}: {:?}",
-                handle,
+                "failed to modify ack deadline of task {}: {:?}",
+                acknowledgment_id,
                 http_response
             ));
         }
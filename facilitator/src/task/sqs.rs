@@ -1,18 +1,235 @@
 use anyhow::{anyhow, Context, Result};
 use derivative::Derivative;
-use log::info;
-use rusoto_core::Region;
+use log::{error, info, warn};
+use rusoto_core::{Region, RusotoError};
 use rusoto_sqs::{
-    ChangeMessageVisibilityRequest, DeleteMessageRequest, ReceiveMessageRequest, Sqs, SqsClient,
+    ChangeMessageVisibilityError, ChangeMessageVisibilityRequest, CreateQueueRequest,
+    DeleteMessageError, DeleteMessageRequest, Message, ReceiveMessageError, ReceiveMessageRequest,
+    SendMessageBatchRequest, SendMessageBatchRequestEntry, SendMessageRequest, Sqs, SqsClient,
+};
+use serde::Serialize;
+use std::{
+    cmp::min, collections::HashMap, io::Read, marker::PhantomData, mem, str::FromStr, sync::Arc,
+    thread, time::Duration,
 };
-use std::{marker::PhantomData, str::FromStr};
 use tokio::runtime::Runtime;
 
 use crate::{
-    aws_credentials::{basic_runtime, DefaultCredentialsProvider},
-    task::{Task, TaskHandle, TaskQueue},
+    aws_credentials::{basic_runtime, CredentialSourceProvider},
+    credentials::CredentialSource,
+    retries::{BackoffStrategy, ExponentialWithJitter},
+    task::{
+        DeadLetterEvent, DeadLetterSink, ExternalizedTask, LogDeadLetterSink, Middleware,
+        StopSignal, Task, TaskHandle, TaskQueue,
+    },
+    transport::Transport,
+    Error,
 };
 
+/// How long messages received by AwsSqsTaskQueue::peek stay invisible to other
+/// consumers if we fail to return them to the queue.
+const PEEK_VISIBILITY_TIMEOUT_SECONDS: i64 = 5;
+
+/// The longest a ReceiveMessage request may wait for messages to arrive.
+/// https://docs.aws.amazon.com/AWSSimpleQueueService/latest/SQSDeveloperGuide/sqs-short-and-long-polling.html#sqs-long-polling
+const MAX_RECEIVE_WAIT_TIME_SECONDS: u64 = 20;
+
+/// The longest visibility timeout SQS allows.
+const MAX_VISIBILITY_TIMEOUT_SECONDS: u64 = 43_200;
+
+/// SQS limits how many messages may be sent in one SendMessageBatch request,
+/// and how big they may be in total. The size limit also applies to a single
+/// message.
+/// https://docs.aws.amazon.com/AWSSimpleQueueService/latest/APIReference/API_SendMessageBatch.html
+const MAX_SEND_MESSAGE_BATCH_ENTRIES: usize = 10;
+const MAX_SEND_MESSAGE_BATCH_BYTES: usize = 262_144;
+
+/// SQS limits how many messages one ReceiveMessage request may return.
+/// https://docs.aws.amazon.com/AWSSimpleQueueService/latest/APIReference/API_ReceiveMessage.html
+const MAX_RECEIVE_MESSAGE_BATCH_ENTRIES: usize = 10;
+
+/// The system attributes that ReceiveMessage can return.
+/// https://docs.aws.amazon.com/AWSSimpleQueueService/latest/APIReference/API_ReceiveMessage.html#SQS-ReceiveMessage-request-AttributeNames
+const SYSTEM_ATTRIBUTE_NAMES: [&str; 9] = [
+    "All",
+    "ApproximateFirstReceiveTimestamp",
+    "ApproximateReceiveCount",
+    "AWSTraceHeader",
+    "SenderId",
+    "SentTimestamp",
+    "MessageDeduplicationId",
+    "MessageGroupId",
+    "SequenceNumber",
+];
+
+/// Options for configuring an AwsSqsTaskQueue.
+#[derive(Clone, Debug)]
+pub struct AwsSqsTaskQueueOptions {
+    /// Consulted when SQS refuses to deliver messages because the queue has
+    /// too many messages in flight (i.e., received but not yet deleted).
+    /// https://docs.aws.amazon.com/AWSSimpleQueueService/latest/SQSDeveloperGuide/sqs-quotas.html
+    /// The attempt count passed to the strategy is the number of consecutive
+    /// dequeue calls that have encountered the error. Once the strategy
+    /// returns None, the error is returned from dequeue.
+    pub over_limit_backoff: Arc<dyn BackoffStrategy>,
+    /// URL of the queue to which messages that can never be decoded into a
+    /// task are moved. If None, such messages are left in the queue to be
+    /// handled by the queue's redrive policy, if any.
+    /// https://docs.aws.amazon.com/AWSSimpleQueueService/latest/SQSDeveloperGuide/sqs-dead-letter-queues.html
+    pub dead_letter_queue_url: Option<String>,
+    /// Source of the credentials used to authenticate to SQS. If None, or if
+    /// the source has no AWS credentials, credentials are sourced from
+    /// environment variables, ~/.aws/credentials and the other sources
+    /// rusoto's default provider chain consults.
+    pub credential_source: Option<Arc<dyn CredentialSource>>,
+    /// Names of the system attributes, like SentTimestamp or
+    /// ApproximateReceiveCount, requested with each received message. None
+    /// are requested by default, which keeps responses small.
+    pub system_attribute_names: Vec<String>,
+    /// Names of the message attributes requested with each received message.
+    /// A name may end in ".*" to request every attribute with that prefix, or
+    /// be "All". None are requested by default.
+    /// https://docs.aws.amazon.com/AWSSimpleQueueService/latest/SQSDeveloperGuide/sqs-message-metadata.html#sqs-message-attributes
+    pub message_attribute_names: Vec<String>,
+    /// Name of a message attribute that producers set to the ID of the KMS key
+    /// the queue's server-side encryption used for the message. SQS decrypts
+    /// SSE-KMS messages before delivering them and doesn't say whether a
+    /// message was encrypted, so this is how consumers find out. If set, the
+    /// attribute is requested with each received message and the key ID is
+    /// logged. A message without the attribute most likely came from a
+    /// misconfigured producer or was sent to an unencrypted queue: it is dead
+    /// lettered and dequeue fails. If None, messages aren't checked.
+    /// https://docs.aws.amazon.com/AWSSimpleQueueService/latest/SQSDeveloperGuide/sqs-server-side-encryption.html
+    pub encryption_attribute_name: Option<String>,
+    /// Told about each message moved to the dead letter queue, along with its
+    /// ApproximateReceiveCount if that is among system_attribute_names.
+    /// Defaults to a LogDeadLetterSink.
+    pub dead_letter_sink: Arc<dyn DeadLetterSink>,
+    /// How long dequeue waits for a message to arrive in an empty queue, in
+    /// whole seconds up to the 20 SQS allows, which is the default. Waiting
+    /// longer means fewer requests, but a consumer that polls several queues
+    /// in turn, like a MultiQueue, waits this long on each empty queue before
+    /// moving on to the next one, so such queues should wait briefly or not
+    /// at all. Zero makes dequeue return right away, with short polling.
+    pub receive_wait_time: Duration,
+}
+
+impl AwsSqsTaskQueueOptions {
+    /// Checks that every requested attribute name is one SQS would accept, so
+    /// that a typo is caught when the queue is created rather than on the
+    /// first receive.
+    fn validate_attribute_names(&self) -> Result<()> {
+        for name in &self.system_attribute_names {
+            if !SYSTEM_ATTRIBUTE_NAMES.contains(&name.as_str()) {
+                return Err(anyhow!("unknown SQS system attribute name {:?}", name));
+            }
+        }
+        for name in &self.message_attribute_names {
+            validate_message_attribute_name(name)?;
+        }
+        if let Some(name) = &self.encryption_attribute_name {
+            if name == "All" || name.ends_with(".*") {
+                return Err(anyhow!(
+                    "encryption attribute name {:?} must name a single attribute",
+                    name
+                ));
+            }
+            validate_message_attribute_name(name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks that the provided name, optionally followed by ".*", follows SQS's
+/// rules for message attribute names.
+/// https://docs.aws.amazon.com/AWSSimpleQueueService/latest/APIReference/API_ReceiveMessage.html#SQS-ReceiveMessage-request-MessageAttributeNames
+fn validate_message_attribute_name(name: &str) -> Result<()> {
+    if name == "All" {
+        return Ok(());
+    }
+    let base = name.strip_suffix(".*").unwrap_or(name);
+    let lowercase = base.to_lowercase();
+    let valid = !base.is_empty()
+        && base.len() <= 256
+        && base
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        && !base.starts_with('.')
+        && !base.ends_with('.')
+        && !base.contains("..")
+        && !lowercase.starts_with("aws.")
+        && !lowercase.starts_with("amazon.");
+    if !valid {
+        return Err(anyhow!("invalid SQS message attribute name {:?}", name));
+    }
+    Ok(())
+}
+
+/// Returns the provided names, or None if there are none, so that they are
+/// left out of a request entirely.
+fn non_empty(names: &[String]) -> Option<Vec<String>> {
+    if names.is_empty() {
+        None
+    } else {
+        Some(names.to_vec())
+    }
+}
+
+impl Default for AwsSqsTaskQueueOptions {
+    fn default() -> Self {
+        AwsSqsTaskQueueOptions {
+            // The in-flight limit only clears as workers acknowledge tasks, so
+            // there's no point polling aggressively: wait a long time, and
+            // keep waiting for as long as the condition persists.
+            over_limit_backoff: Arc::new(ExponentialWithJitter {
+                initial_delay: Duration::from_secs(30),
+                max_delay: Duration::from_secs(300),
+                max_attempts: u32::MAX,
+                ..Default::default()
+            }),
+            dead_letter_queue_url: None,
+            credential_source: None,
+            system_attribute_names: Vec::new(),
+            message_attribute_names: Vec::new(),
+            encryption_attribute_name: None,
+            dead_letter_sink: Arc::new(LogDeadLetterSink),
+            receive_wait_time: Duration::from_secs(MAX_RECEIVE_WAIT_TIME_SECONDS),
+        }
+    }
+}
+
+/// Attributes with which AwsSqsTaskQueue::ensure_queue creates a queue. Any
+/// attribute left as None takes SQS's default value.
+/// https://docs.aws.amazon.com/AWSSimpleQueueService/latest/APIReference/API_CreateQueue.html
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SqsQueueAttributes {
+    /// How long SQS retains a message that is never deleted, between one
+    /// minute and 14 days.
+    pub message_retention_period: Option<Duration>,
+    /// How long delivery of every message sent to the queue is delayed, up to
+    /// 15 minutes.
+    pub delay: Option<Duration>,
+    /// How long a received message stays invisible to other consumers unless
+    /// it is deleted or its visibility is changed, up to 12 hours.
+    pub visibility_timeout: Option<Duration>,
+}
+
+impl SqsQueueAttributes {
+    /// Returns the attributes in the form CreateQueue takes them.
+    fn to_attribute_map(&self) -> HashMap<String, String> {
+        [
+            ("MessageRetentionPeriod", self.message_retention_period),
+            ("DelaySeconds", self.delay),
+            ("VisibilityTimeout", self.visibility_timeout),
+        ]
+        .iter()
+        .filter_map(|(name, value)| {
+            value.map(|value| (name.to_string(), value.as_secs().to_string()))
+        })
+        .collect()
+    }
+}
+
 /// A task queue backed by AWS SQS
 #[derive(Derivative)]
 #[derivative(Debug)]
@@ -21,123 +238,2148 @@ pub struct AwsSqsTaskQueue<T: Task> {
     client: SqsClient,
     queue_url: String,
     runtime: Runtime,
+    options: AwsSqsTaskQueueOptions,
+    /// How many consecutive dequeue calls have failed because the queue has
+    /// too many messages in flight.
+    over_limit_errors: u32,
+    /// Where the tasks of messages holding an ExternalizedTask are fetched
+    /// from, if anywhere.
+    payload_transport: Option<Box<dyn Transport>>,
+    /// Whether acknowledging an externalized task deletes the object it was
+    /// fetched from.
+    delete_payload_on_acknowledge: bool,
+    /// Keys of the objects from which the tasks of messages that have been
+    /// dequeued but not yet acknowledged were fetched, by receipt handle.
+    payload_keys: HashMap<String, String>,
+    /// ApproximateReceiveCount of the messages that have been dequeued but not
+    /// yet acknowledged, by receipt handle, for those received with it.
+    receive_counts: HashMap<String, u32>,
+    /// Aborts long polls for messages when raised.
+    stop_signal: Option<StopSignal>,
     phantom_task: PhantomData<*const T>,
 }
 
 impl<T: Task> AwsSqsTaskQueue<T> {
-    pub fn new(region: &str, queue_url: &str) -> Result<AwsSqsTaskQueue<T>> {
+    pub fn new(
+        region: &str,
+        queue_url: &str,
+        options: AwsSqsTaskQueueOptions,
+    ) -> Result<AwsSqsTaskQueue<T>> {
         let region = Region::from_str(region).context("invalid AWS region")?;
-        let runtime = basic_runtime()?;
 
-        // Credentials for authenticating to AWS are automatically
-        // sourced from environment variables or ~/.aws/credentials.
+        // Unless the options provide a credential source, credentials for
+        // authenticating to AWS are automatically sourced from environment
+        // variables or ~/.aws/credentials.
         // https://github.com/rusoto/rusoto/blob/master/AWS-CREDENTIALS.md
-        let credentials_provider =
-            DefaultCredentialsProvider::new().context("failed to create credentials provider")?;
+        let credentials_provider = CredentialSourceProvider::new(options.credential_source.clone())
+            .context("failed to create credentials provider")?;
 
         let http_client = rusoto_core::HttpClient::new().context("failed to create HTTP client")?;
 
+        AwsSqsTaskQueue::new_with_client(
+            SqsClient::new_with(http_client, credentials_provider, region),
+            queue_url,
+            options,
+        )
+    }
+
+    /// Creates a task queue that uses the provided client, allowing injection
+    /// of a mock SqsClient for testing.
+    fn new_with_client(
+        client: SqsClient,
+        queue_url: &str,
+        options: AwsSqsTaskQueueOptions,
+    ) -> Result<AwsSqsTaskQueue<T>> {
+        options.validate_attribute_names()?;
+        if options.receive_wait_time.as_secs() > MAX_RECEIVE_WAIT_TIME_SECONDS {
+            return Err(anyhow!(
+                "SQS receive wait time {:?} exceeds the {} seconds SQS allows",
+                options.receive_wait_time,
+                MAX_RECEIVE_WAIT_TIME_SECONDS
+            ));
+        }
         Ok(AwsSqsTaskQueue {
-            client: SqsClient::new_with(http_client, credentials_provider, region),
+            client,
             queue_url: queue_url.to_owned(),
-            runtime: runtime,
+            runtime: basic_runtime()?,
+            options,
+            over_limit_errors: 0,
+            payload_transport: None,
+            delete_payload_on_acknowledge: false,
+            payload_keys: HashMap::new(),
+            receive_counts: HashMap::new(),
+            stop_signal: None,
             phantom_task: PhantomData,
         })
     }
-}
 
-impl<T: Task> TaskQueue<T> for AwsSqsTaskQueue<T> {
-    fn dequeue(&mut self) -> Result<Option<TaskHandle<T>>> {
-        info!("pull task from {}", self.queue_url);
+    /// Makes dequeue fetch the task of a message whose body is an
+    /// ExternalizedTask from the object it names in the provided transport,
+    /// so that producers can enqueue tasks bigger than SQS allows. If
+    /// delete_on_acknowledge is true, acknowledging such a task deletes the
+    /// object. A task that can't be decoded is dead lettered with the
+    /// object's contents in place of the pointer to it, after which the
+    /// object is likewise deleted. Other messages are decoded as usual.
+    pub fn set_payload_transport(
+        &mut self,
+        transport: Box<dyn Transport>,
+        delete_on_acknowledge: bool,
+    ) {
+        self.payload_transport = Some(transport);
+        self.delete_payload_on_acknowledge = delete_on_acknowledge;
+    }
 
+    /// Makes raising the provided signal abort any long poll dequeue is in the
+    /// middle of, so that a worker shutting down needn't wait for the poll to
+    /// run its course. Once the signal is raised, dequeue and dequeue_raw
+    /// return Ok(None) without asking SQS for messages. Acknowledgments and
+    /// the like are unaffected, so tasks in progress can still be disposed
+    /// of. Any message SQS was about to deliver to an aborted poll stays
+    /// invisible until its visibility timeout lapses.
+    pub fn set_stop_signal(&mut self, stop_signal: StopSignal) {
+        self.stop_signal = Some(stop_signal);
+    }
+
+    /// Creates the queue this task queue consumes from, named by the last path
+    /// segment of its URL, with the provided attributes if it does not already
+    /// exist. CreateQueue is idempotent, so this succeeds if the queue exists
+    /// with the same attributes, but SQS rejects it if the queue exists with
+    /// different ones. Queues whose names end in ".fifo" are created as FIFO
+    /// queues, as SQS requires. The queue URL SQS returns replaces the one
+    /// this queue was created with, and is returned.
+    /// https://docs.aws.amazon.com/AWSSimpleQueueService/latest/APIReference/API_CreateQueue.html
+    pub fn ensure_queue(&mut self, attributes: &SqsQueueAttributes) -> Result<String> {
+        let queue_name = self
+            .queue_url
+            .trim_end_matches('/')
+            .rsplit('/')
+            .next()
+            .filter(|name| !name.is_empty())
+            .with_context(|| format!("no queue name in queue URL {}", self.queue_url))?
+            .to_owned();
+        info!(
+            "ensuring queue {} exists with attributes {:?}",
+            queue_name, attributes
+        );
+
+        let mut attribute_map = attributes.to_attribute_map();
+        if queue_name.ends_with(".fifo") {
+            attribute_map.insert("FifoQueue".to_owned(), "true".to_owned());
+        }
+        let request = CreateQueueRequest {
+            queue_name: queue_name.clone(),
+            attributes: Some(attribute_map),
+            ..Default::default()
+        };
+        let queue_url = self
+            .runtime
+            .block_on(self.client.create_queue(request))
+            .with_context(|| format!("failed to create SQS queue {}", queue_name))?
+            .queue_url
+            .with_context(|| format!("no queue URL in response creating {}", queue_name))?;
+
+        self.queue_url = queue_url.clone();
+        Ok(queue_url)
+    }
+
+    /// Returns up to `max` tasks from the front of the queue without
+    /// permanently removing them, for use in diagnostic tooling. SQS has no
+    /// true peek operation, so this receives messages with a short visibility
+    /// timeout and then nacknowledges all of them so they promptly become
+    /// visible again.
+    ///
+    /// Because SQS delivery is at-least-once, callers should keep in mind that
+    /// peeking is not free of side effects: while peeked messages are briefly
+    /// invisible, other consumers won't see them, each peek counts as a receive
+    /// towards the queue's redrive policy, and SQS may still return a message
+    /// more than once or omit some messages from a given peek.
+    pub fn peek(&mut self, max: usize) -> Result<Vec<T>> {
+        info!("peek at up to {} tasks in {}", max, self.queue_url);
+
+        // Messages are held invisible until all receives are done, so that a
+        // later receive can't return a message we already saw.
+        let mut received = Vec::new();
+        let receiving = self.receive_for_peek(max, &mut received);
+
+        // Whatever was received must be returned to the queue even if a later
+        // receive failed, or it would be hidden from real consumers.
+        for (receipt_handle, _) in &received {
+            self.change_message_visibility(receipt_handle, 0)
+                .context("failed to return peeked message to SQS queue")?;
+        }
+        receiving?;
+
+        received
+            .iter()
+            .map(|(_, body)| AwsSqsTaskQueue::decode_task(body))
+            .collect()
+    }
+
+    /// Receives messages for peek until max have been received into received
+    /// or the queue has no more visible messages.
+    fn receive_for_peek(&mut self, max: usize, received: &mut Vec<(String, String)>) -> Result<()> {
+        while received.len() < max {
+            let request = ReceiveMessageRequest {
+                // SQS allows receiving at most 10 messages per request
+                max_number_of_messages: Some(min(max - received.len(), 10) as i64),
+                queue_url: self.queue_url.clone(),
+                // Short polling, so that we return promptly if the queue is
+                // empty.
+                wait_time_seconds: Some(0),
+                visibility_timeout: Some(PEEK_VISIBILITY_TIMEOUT_SECONDS),
+                ..Default::default()
+            };
+
+            let response = self
+                .runtime
+                .block_on(self.client.receive_message(request))
+                .context("failed to receive messages from SQS")?;
+
+            let messages = match response.messages {
+                Some(messages) if !messages.is_empty() => messages,
+                _ => break,
+            };
+
+            for message in messages {
+                let receipt_handle = message
+                    .receipt_handle
+                    .context("no receipt handle in SQS message")?;
+                let body = message.body.context("no body in SQS message")?;
+                received.push((receipt_handle, body));
+            }
+        }
+        Ok(())
+    }
+
+    /// Dequeues up to max tasks, at most 10, with a single ReceiveMessage
+    /// request, so that working through a long queue doesn't take a round
+    /// trip to SQS per task. Each message is checked and decoded on its own,
+    /// and one that can't be is handled as dequeue would handle it, then left
+    /// out of the returned tasks instead of failing the whole batch. If none
+    /// of the messages received can be decoded, receives again, so no tasks
+    /// are returned only if the queue had no messages available.
+    pub fn dequeue_batch(&mut self, max: usize) -> Result<Vec<TaskHandle<T>>> {
+        if max == 0 || max > MAX_RECEIVE_MESSAGE_BATCH_ENTRIES {
+            return Err(anyhow!(
+                "cannot dequeue {} tasks at once: SQS allows 1 to {}",
+                max,
+                MAX_RECEIVE_MESSAGE_BATCH_ENTRIES
+            ));
+        }
+        info!("pull up to {} tasks from {}", max, self.queue_url);
+
+        let mut handles = Vec::new();
+        while handles.is_empty() {
+            let messages = self.receive_messages(max)?;
+            if messages.is_empty() {
+                break;
+            }
+            for message in messages {
+                let decoded = message.and_then(|(receipt_handle, body)| {
+                    self.decode_message(receipt_handle, body, None)
+                });
+                match decoded {
+                    Ok(Some(handle)) => handles.push(handle),
+                    Ok(None) => (),
+                    Err(err) => warn!(
+                        "skipping message in batch from queue {}: {:?}",
+                        self.queue_url, err
+                    ),
+                }
+            }
+        }
+        Ok(handles)
+    }
+
+    /// Receives up to max messages, waiting up to receive_wait_time for them,
+    /// returning the receipt handle and body of each that passes
+    /// check_message. Returns no messages if none arrived before the poll
+    /// ended, if the stop signal was raised or if the queue has too many
+    /// messages in flight.
+    fn receive_messages(&mut self, max: usize) -> Result<Vec<Result<(String, String)>>> {
         let request = ReceiveMessageRequest {
-            // Dequeue one task at a time
-            max_number_of_messages: Some(1),
+            // SQS allows receiving at most 10 messages per request
+            max_number_of_messages: Some(max as i64),
             queue_url: self.queue_url.clone(),
-            // Long polling. SQS allows us to wait up to 20 seconds.
-            // https://docs.aws.amazon.com/AWSSimpleQueueService/latest/SQSDeveloperGuide/sqs-short-and-long-polling.html#sqs-long-polling
-            wait_time_seconds: Some(20),
+            wait_time_seconds: Some(self.options.receive_wait_time.as_secs() as i64),
             // Visibility timeout configures how long SQS will wait for message
             // deletion by this client before making a message visible again to
             // other queue consumers. We set it to 600s = 10 minutes.
             visibility_timeout: Some(600),
+            attribute_names: non_empty(&self.options.system_attribute_names),
+            message_attribute_names: non_empty(&self.message_attribute_names()),
             ..Default::default()
         };
 
-        let response = self
-            .runtime
-            .block_on(self.client.receive_message(request))
-            .context("failed to dequeue message from SQS")?;
-
-        let received_messages = match response.messages {
-            Some(ref messages) => messages,
-            None => return Ok(None),
+        let receive = self.client.receive_message(request);
+        let received = match &self.stop_signal {
+            Some(stop_signal) => {
+                let runtime = &mut self.runtime;
+                match stop_signal.run_until_stopped(receive, |receive| runtime.block_on(receive)) {
+                    Ok(received) => received,
+                    Err(_) => {
+                        info!("stopped pulling tasks from {}", self.queue_url);
+                        return Ok(Vec::new());
+                    }
+                }
+            }
+            None => self.runtime.block_on(receive),
         };
+        let response = match received {
+            Err(RusotoError::Service(ReceiveMessageError::OverLimit(message))) => {
+                // Polling harder won't help here: the queue only drops back
+                // under its in-flight limit once messages are deleted, so back
+                // off for a long time and tell the operator what's going on.
+                self.over_limit_errors += 1;
+                let delay = match self
+                    .options
+                    .over_limit_backoff
+                    .next_delay(self.over_limit_errors)
+                {
+                    Some(delay) => delay,
+                    None => {
+                        return Err(anyhow!(
+                            "SQS queue {} has too many messages in flight after {} attempts: {}",
+                            self.queue_url,
+                            self.over_limit_errors,
+                            message
+                        ))
+                    }
+                };
+                warn!(
+                    "SQS queue {} has too many messages in flight ({}). Tasks must be \
+                    acknowledged faster or the queue drained before more can be \
+                    received. Waiting {:?} before dequeuing again.",
+                    self.queue_url, message, delay
+                );
+                thread::sleep(delay);
+                return Ok(Vec::new());
+            }
+            response => response.context("failed to dequeue message from SQS")?,
+        };
+        self.over_limit_errors = 0;
 
-        if received_messages.len() == 0 {
-            return Ok(None);
-        }
-
-        if received_messages.len() > 1 {
+        if response.messages.as_ref().map_or(0, Vec::len) > max {
             return Err(anyhow!(
                 "unexpected number of messages in SQS response: {:?}",
                 response
             ));
         }
 
-        let body = match &received_messages[0].body {
+        Ok(response
+            .messages
+            .unwrap_or_default()
+            .iter()
+            .map(|message| self.check_message(message))
+            .collect())
+    }
+
+    /// Returns the receipt handle and body of a message SQS delivered, after
+    /// checking that it arrived intact and, if the queue expects it, that it
+    /// was encrypted. A corrupted message is returned to the queue, so that
+    /// SQS can deliver it intact, while an unencrypted one is dead lettered.
+    fn check_message(&mut self, message: &Message) -> Result<(String, String)> {
+        let body = match &message.body {
             Some(body) => body,
             None => return Err(anyhow!("no body in SQS message")),
         };
-        let receipt_handle = match &received_messages[0].receipt_handle {
+        let receipt_handle = match &message.receipt_handle {
             Some(handle) => handle,
             None => return Err(anyhow!("no receipt handle in SQS message")),
         };
+        // SQS sends the MD5 digest of every message body it delivers, so that
+        // corruption in transit can be caught before the body is decoded.
+        if let Some(md5_of_body) = &message.md5_of_body {
+            if format!("{:x}", md5::compute(body)) != md5_of_body.to_lowercase() {
+                error!(
+                    "message {} in queue {} does not match its MD5OfBody {}",
+                    receipt_handle, self.queue_url, md5_of_body
+                );
+                self.change_message_visibility(receipt_handle, 0)
+                    .context("failed to nacknowledge corrupted message in SQS")?;
+                return Err(Error::MessageCorrupted(
+                    receipt_handle.to_owned(),
+                    self.queue_url.clone(),
+                )
+                .into());
+            }
+        }
+        if let Some(receive_count) = message
+            .attributes
+            .as_ref()
+            .and_then(|attributes| attributes.get("ApproximateReceiveCount"))
+            .and_then(|count| count.parse().ok())
+        {
+            self.receive_counts
+                .insert(receipt_handle.to_owned(), receive_count);
+        }
 
-        let task = serde_json::from_reader(body.as_bytes())
-            .context(format!("failed to decode JSON task {:?}", body))?;
+        if let Some(name) = self.options.encryption_attribute_name.clone() {
+            let key_id = message
+                .message_attributes
+                .as_ref()
+                .and_then(|attributes| attributes.get(&name))
+                .and_then(|value| value.string_value.as_ref());
+            match key_id {
+                Some(key_id) => info!(
+                    "message {} in queue {} was encrypted with KMS key {}",
+                    receipt_handle, self.queue_url, key_id
+                ),
+                None => {
+                    error!(
+                        "message {} in queue {} has no {} attribute and may not have been \
+                        encrypted. Check that its producer sends to an SSE-KMS queue and \
+                        sets the attribute.",
+                        receipt_handle, self.queue_url, name
+                    );
+                    // Redelivering the message won't encrypt it, so it is set
+                    // aside rather than returned to the queue.
+                    let reason = format!(
+                        "received message without encryption attribute {} from SQS queue {}",
+                        name, self.queue_url
+                    );
+                    self.dead_letter(receipt_handle, body, &reason)
+                        .context("failed to dead letter unencrypted message in SQS")?;
+                    return Err(anyhow!(reason));
+                }
+            }
+        }
 
-        Ok(Some(TaskHandle {
-            task: task,
-            acknowledgment_id: receipt_handle.to_owned(),
-        }))
+        Ok((receipt_handle.to_owned(), body.to_owned()))
     }
 
-    fn acknowledge_task(&mut self, task: TaskHandle<T>) -> Result<()> {
+    /// Decodes the task in a message received from the queue, after passing
+    /// its task body through the provided middleware, if any. Messages that
+    /// can't be decoded are returned to the queue or dead lettered, the
+    /// middleware is told as much, and None is returned.
+    fn decode_message(
+        &mut self,
+        receipt_handle: String,
+        body: String,
+        mut middleware: Option<&mut dyn Middleware>,
+    ) -> Result<Option<TaskHandle<T>>> {
+        let received_body = self
+            .fetch_externalized_task(&receipt_handle, &body)?
+            .unwrap_or_else(|| body.clone());
+        let task_body = match &mut middleware {
+            Some(middleware) => middleware.on_dequeue(&receipt_handle, received_body.clone()),
+            None => Ok(received_body.clone()),
+        };
+
+        // A body that ends early was most likely truncated somewhere between
+        // the producer and us, so it's worth having SQS deliver it again. Any
+        // other decoding error means the message is malformed or doesn't match
+        // the task schema, and redelivering it would only fail again.
+        let reason = match task_body {
+            Ok(task_body) => match serde_json::from_str(&task_body) {
+                Ok(task) => {
+                    return Ok(Some(TaskHandle {
+                        task: task,
+                        acknowledgment_id: receipt_handle,
+                        body,
+                    }))
+                }
+                Err(err) if err.is_eof() => {
+                    warn!(
+                        "message {:?} in queue {} appears to be truncated ({}), returning it to the queue",
+                        task_body, self.queue_url, err
+                    );
+                    if let Some(middleware) = middleware {
+                        middleware.on_nack(&receipt_handle)?;
+                    }
+                    self.payload_keys.remove(&receipt_handle);
+                    self.receive_counts.remove(&receipt_handle);
+                    self.change_message_visibility(&receipt_handle, 0)
+                        .context("failed to nacknowledge truncated message in SQS")?;
+                    return Ok(None);
+                }
+                Err(err) => format!("failed to decode JSON task: {}", err),
+            },
+            Err(err) => format!("{:#}", err),
+        };
+
+        if let Some(middleware) = middleware {
+            middleware.on_ack(&receipt_handle)?;
+        }
+        match self.payload_keys.remove(&receipt_handle) {
+            // The task is moved into the dead letter queue in place of the
+            // pointer to it, so that its object isn't needed any longer.
+            Some(key) => {
+                if self.dead_letter(&receipt_handle, &received_body, &reason)? {
+                    self.delete_payload(&key);
+                }
+            }
+            None => {
+                self.dead_letter(&receipt_handle, &body, &reason)?;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the names of the message attributes to request with each
+    /// received message.
+    fn message_attribute_names(&self) -> Vec<String> {
+        let mut names = self.options.message_attribute_names.clone();
+        if let Some(name) = &self.options.encryption_attribute_name {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+        names
+    }
+
+    /// If the provided message body is an ExternalizedTask and this queue has
+    /// a payload transport, returns the task stored in the object it names.
+    /// If the object can't be fetched, the message is nacknowledged so that
+    /// it is retried.
+    fn fetch_externalized_task(
+        &mut self,
+        receipt_handle: &str,
+        body: &str,
+    ) -> Result<Option<String>> {
+        let transport = match &mut self.payload_transport {
+            Some(transport) => transport,
+            None => return Ok(None),
+        };
+        let pointer: ExternalizedTask = match serde_json::from_str(body) {
+            Ok(pointer) => pointer,
+            Err(_) => return Ok(None),
+        };
         info!(
-            "acknowledging task {} in queue {}",
-            task.acknowledgment_id, self.queue_url
+            "fetching externalized task {} from {}",
+            pointer.key,
+            transport.path()
+        );
+        let mut task_body = String::new();
+        let fetched = transport
+            .get(&pointer.key)
+            .and_then(|mut reader| Ok(reader.read_to_string(&mut task_body)?));
+        if let Err(err) = fetched {
+            self.change_message_visibility(receipt_handle, 0)
+                .context("failed to nacknowledge externalized task in SQS")?;
+            return Err(err.context(format!(
+                "failed to fetch externalized task {} from {}",
+                pointer.key,
+                self.payload_transport.as_ref().unwrap().path()
+            )));
+        }
+        self.payload_keys
+            .insert(receipt_handle.to_owned(), pointer.key);
+        Ok(Some(task_body))
+    }
+
+    /// Decodes a task from the body of an SQS message.
+    fn decode_task(body: &str) -> Result<T> {
+        serde_json::from_reader(body.as_bytes())
+            .context(format!("failed to decode JSON task {:?}", body))
+    }
+
+    /// Moves the message with the provided receipt handle and body to the dead
+    /// letter queue, if one is configured. Otherwise the message is left in
+    /// the queue, where it will become visible again once its visibility
+    /// timeout elapses and may eventually be moved by the queue's redrive
+    /// policy. Returns true if the message was moved.
+    fn dead_letter(&mut self, receipt_handle: &str, body: &str, reason: &str) -> Result<bool> {
+        let dead_letter_queue_url = match &self.options.dead_letter_queue_url {
+            Some(url) => url.clone(),
+            None => {
+                error!(
+                    "message {:?} in queue {} can never be handled ({}) and no dead letter \
+                    queue is configured",
+                    body, self.queue_url, reason
+                );
+                return Ok(false);
+            }
+        };
+        error!(
+            "moving message {:?} from queue {} to dead letter queue {}: {}",
+            body, self.queue_url, dead_letter_queue_url, reason
         );
 
+        let request = SendMessageRequest {
+            queue_url: dead_letter_queue_url,
+            message_body: body.to_owned(),
+            ..Default::default()
+        };
+        self.runtime
+            .block_on(self.client.send_message(request))
+            .context("failed to send message to dead letter queue")?;
+
+        self.delete_message(receipt_handle)
+            .context("failed to delete dead lettered message from SQS")?;
+        self.options
+            .dead_letter_sink
+            .dead_lettered(&DeadLetterEvent {
+                queue: self.queue_url.clone(),
+                body: body.to_owned(),
+                receive_count: self.receive_counts.remove(receipt_handle),
+                reason: reason.to_owned(),
+            });
+        Ok(true)
+    }
+
+    /// Deletes the object holding an externalized task that is done with, if
+    /// this queue is responsible for deleting such objects.
+    fn delete_payload(&mut self, key: &str) {
+        let transport = match &mut self.payload_transport {
+            Some(transport) if self.delete_payload_on_acknowledge => transport,
+            _ => return,
+        };
+        // Failing to delete the object only leaves garbage behind.
+        if let Err(err) = transport.delete(key) {
+            warn!(
+                "failed to delete externalized task {} from {}: {:?}",
+                key,
+                transport.path(),
+                err
+            );
+        }
+    }
+
+    /// Deletes the message with the provided receipt handle.
+    fn delete_message(&mut self, receipt_handle: &str) -> Result<()> {
         let request = DeleteMessageRequest {
             queue_url: self.queue_url.clone(),
-            receipt_handle: task.acknowledgment_id.clone(),
+            receipt_handle: receipt_handle.to_owned(),
+        };
+
+        match self.runtime.block_on(self.client.delete_message(request)) {
+            Err(RusotoError::Service(DeleteMessageError::ReceiptHandleIsInvalid(_))) => {
+                Err(Error::ReceiptHandleExpired(receipt_handle.to_owned()).into())
+            }
+            result => Ok(result?),
+        }
+    }
+
+    /// Changes the visibility timeout of the message with the provided receipt
+    /// handle.
+    fn change_message_visibility(
+        &mut self,
+        receipt_handle: &str,
+        visibility_timeout: i64,
+    ) -> Result<()> {
+        let request = ChangeMessageVisibilityRequest {
+            queue_url: self.queue_url.clone(),
+            receipt_handle: receipt_handle.to_owned(),
+            visibility_timeout,
+        };
+
+        match self
+            .runtime
+            .block_on(self.client.change_message_visibility(request))
+        {
+            Err(RusotoError::Service(ChangeMessageVisibilityError::ReceiptHandleIsInvalid(_))) => {
+                Err(Error::ReceiptHandleExpired(receipt_handle.to_owned()).into())
+            }
+            result => Ok(result.context("failed to change message visibility in SQS")?),
+        }
+    }
+}
+
+impl<T: Task + Serialize> AwsSqsTaskQueue<T> {
+    /// Adds the provided tasks to the queue using as few SendMessageBatch
+    /// requests as SQS's limits on the number and total size of messages in a
+    /// batch allow. Returns one result per task, in the same order as tasks,
+    /// since SQS may accept some messages in a batch and reject others. The
+    /// results for messages SQS rejected are crate::Error::BatchEntryRejected,
+    /// so that callers can retry just those tasks. Tasks that could not be
+    /// encoded or are too big for SQS fail without being sent.
+    pub fn enqueue_batch(&mut self, tasks: &[T]) -> Result<Vec<Result<()>>> {
+        info!("enqueue {} tasks to {}", tasks.len(), self.queue_url);
+
+        let mut results: Vec<Option<Result<()>>> = tasks.iter().map(|_| None).collect();
+        let mut batch = Vec::new();
+        let mut batch_bytes = 0;
+        for (index, task) in tasks.iter().enumerate() {
+            let body = match serde_json::to_string(task) {
+                Ok(body) => body,
+                Err(err) => {
+                    results[index] = Some(Err(anyhow!("failed to encode task: {}", err)));
+                    continue;
+                }
+            };
+            if body.len() > MAX_SEND_MESSAGE_BATCH_BYTES {
+                results[index] = Some(Err(anyhow!(
+                    "encoded task is {} bytes long, more than SQS allows in a message",
+                    body.len()
+                )));
+                continue;
+            }
+
+            if batch.len() == MAX_SEND_MESSAGE_BATCH_ENTRIES
+                || batch_bytes + body.len() > MAX_SEND_MESSAGE_BATCH_BYTES
+            {
+                self.send_message_batch(mem::take(&mut batch), &mut results)?;
+                batch_bytes = 0;
+            }
+            batch_bytes += body.len();
+            batch.push(SendMessageBatchRequestEntry {
+                // Entry IDs are the task's index, so that we can match results
+                // in the response back to tasks.
+                id: index.to_string(),
+                message_body: body,
+                ..Default::default()
+            });
+        }
+        if !batch.is_empty() {
+            self.send_message_batch(batch, &mut results)?;
+        }
+
+        Ok(results
+            .into_iter()
+            .map(|result| {
+                result.unwrap_or_else(|| Err(anyhow!("no result for task in SQS response")))
+            })
+            .collect())
+    }
+
+    /// Sends the provided entries in a single SendMessageBatch request,
+    /// storing the result for each entry in results at the index given by the
+    /// entry's ID.
+    fn send_message_batch(
+        &mut self,
+        entries: Vec<SendMessageBatchRequestEntry>,
+        results: &mut [Option<Result<()>>],
+    ) -> Result<()> {
+        let ids: Vec<usize> = entries
+            .iter()
+            .map(|entry| entry.id.parse().context("invalid batch entry ID"))
+            .collect::<Result<_>>()?;
+        let request = SendMessageBatchRequest {
+            entries,
+            queue_url: self.queue_url.clone(),
         };
 
-        Ok(self
+        let response = match self
             .runtime
-            .block_on(self.client.delete_message(request))
-            .context("failed to delete/acknowledge message in SQS")?)
+            .block_on(self.client.send_message_batch(request))
+        {
+            Ok(response) => response,
+            Err(err) => {
+                for id in ids {
+                    results[id] =
+                        Some(Err(anyhow!("failed to send message batch to SQS: {}", err)));
+                }
+                return Ok(());
+            }
+        };
+
+        let result_index = |id: &str| -> Result<usize> {
+            id.parse::<usize>()
+                .ok()
+                .filter(|index| ids.contains(index))
+                .with_context(|| format!("unexpected entry ID {} in SQS response", id))
+        };
+        for entry in response.successful {
+            results[result_index(&entry.id)?] = Some(Ok(()));
+        }
+        for entry in response.failed {
+            results[result_index(&entry.id)?] = Some(Err(Error::BatchEntryRejected(
+                entry.code,
+                entry.message.unwrap_or_default(),
+                entry.sender_fault,
+            )
+            .into()));
+        }
+        Ok(())
+    }
+}
+
+impl<T: Task> TaskQueue<T> for AwsSqsTaskQueue<T> {
+    fn dequeue(&mut self) -> Result<Option<TaskHandle<T>>> {
+        // A message that can't be decoded has already been disposed of, but
+        // the queue may have more behind it, so only a receive that returns
+        // no messages at all means there is no work available.
+        while let Some((receipt_handle, body)) = self.dequeue_raw()? {
+            if let Some(handle) = self.decode_message(receipt_handle, body, None)? {
+                return Ok(Some(handle));
+            }
+        }
+        Ok(None)
+    }
+
+    fn dequeue_through(
+        &mut self,
+        middleware: &mut dyn Middleware,
+    ) -> Result<Option<TaskHandle<T>>> {
+        while let Some((receipt_handle, body)) = self.dequeue_raw()? {
+            if let Some(handle) = self.decode_message(receipt_handle, body, Some(middleware))? {
+                return Ok(Some(handle));
+            }
+        }
+        Ok(None)
     }
 
-    fn nacknowledge_task(&mut self, task: TaskHandle<T>) -> Result<()> {
+    fn dequeue_raw(&mut self) -> Result<Option<(String, String)>> {
+        info!("pull task from {}", self.queue_url);
+        self.receive_messages(1)?.pop().transpose()
+    }
+
+    fn acknowledge_raw(&mut self, acknowledgment_id: &str) -> Result<()> {
+        info!(
+            "acknowledging task {} in queue {}",
+            acknowledgment_id, self.queue_url
+        );
+
+        self.delete_message(acknowledgment_id)
+            .context("failed to delete/acknowledge message in SQS")?;
+        self.receive_counts.remove(acknowledgment_id);
+
+        if let Some(key) = self.payload_keys.remove(acknowledgment_id) {
+            self.delete_payload(&key);
+        }
+        Ok(())
+    }
+
+    fn nacknowledge_raw(&mut self, acknowledgment_id: &str) -> Result<()> {
         // In SQS, messages are nacked by changing the message visibility
         // timeout to 0
         // https://docs.aws.amazon.com/AWSSimpleQueueService/latest/SQSDeveloperGuide/sqs-visibility-timeout.html#terminating-message-visibility-timeout
         info!(
             "nacknowledging task {} in queue {}",
-            task.acknowledgment_id, self.queue_url
+            acknowledgment_id, self.queue_url
         );
+        self.payload_keys.remove(acknowledgment_id);
+        self.receive_counts.remove(acknowledgment_id);
 
-        let request = ChangeMessageVisibilityRequest {
-            queue_url: self.queue_url.clone(),
-            receipt_handle: task.acknowledgment_id.clone(),
-            visibility_timeout: 0,
+        self.change_message_visibility(acknowledgment_id, 0)
+            .context("failed to nacknowledge message in SQS")
+    }
+
+    fn retry_raw_after(&mut self, acknowledgment_id: &str, delay: Duration) -> Result<()> {
+        info!(
+            "retrying task {} in queue {} after {:?}",
+            acknowledgment_id, self.queue_url, delay
+        );
+        self.payload_keys.remove(acknowledgment_id);
+        self.receive_counts.remove(acknowledgment_id);
+
+        // The message becomes visible again once its visibility timeout
+        // passes, which SQS allows to be at most 12 hours.
+        // https://docs.aws.amazon.com/AWSSimpleQueueService/latest/SQSDeveloperGuide/sqs-visibility-timeout.html
+        self.change_message_visibility(
+            acknowledgment_id,
+            delay.as_secs().min(MAX_VISIBILITY_TIMEOUT_SECONDS) as i64,
+        )
+        .context("failed to delay message in SQS")
+    }
+
+    fn dead_letter_raw(&mut self, acknowledgment_id: &str, body: &str, reason: &str) -> Result<()> {
+        // The dead lettered message still names the externalized task's
+        // object, which must therefore be kept.
+        self.payload_keys.remove(acknowledgment_id);
+        self.dead_letter(acknowledgment_id, body, reason)
+            .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        credentials::StaticCredentialSource,
+        retries::FixedDelay,
+        task::{GzipDecode, IntakeBatchTask, MiddlewareQueue, TaskOutcome, WorkerHarness},
+        test_utils::log_init,
+        transport::InMemoryTransport,
+    };
+    use assert_matches::assert_matches;
+    use rusoto_core::credential::AwsCredentials;
+    use rusoto_core::request::{DispatchSignedRequest, DispatchSignedRequestFuture};
+    use rusoto_core::signature::{SignedRequest, SignedRequestPayload};
+    use rusoto_mock::{
+        MockCredentialsProvider, MockRequestDispatcher, MultipleMockRequestDispatcher,
+    };
+    use std::{
+        collections::HashMap,
+        io::Write,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Mutex,
+        },
+        time::Instant,
+    };
+
+    // As in the S3 transport tests, we examine the outgoing requests with
+    // with_request_checker to make sure we issue the expected SQS API calls.
+    // SQS uses the AWS query protocol, so the action and its parameters are
+    // form-encoded into the request body.
+
+    const TEST_QUEUE_URL: &str = "https://sqs.us-west-2.amazonaws.com/12345/fake-queue";
+
+    fn request_params(request: &SignedRequest) -> HashMap<String, String> {
+        let payload = match &request.payload {
+            Some(SignedRequestPayload::Buffer(buffer)) => {
+                String::from_utf8(buffer.to_vec()).expect("payload is not UTF-8")
+            }
+            _ => panic!("unexpected payload in request {:?}", request),
         };
+        payload
+            .split('&')
+            .filter_map(|pair| {
+                let mut components = pair.splitn(2, '=');
+                let decode = |s: &str| urlencoding::decode(&s.replace("+", " ")).unwrap();
+                Some((decode(components.next()?), decode(components.next()?)))
+            })
+            .collect()
+    }
 
-        Ok(self
-            .runtime
-            .block_on(self.client.change_message_visibility(request))
-            .context("failed to change message visibility/nacknowledge message in SQS")?)
+    fn is_receive_message_request(request: &SignedRequest) {
+        // https://docs.aws.amazon.com/AWSSimpleQueueService/latest/APIReference/API_ReceiveMessage.html
+        let params = request_params(request);
+        assert_eq!(
+            params.get("Action").map(String::as_str),
+            Some("ReceiveMessage"),
+            "expected ReceiveMessage request, found {:?}",
+            params
+        );
+        assert_eq!(
+            params.get("QueueUrl").map(String::as_str),
+            Some(TEST_QUEUE_URL),
+            "unexpected queue URL in {:?}",
+            params
+        );
+    }
+
+    fn is_nacknowledge_request(receipt_handle: &'static str) -> impl Fn(&SignedRequest) {
+        is_change_visibility_request(receipt_handle, "0")
+    }
+
+    fn is_change_visibility_request(
+        receipt_handle: &'static str,
+        visibility_timeout: &'static str,
+    ) -> impl Fn(&SignedRequest) {
+        move |request: &SignedRequest| {
+            // https://docs.aws.amazon.com/AWSSimpleQueueService/latest/APIReference/API_ChangeMessageVisibility.html
+            let params = request_params(request);
+            assert_eq!(
+                params.get("Action").map(String::as_str),
+                Some("ChangeMessageVisibility"),
+                "expected ChangeMessageVisibility request, found {:?}",
+                params
+            );
+            assert_eq!(
+                params.get("ReceiptHandle").map(String::as_str),
+                Some(receipt_handle),
+                "unexpected receipt handle in {:?}",
+                params
+            );
+            assert_eq!(
+                params.get("VisibilityTimeout").map(String::as_str),
+                Some(visibility_timeout),
+                "unexpected visibility timeout in {:?}",
+                params
+            );
+        }
+    }
+
+    /// Constructs the body of a ReceiveMessage response containing the provided
+    /// (receipt handle, message body) pairs.
+    fn receive_message_response(messages: &[(&str, &str)]) -> String {
+        // Response body format from
+        // https://docs.aws.amazon.com/AWSSimpleQueueService/latest/APIReference/API_ReceiveMessage.html#API_ReceiveMessage_Examples
+        let messages: String = messages
+            .iter()
+            .enumerate()
+            .map(|(index, (receipt_handle, body))| {
+                format!(
+                    "<Message><MessageId>message-{}</MessageId>\
+                    <ReceiptHandle>{}</ReceiptHandle><Body>{}</Body></Message>",
+                    index,
+                    receipt_handle,
+                    body.replace("\"", "&quot;")
+                )
+            })
+            .collect();
+        format!(
+            "<ReceiveMessageResponse><ReceiveMessageResult>{}</ReceiveMessageResult>\
+            <ResponseMetadata><RequestId>request-id</RequestId></ResponseMetadata>\
+            </ReceiveMessageResponse>",
+            messages
+        )
+    }
+
+    const CHANGE_MESSAGE_VISIBILITY_RESPONSE: &str = "<ChangeMessageVisibilityResponse>\
+        <ResponseMetadata><RequestId>request-id</RequestId></ResponseMetadata>\
+        </ChangeMessageVisibilityResponse>";
+
+    fn intake_task_body(batch_id: &str) -> String {
+        format!(
+            r#"{{"aggregation-id":"fake-aggregation","batch-id":"{}","date":"2020/10/31/20/29"}}"#,
+            batch_id
+        )
+    }
+
+    fn intake_task(batch_id: &str) -> IntakeBatchTask {
+        IntakeBatchTask {
+            aggregation_id: "fake-aggregation".to_owned(),
+            batch_id: batch_id.to_owned(),
+            date: "2020/10/31/20/29".to_owned(),
+        }
+    }
+
+    fn queue_with_responses(
+        responses: Vec<MockRequestDispatcher>,
+    ) -> AwsSqsTaskQueue<IntakeBatchTask> {
+        queue_with_options(responses, AwsSqsTaskQueueOptions::default())
+    }
+
+    fn queue_with_options(
+        responses: Vec<MockRequestDispatcher>,
+        options: AwsSqsTaskQueueOptions,
+    ) -> AwsSqsTaskQueue<IntakeBatchTask> {
+        AwsSqsTaskQueue::new_with_client(
+            SqsClient::new_with(
+                MultipleMockRequestDispatcher::new(responses),
+                MockCredentialsProvider,
+                Region::UsWest2,
+            ),
+            TEST_QUEUE_URL,
+            options,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn peek_nacknowledges_peeked_messages() {
+        log_init();
+        let mut queue = queue_with_responses(vec![
+            MockRequestDispatcher::with_status(200)
+                .with_body(&receive_message_response(&[
+                    ("receipt-1", &intake_task_body("batch-1")),
+                    ("receipt-2", &intake_task_body("batch-2")),
+                ]))
+                .with_request_checker(is_receive_message_request),
+            // The queue has no more messages visible
+            MockRequestDispatcher::with_status(200)
+                .with_body(&receive_message_response(&[]))
+                .with_request_checker(is_receive_message_request),
+            MockRequestDispatcher::with_status(200)
+                .with_body(CHANGE_MESSAGE_VISIBILITY_RESPONSE)
+                .with_request_checker(is_nacknowledge_request("receipt-1")),
+            MockRequestDispatcher::with_status(200)
+                .with_body(CHANGE_MESSAGE_VISIBILITY_RESPONSE)
+                .with_request_checker(is_nacknowledge_request("receipt-2")),
+        ]);
+
+        let tasks = queue.peek(3).unwrap();
+        assert_eq!(tasks, vec![intake_task("batch-1"), intake_task("batch-2")]);
+    }
+
+    #[test]
+    fn peek_nacknowledges_peeked_messages_when_receive_fails() {
+        log_init();
+        let nacknowledged = Arc::new(AtomicBool::new(false));
+        let nack_recorder = nacknowledged.clone();
+        let mut queue = queue_with_responses(vec![
+            MockRequestDispatcher::with_status(200)
+                .with_body(&receive_message_response(&[(
+                    "receipt-1",
+                    &intake_task_body("batch-1"),
+                )]))
+                .with_request_checker(is_receive_message_request),
+            MockRequestDispatcher::with_status(500)
+                .with_body("internal error")
+                .with_request_checker(is_receive_message_request),
+            MockRequestDispatcher::with_status(200)
+                .with_body(CHANGE_MESSAGE_VISIBILITY_RESPONSE)
+                .with_request_checker(move |request| {
+                    is_nacknowledge_request("receipt-1")(request);
+                    nack_recorder.store(true, Ordering::SeqCst);
+                }),
+        ]);
+
+        assert!(queue.peek(3).is_err());
+        assert!(nacknowledged.load(Ordering::SeqCst));
+    }
+
+    /// A BackoffStrategy that records the attempts it is consulted for.
+    #[derive(Debug, Default)]
+    struct RecordingBackoff {
+        delay: Duration,
+        attempts: Mutex<Vec<u32>>,
+    }
+
+    impl BackoffStrategy for RecordingBackoff {
+        fn next_delay(&self, attempt: u32) -> Option<Duration> {
+            self.attempts.lock().unwrap().push(attempt);
+            Some(self.delay)
+        }
+    }
+
+    // https://docs.aws.amazon.com/AWSSimpleQueueService/latest/APIReference/CommonErrors.html
+    const OVER_LIMIT_RESPONSE: &str = "<ErrorResponse><Error><Type>Sender</Type>\
+        <Code>OverLimit</Code><Message>too many messages in flight</Message></Error>\
+        <RequestId>request-id</RequestId></ErrorResponse>";
+
+    #[test]
+    fn dequeue_backs_off_when_over_limit() {
+        log_init();
+        let backoff = Arc::new(RecordingBackoff {
+            delay: Duration::from_millis(100),
+            ..Default::default()
+        });
+        // The mock dispatcher panics if more requests are made than there are
+        // responses, so this also checks that OverLimit isn't retried
+        // immediately within dequeue.
+        let mut queue = queue_with_options(
+            vec![
+                MockRequestDispatcher::with_status(403)
+                    .with_body(OVER_LIMIT_RESPONSE)
+                    .with_request_checker(is_receive_message_request),
+                MockRequestDispatcher::with_status(403)
+                    .with_body(OVER_LIMIT_RESPONSE)
+                    .with_request_checker(is_receive_message_request),
+                MockRequestDispatcher::with_status(200)
+                    .with_body(&receive_message_response(&[(
+                        "receipt-1",
+                        &intake_task_body("batch-1"),
+                    )]))
+                    .with_request_checker(is_receive_message_request),
+                MockRequestDispatcher::with_status(403)
+                    .with_body(OVER_LIMIT_RESPONSE)
+                    .with_request_checker(is_receive_message_request),
+            ],
+            AwsSqsTaskQueueOptions {
+                over_limit_backoff: backoff.clone(),
+                ..Default::default()
+            },
+        );
+
+        let start = Instant::now();
+        assert!(queue.dequeue().unwrap().is_none());
+        assert!(queue.dequeue().unwrap().is_none());
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert_eq!(*backoff.attempts.lock().unwrap(), vec![1, 2]);
+
+        let handle = queue.dequeue().unwrap().unwrap();
+        assert_eq!(handle.task, intake_task("batch-1"));
+
+        // A successful dequeue resets the consecutive error count
+        assert!(queue.dequeue().unwrap().is_none());
+        assert_eq!(*backoff.attempts.lock().unwrap(), vec![1, 2, 1]);
+    }
+
+    #[test]
+    fn dequeue_fails_when_over_limit_backoff_exhausted() {
+        log_init();
+        let mut queue = queue_with_options(
+            vec![MockRequestDispatcher::with_status(403)
+                .with_body(OVER_LIMIT_RESPONSE)
+                .with_request_checker(is_receive_message_request)],
+            AwsSqsTaskQueueOptions {
+                over_limit_backoff: Arc::new(FixedDelay {
+                    delay: Duration::from_millis(0),
+                    max_attempts: 1,
+                }),
+                ..Default::default()
+            },
+        );
+
+        queue.dequeue().unwrap_err();
+    }
+
+    const TEST_DEAD_LETTER_QUEUE_URL: &str =
+        "https://sqs.us-west-2.amazonaws.com/12345/fake-dead-letter-queue";
+
+    fn is_send_message_request(queue_url: &'static str, body: String) -> impl Fn(&SignedRequest) {
+        move |request: &SignedRequest| {
+            // https://docs.aws.amazon.com/AWSSimpleQueueService/latest/APIReference/API_SendMessage.html
+            let params = request_params(request);
+            assert_eq!(
+                params.get("Action").map(String::as_str),
+                Some("SendMessage"),
+                "expected SendMessage request, found {:?}",
+                params
+            );
+            assert_eq!(
+                params.get("QueueUrl").map(String::as_str),
+                Some(queue_url),
+                "unexpected queue URL in {:?}",
+                params
+            );
+            assert_eq!(
+                params.get("MessageBody"),
+                Some(&body),
+                "unexpected message body in {:?}",
+                params
+            );
+        }
+    }
+
+    fn is_delete_message_request(receipt_handle: &'static str) -> impl Fn(&SignedRequest) {
+        move |request: &SignedRequest| {
+            // https://docs.aws.amazon.com/AWSSimpleQueueService/latest/APIReference/API_DeleteMessage.html
+            let params = request_params(request);
+            assert_eq!(
+                params.get("Action").map(String::as_str),
+                Some("DeleteMessage"),
+                "expected DeleteMessage request, found {:?}",
+                params
+            );
+            assert_eq!(
+                params.get("ReceiptHandle").map(String::as_str),
+                Some(receipt_handle),
+                "unexpected receipt handle in {:?}",
+                params
+            );
+        }
+    }
+
+    const SEND_MESSAGE_RESPONSE: &str = "<SendMessageResponse><SendMessageResult>\
+        <MD5OfMessageBody>fafb00f5732ab283681e124bf8747ed1</MD5OfMessageBody>\
+        <MessageId>message-id</MessageId></SendMessageResult>\
+        <ResponseMetadata><RequestId>request-id</RequestId></ResponseMetadata>\
+        </SendMessageResponse>";
+
+    const DELETE_MESSAGE_RESPONSE: &str = "<DeleteMessageResponse>\
+        <ResponseMetadata><RequestId>request-id</RequestId></ResponseMetadata>\
+        </DeleteMessageResponse>";
+
+    #[test]
+    fn dequeue_nacknowledges_truncated_message() {
+        log_init();
+        let body = intake_task_body("batch-1");
+        let truncated_body = &body[..body.len() - 10];
+        let mut queue = queue_with_options(
+            vec![
+                MockRequestDispatcher::with_status(200)
+                    .with_body(&receive_message_response(&[("receipt-1", truncated_body)]))
+                    .with_request_checker(is_receive_message_request),
+                MockRequestDispatcher::with_status(200)
+                    .with_body(CHANGE_MESSAGE_VISIBILITY_RESPONSE)
+                    .with_request_checker(is_nacknowledge_request("receipt-1")),
+                MockRequestDispatcher::with_status(200)
+                    .with_body(&receive_message_response(&[("receipt-2", &body)]))
+                    .with_request_checker(is_receive_message_request),
+            ],
+            AwsSqsTaskQueueOptions {
+                dead_letter_queue_url: Some(TEST_DEAD_LETTER_QUEUE_URL.to_owned()),
+                ..Default::default()
+            },
+        );
+
+        // The truncated message doesn't end the dequeue, which moves on to
+        // the next message.
+        let handle = queue.dequeue().unwrap().unwrap();
+        assert_eq!(handle.acknowledgment_id, "receipt-2");
+        assert_eq!(handle.task, intake_task("batch-1"));
+    }
+
+    #[test]
+    fn dequeue_dead_letters_invalid_message() {
+        log_init();
+        let invalid_body = r#"{"aggregation-id":"fake-aggregation","batch-id":12}"#;
+        let mut queue = queue_with_options(
+            vec![
+                MockRequestDispatcher::with_status(200)
+                    .with_body(&receive_message_response(&[("receipt-1", invalid_body)]))
+                    .with_request_checker(is_receive_message_request),
+                MockRequestDispatcher::with_status(200)
+                    .with_body(SEND_MESSAGE_RESPONSE)
+                    .with_request_checker(is_send_message_request(
+                        TEST_DEAD_LETTER_QUEUE_URL,
+                        invalid_body.to_owned(),
+                    )),
+                MockRequestDispatcher::with_status(200)
+                    .with_body(DELETE_MESSAGE_RESPONSE)
+                    .with_request_checker(is_delete_message_request("receipt-1")),
+                MockRequestDispatcher::with_status(200)
+                    .with_body(&receive_message_response(&[(
+                        "receipt-2",
+                        &intake_task_body("batch-2"),
+                    )]))
+                    .with_request_checker(is_receive_message_request),
+                MockRequestDispatcher::with_status(200)
+                    .with_body(&receive_message_response(&[]))
+                    .with_request_checker(is_receive_message_request),
+            ],
+            AwsSqsTaskQueueOptions {
+                dead_letter_queue_url: Some(TEST_DEAD_LETTER_QUEUE_URL.to_owned()),
+                ..Default::default()
+            },
+        );
+
+        let handle = queue.dequeue().unwrap().unwrap();
+        assert_eq!(handle.acknowledgment_id, "receipt-2");
+        assert_eq!(handle.task, intake_task("batch-2"));
+        assert!(queue.dequeue().unwrap().is_none());
+    }
+
+    #[test]
+    fn dequeue_batch_skips_invalid_messages() {
+        log_init();
+        let mut queue = queue_with_responses(vec![MockRequestDispatcher::with_status(200)
+            .with_body(&receive_message_response(&[
+                ("receipt-1", &intake_task_body("batch-1")),
+                (
+                    "receipt-2",
+                    r#"{"aggregation-id":"fake-aggregation","batch-id":12}"#,
+                ),
+                ("receipt-3", &intake_task_body("batch-3")),
+            ]))
+            .with_request_checker(|request| {
+                is_receive_message_request(request);
+                assert_eq!(
+                    request_params(request)
+                        .get("MaxNumberOfMessages")
+                        .map(String::as_str),
+                    Some("10")
+                );
+            })]);
+
+        let handles = queue.dequeue_batch(10).unwrap();
+        assert_eq!(handles.len(), 2);
+        assert_eq!(handles[0].acknowledgment_id, "receipt-1");
+        assert_eq!(handles[0].task, intake_task("batch-1"));
+        assert_eq!(handles[1].acknowledgment_id, "receipt-3");
+        assert_eq!(handles[1].task, intake_task("batch-3"));
+
+        assert!(queue.dequeue_batch(0).is_err());
+        assert!(queue.dequeue_batch(11).is_err());
+    }
+
+    #[test]
+    fn dequeue_batch_receives_again_after_only_invalid_messages() {
+        log_init();
+        let mut queue = queue_with_responses(vec![
+            MockRequestDispatcher::with_status(200)
+                .with_body(&receive_message_response(&[(
+                    "receipt-1",
+                    r#"{"aggregation-id":"fake-aggregation","batch-id":12}"#,
+                )]))
+                .with_request_checker(is_receive_message_request),
+            MockRequestDispatcher::with_status(200)
+                .with_body(&receive_message_response(&[(
+                    "receipt-2",
+                    &intake_task_body("batch-2"),
+                )]))
+                .with_request_checker(is_receive_message_request),
+        ]);
+
+        let handles = queue.dequeue_batch(10).unwrap();
+        assert_eq!(handles.len(), 1);
+        assert_eq!(handles[0].acknowledgment_id, "receipt-2");
+    }
+
+    #[test]
+    fn receive_wait_time_is_configurable() {
+        let mut queue = queue_with_options(
+            vec![MockRequestDispatcher::with_status(200)
+                .with_body(&receive_message_response(&[]))
+                .with_request_checker(|request: &SignedRequest| {
+                    is_receive_message_request(request);
+                    assert_eq!(
+                        request_params(request)
+                            .get("WaitTimeSeconds")
+                            .map(String::as_str),
+                        Some("1")
+                    );
+                })],
+            AwsSqsTaskQueueOptions {
+                receive_wait_time: Duration::from_secs(1),
+                ..Default::default()
+            },
+        );
+        assert!(queue.dequeue().unwrap().is_none());
+
+        AwsSqsTaskQueue::<IntakeBatchTask>::new_with_client(
+            SqsClient::new_with(
+                MockRequestDispatcher::default(),
+                MockCredentialsProvider,
+                Region::UsWest2,
+            ),
+            TEST_QUEUE_URL,
+            AwsSqsTaskQueueOptions {
+                receive_wait_time: Duration::from_secs(21),
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+    }
+
+    /// Dispatches requests that never get a response, like a long poll of an
+    /// empty queue that never ends.
+    struct NeverRespondingDispatcher;
+
+    impl DispatchSignedRequest for NeverRespondingDispatcher {
+        fn dispatch(
+            &self,
+            _request: SignedRequest,
+            _timeout: Option<Duration>,
+        ) -> DispatchSignedRequestFuture {
+            Box::pin(futures::future::pending())
+        }
+    }
+
+    #[test]
+    fn stop_signal_aborts_long_poll() {
+        let mut queue = AwsSqsTaskQueue::<IntakeBatchTask>::new_with_client(
+            SqsClient::new_with(
+                NeverRespondingDispatcher,
+                MockCredentialsProvider,
+                Region::UsWest2,
+            ),
+            TEST_QUEUE_URL,
+            AwsSqsTaskQueueOptions::default(),
+        )
+        .unwrap();
+        let stop_signal = StopSignal::new();
+        queue.set_stop_signal(stop_signal.clone());
+
+        let started = Instant::now();
+        let stopper = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            stop_signal.stop();
+        });
+        assert!(queue.dequeue().unwrap().is_none());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        stopper.join().unwrap();
+
+        // Once stopped, dequeue doesn't poll at all.
+        let started = Instant::now();
+        assert!(queue.dequeue().unwrap().is_none());
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    /// Records the events it is told about.
+    #[derive(Debug, Default)]
+    struct RecordingSink(Mutex<Vec<DeadLetterEvent>>);
+
+    impl DeadLetterSink for RecordingSink {
+        fn dead_lettered(&self, event: &DeadLetterEvent) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+
+    #[test]
+    fn dead_letter_sink_is_told_once() {
+        log_init();
+        let invalid_body = r#"{"aggregation-id":"fake-aggregation","batch-id":12}"#;
+        let sink = Arc::new(RecordingSink::default());
+        let mut queue = queue_with_options(
+            vec![
+                MockRequestDispatcher::with_status(200)
+                    .with_body(&format!(
+                        "<ReceiveMessageResponse><ReceiveMessageResult><Message>\
+                        <MessageId>message-0</MessageId><ReceiptHandle>receipt-1</ReceiptHandle>\
+                        <Body>{}</Body><Attribute><Name>ApproximateReceiveCount</Name>\
+                        <Value>4</Value></Attribute></Message></ReceiveMessageResult>\
+                        <ResponseMetadata><RequestId>request-id</RequestId></ResponseMetadata>\
+                        </ReceiveMessageResponse>",
+                        invalid_body.replace("\"", "&quot;")
+                    ))
+                    .with_request_checker(is_receive_message_request),
+                MockRequestDispatcher::with_status(200)
+                    .with_body(SEND_MESSAGE_RESPONSE)
+                    .with_request_checker(is_send_message_request(
+                        TEST_DEAD_LETTER_QUEUE_URL,
+                        invalid_body.to_owned(),
+                    )),
+                MockRequestDispatcher::with_status(200)
+                    .with_body(DELETE_MESSAGE_RESPONSE)
+                    .with_request_checker(is_delete_message_request("receipt-1")),
+                MockRequestDispatcher::with_status(200)
+                    .with_body(&receive_message_response(&[]))
+                    .with_request_checker(is_receive_message_request),
+            ],
+            AwsSqsTaskQueueOptions {
+                dead_letter_queue_url: Some(TEST_DEAD_LETTER_QUEUE_URL.to_owned()),
+                system_attribute_names: vec!["ApproximateReceiveCount".to_owned()],
+                dead_letter_sink: sink.clone(),
+                ..Default::default()
+            },
+        );
+
+        assert!(queue.dequeue().unwrap().is_none());
+
+        let events = sink.0.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].queue, TEST_QUEUE_URL);
+        assert_eq!(events[0].body, invalid_body);
+        assert_eq!(events[0].receive_count, Some(4));
+        assert!(
+            events[0].reason.starts_with("failed to decode JSON task"),
+            "unexpected reason {:?}",
+            events[0].reason
+        );
+        assert!(queue.receive_counts.is_empty());
+    }
+
+    fn is_send_message_batch_request(expected_ids: Vec<usize>) -> impl Fn(&SignedRequest) {
+        move |request: &SignedRequest| {
+            // https://docs.aws.amazon.com/AWSSimpleQueueService/latest/APIReference/API_SendMessageBatch.html
+            let params = request_params(request);
+            assert_eq!(
+                params.get("Action").map(String::as_str),
+                Some("SendMessageBatch"),
+                "expected SendMessageBatch request, found {:?}",
+                params
+            );
+            for (entry, id) in expected_ids.iter().enumerate() {
+                assert_eq!(
+                    params.get(&format!("SendMessageBatchRequestEntry.{}.Id", entry + 1)),
+                    Some(&id.to_string()),
+                    "unexpected entry ID in {:?}",
+                    params
+                );
+                assert_eq!(
+                    params.get(&format!(
+                        "SendMessageBatchRequestEntry.{}.MessageBody",
+                        entry + 1
+                    )),
+                    Some(&intake_task_body(&format!("batch-{}", id))),
+                    "unexpected message body in {:?}",
+                    params
+                );
+            }
+            assert!(
+                !params.contains_key(&format!(
+                    "SendMessageBatchRequestEntry.{}.Id",
+                    expected_ids.len() + 1
+                )),
+                "unexpected entries in {:?}",
+                params
+            );
+        }
+    }
+
+    /// Constructs the body of a SendMessageBatch response in which the entries
+    /// with the provided IDs succeeded or failed.
+    fn send_message_batch_response(successful: &[usize], failed: &[usize]) -> String {
+        // Response body format from
+        // https://docs.aws.amazon.com/AWSSimpleQueueService/latest/APIReference/API_SendMessageBatch.html#API_SendMessageBatch_Examples
+        let successful: String = successful
+            .iter()
+            .map(|id| {
+                format!(
+                    "<SendMessageBatchResultEntry><Id>{}</Id><MessageId>message-{}</MessageId>\
+                    <MD5OfMessageBody>fafb00f5732ab283681e124bf8747ed1</MD5OfMessageBody>\
+                    </SendMessageBatchResultEntry>",
+                    id, id
+                )
+            })
+            .collect();
+        let failed: String = failed
+            .iter()
+            .map(|id| {
+                format!(
+                    "<BatchResultErrorEntry><Id>{}</Id><Code>InternalError</Code>\
+                    <Message>try again</Message><SenderFault>false</SenderFault>\
+                    </BatchResultErrorEntry>",
+                    id
+                )
+            })
+            .collect();
+        format!(
+            "<SendMessageBatchResponse><SendMessageBatchResult>{}{}</SendMessageBatchResult>\
+            <ResponseMetadata><RequestId>request-id</RequestId></ResponseMetadata>\
+            </SendMessageBatchResponse>",
+            successful, failed
+        )
+    }
+
+    #[test]
+    fn enqueue_batch_in_chunks() {
+        log_init();
+        let tasks: Vec<IntakeBatchTask> = (0..25)
+            .map(|index| intake_task(&format!("batch-{}", index)))
+            .collect();
+        // The mock dispatcher panics if more requests are made than there are
+        // responses.
+        let mut queue = queue_with_responses(vec![
+            MockRequestDispatcher::with_status(200)
+                .with_body(&send_message_batch_response(
+                    &(0..10).collect::<Vec<_>>(),
+                    &[],
+                ))
+                .with_request_checker(is_send_message_batch_request((0..10).collect())),
+            MockRequestDispatcher::with_status(200)
+                .with_body(&send_message_batch_response(
+                    &(10..20).collect::<Vec<_>>(),
+                    &[],
+                ))
+                .with_request_checker(is_send_message_batch_request((10..20).collect())),
+            MockRequestDispatcher::with_status(200)
+                .with_body(&send_message_batch_response(&[20, 21, 22, 24], &[23]))
+                .with_request_checker(is_send_message_batch_request((20..25).collect())),
+        ]);
+
+        let results = queue.enqueue_batch(&tasks).unwrap();
+        assert_eq!(results.len(), 25);
+        for (index, result) in results.iter().enumerate() {
+            assert_eq!(
+                result.is_ok(),
+                index != 23,
+                "unexpected result for task {}",
+                index
+            );
+        }
+    }
+
+    #[test]
+    fn enqueue_batch_partial_failure() {
+        log_init();
+        let tasks: Vec<IntakeBatchTask> = (0..3)
+            .map(|index| intake_task(&format!("batch-{}", index)))
+            .collect();
+        let mut queue = queue_with_responses(vec![
+            MockRequestDispatcher::with_status(200)
+                .with_body(&send_message_batch_response(&[0, 2], &[1]))
+                .with_request_checker(is_send_message_batch_request(vec![0, 1, 2])),
+            // Only the failed task is sent again
+            MockRequestDispatcher::with_status(200)
+                .with_body(&send_message_batch_response(&[0], &[]))
+                .with_request_checker(|request: &SignedRequest| {
+                    let params = request_params(request);
+                    assert_eq!(
+                        params.get("SendMessageBatchRequestEntry.1.MessageBody"),
+                        Some(&intake_task_body("batch-1"))
+                    );
+                    assert!(!params.contains_key("SendMessageBatchRequestEntry.2.Id"));
+                }),
+        ]);
+
+        let results = queue.enqueue_batch(&tasks).unwrap();
+        assert!(results[0].is_ok());
+        assert!(results[2].is_ok());
+        assert_matches!(
+            results[1].as_ref().unwrap_err().downcast_ref(),
+            Some(Error::BatchEntryRejected(code, message, false)) => {
+                assert_eq!(code, "InternalError");
+                assert_eq!(message, "try again");
+            }
+        );
+
+        let failed: Vec<IntakeBatchTask> = results
+            .iter()
+            .zip(tasks)
+            .filter(|(result, _)| result.is_err())
+            .map(|(_, task)| task)
+            .collect();
+        let retried = queue.enqueue_batch(&failed).unwrap();
+        assert_eq!(retried.len(), 1);
+        assert!(retried[0].is_ok());
+    }
+
+    #[test]
+    fn authenticate_with_credential_source() {
+        log_init();
+        let credential_source = StaticCredentialSource {
+            aws_credentials: Some(AwsCredentials::new(
+                "credential-source-key",
+                "credential-source-secret",
+                None,
+                None,
+            )),
+            ..Default::default()
+        };
+        let mut queue: AwsSqsTaskQueue<IntakeBatchTask> = AwsSqsTaskQueue::new_with_client(
+            SqsClient::new_with(
+                MultipleMockRequestDispatcher::new(vec![MockRequestDispatcher::with_status(200)
+                    .with_body(&receive_message_response(&[]))
+                    .with_request_checker(|request: &SignedRequest| {
+                        let authorization = request
+                            .headers
+                            .get("authorization")
+                            .and_then(|values| values.first())
+                            .map(|value| String::from_utf8_lossy(value).into_owned())
+                            .unwrap_or_default();
+                        assert!(
+                            authorization.contains("Credential=credential-source-key/"),
+                            "request not signed with credentials from source: {}",
+                            authorization
+                        );
+                    })]),
+                CredentialSourceProvider::new(Some(Arc::new(credential_source))).unwrap(),
+                Region::UsWest2,
+            ),
+            TEST_QUEUE_URL,
+            AwsSqsTaskQueueOptions::default(),
+        )
+        .unwrap();
+
+        assert!(queue.dequeue().unwrap().is_none());
+    }
+
+    #[test]
+    fn ensure_queue_creates_queue_with_attributes() {
+        let mut queue = queue_with_responses(vec![MockRequestDispatcher::with_status(200)
+            .with_body(
+                "<CreateQueueResponse><CreateQueueResult>\
+                <QueueUrl>https://sqs.us-west-2.amazonaws.com/12345/fake-queue-created</QueueUrl>\
+                </CreateQueueResult>\
+                <ResponseMetadata><RequestId>request-id</RequestId></ResponseMetadata>\
+                </CreateQueueResponse>",
+            )
+            .with_request_checker(|request: &SignedRequest| {
+                // https://docs.aws.amazon.com/AWSSimpleQueueService/latest/APIReference/API_CreateQueue.html
+                let params = request_params(request);
+                assert_eq!(
+                    params.get("Action").map(String::as_str),
+                    Some("CreateQueue"),
+                    "expected CreateQueue request, found {:?}",
+                    params
+                );
+                assert_eq!(
+                    params.get("QueueName").map(String::as_str),
+                    Some("fake-queue")
+                );
+                let attributes: HashMap<&str, &str> = (1..)
+                    .map(|index| {
+                        Some((
+                            params.get(&format!("Attribute.{}.Name", index))?.as_str(),
+                            params.get(&format!("Attribute.{}.Value", index))?.as_str(),
+                        ))
+                    })
+                    .take_while(Option::is_some)
+                    .flatten()
+                    .collect();
+                let expected: HashMap<&str, &str> = [
+                    ("MessageRetentionPeriod", "86400"),
+                    ("DelaySeconds", "5"),
+                    ("VisibilityTimeout", "600"),
+                ]
+                .iter()
+                .cloned()
+                .collect();
+                assert_eq!(attributes, expected);
+            })]);
+
+        let queue_url = queue
+            .ensure_queue(&SqsQueueAttributes {
+                message_retention_period: Some(Duration::from_secs(86_400)),
+                delay: Some(Duration::from_secs(5)),
+                visibility_timeout: Some(Duration::from_secs(600)),
+            })
+            .unwrap();
+        assert_eq!(
+            queue_url,
+            "https://sqs.us-west-2.amazonaws.com/12345/fake-queue-created"
+        );
+        assert_eq!(queue.queue_url, queue_url);
+    }
+
+    #[test]
+    fn dequeue_requests_configured_attributes() {
+        let attribute_names = |params: &HashMap<String, String>, prefix: &str| -> Vec<String> {
+            (1..)
+                .map(|index| params.get(&format!("{}.{}", prefix, index)).cloned())
+                .take_while(Option::is_some)
+                .flatten()
+                .collect()
+        };
+        let mut queue = queue_with_options(
+            vec![
+                MockRequestDispatcher::with_status(200)
+                    .with_body(&receive_message_response(&[]))
+                    .with_request_checker(move |request: &SignedRequest| {
+                        is_receive_message_request(request);
+                        let params = request_params(request);
+                        assert_eq!(
+                            attribute_names(&params, "AttributeName"),
+                            vec!["SentTimestamp", "ApproximateReceiveCount"]
+                        );
+                        assert_eq!(
+                            attribute_names(&params, "MessageAttributeName"),
+                            vec!["trace-id", "facilitator.*"]
+                        );
+                    }),
+                // By default, no attributes are requested
+                MockRequestDispatcher::with_status(200)
+                    .with_body(&receive_message_response(&[]))
+                    .with_request_checker(move |request: &SignedRequest| {
+                        let params = request_params(request);
+                        assert!(
+                            !params.keys().any(|key| key.starts_with("AttributeName")
+                                || key.starts_with("MessageAttributeName")),
+                            "unexpected attribute names in {:?}",
+                            params
+                        );
+                    }),
+            ],
+            AwsSqsTaskQueueOptions {
+                system_attribute_names: vec![
+                    "SentTimestamp".to_owned(),
+                    "ApproximateReceiveCount".to_owned(),
+                ],
+                message_attribute_names: vec!["trace-id".to_owned(), "facilitator.*".to_owned()],
+                ..Default::default()
+            },
+        );
+        assert!(queue.dequeue().unwrap().is_none());
+        queue.options.system_attribute_names.clear();
+        queue.options.message_attribute_names.clear();
+        assert!(queue.dequeue().unwrap().is_none());
+
+        for options in &[
+            AwsSqsTaskQueueOptions {
+                system_attribute_names: vec!["SentTimeStamp".to_owned()],
+                ..Default::default()
+            },
+            AwsSqsTaskQueueOptions {
+                message_attribute_names: vec!["AWS.reserved".to_owned()],
+                ..Default::default()
+            },
+            AwsSqsTaskQueueOptions {
+                message_attribute_names: vec!["bad..name".to_owned()],
+                ..Default::default()
+            },
+        ] {
+            assert!(AwsSqsTaskQueue::<IntakeBatchTask>::new_with_client(
+                SqsClient::new_with(
+                    MultipleMockRequestDispatcher::new(Vec::<MockRequestDispatcher>::new()),
+                    MockCredentialsProvider,
+                    Region::UsWest2,
+                ),
+                TEST_QUEUE_URL,
+                options.clone(),
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn harness_outcomes() {
+        log_init();
+        let receive = |receipt_handle: &str, batch_id: &str| {
+            MockRequestDispatcher::with_status(200)
+                .with_body(&receive_message_response(&[(
+                    receipt_handle,
+                    &intake_task_body(batch_id),
+                )]))
+                .with_request_checker(is_receive_message_request)
+        };
+        let queue = queue_with_options(
+            vec![
+                receive("receipt-1", "retry-after"),
+                MockRequestDispatcher::with_status(200)
+                    .with_body(CHANGE_MESSAGE_VISIBILITY_RESPONSE)
+                    .with_request_checker(is_change_visibility_request("receipt-1", "90")),
+                receive("receipt-2", "dead-letter"),
+                MockRequestDispatcher::with_status(200)
+                    .with_body(SEND_MESSAGE_RESPONSE)
+                    .with_request_checker(is_send_message_request(
+                        TEST_DEAD_LETTER_QUEUE_URL,
+                        intake_task_body("dead-letter"),
+                    )),
+                MockRequestDispatcher::with_status(200)
+                    .with_body(DELETE_MESSAGE_RESPONSE)
+                    .with_request_checker(is_delete_message_request("receipt-2")),
+                receive("receipt-3", "ack"),
+                MockRequestDispatcher::with_status(200)
+                    .with_body(DELETE_MESSAGE_RESPONSE)
+                    .with_request_checker(is_delete_message_request("receipt-3")),
+                receive("receipt-4", "retry-now"),
+                MockRequestDispatcher::with_status(200)
+                    .with_body(CHANGE_MESSAGE_VISIBILITY_RESPONSE)
+                    .with_request_checker(is_nacknowledge_request("receipt-4")),
+                MockRequestDispatcher::with_status(200)
+                    .with_body(&receive_message_response(&[]))
+                    .with_request_checker(is_receive_message_request),
+            ],
+            AwsSqsTaskQueueOptions {
+                dead_letter_queue_url: Some(TEST_DEAD_LETTER_QUEUE_URL.to_owned()),
+                ..Default::default()
+            },
+        );
+
+        let mut harness = WorkerHarness::new(Box::new(queue));
+        let processed = harness
+            .process_available(Arc::new(|task: &IntakeBatchTask| {
+                Ok(match task.batch_id.as_str() {
+                    "retry-after" => TaskOutcome::RetryAfter(Duration::from_secs(90)),
+                    "dead-letter" => TaskOutcome::DeadLetter,
+                    "retry-now" => TaskOutcome::RetryNow,
+                    _ => TaskOutcome::Ack,
+                })
+            }))
+            .unwrap();
+        assert_eq!(processed, 4);
+    }
+
+    #[test]
+    fn dequeue_rejects_unencrypted_message() {
+        log_init();
+        let encrypted_message_response =
+            receive_message_response(&[("receipt-1", &intake_task_body("batch-1"))]).replace(
+                "</Body>",
+                "</Body><MessageAttribute><Name>encryption-key-id</Name><Value>\
+            <StringValue>alias/fake-key</StringValue><DataType>String</DataType>\
+            </Value></MessageAttribute>",
+            );
+        let mut queue = queue_with_options(
+            vec![
+                MockRequestDispatcher::with_status(200)
+                    .with_body(&encrypted_message_response)
+                    .with_request_checker(|request: &SignedRequest| {
+                        is_receive_message_request(request);
+                        let params = request_params(request);
+                        assert_eq!(
+                            params.get("MessageAttributeName.1").map(String::as_str),
+                            Some("encryption-key-id"),
+                            "encryption attribute not requested in {:?}",
+                            params
+                        );
+                    }),
+                MockRequestDispatcher::with_status(200)
+                    .with_body(&receive_message_response(&[(
+                        "receipt-2",
+                        &intake_task_body("batch-2"),
+                    )]))
+                    .with_request_checker(is_receive_message_request),
+                MockRequestDispatcher::with_status(200)
+                    .with_body(SEND_MESSAGE_RESPONSE)
+                    .with_request_checker(is_send_message_request(
+                        TEST_DEAD_LETTER_QUEUE_URL,
+                        intake_task_body("batch-2"),
+                    )),
+                MockRequestDispatcher::with_status(200)
+                    .with_body(DELETE_MESSAGE_RESPONSE)
+                    .with_request_checker(is_delete_message_request("receipt-2")),
+            ],
+            AwsSqsTaskQueueOptions {
+                encryption_attribute_name: Some("encryption-key-id".to_owned()),
+                dead_letter_queue_url: Some(TEST_DEAD_LETTER_QUEUE_URL.to_owned()),
+                ..Default::default()
+            },
+        );
+
+        let handle = queue.dequeue().unwrap().unwrap();
+        assert_eq!(handle.task, intake_task("batch-1"));
+        let err = queue.dequeue().unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("without encryption attribute encryption-key-id"),
+            "unexpected error {:?}",
+            err
+        );
+
+        AwsSqsTaskQueueOptions {
+            encryption_attribute_name: Some("encryption.*".to_owned()),
+            ..Default::default()
+        }
+        .validate_attribute_names()
+        .unwrap_err();
+    }
+
+    #[test]
+    fn dequeue_rejects_corrupted_message() {
+        let with_md5 = |receipt_handle: &str, body: &str, md5: &str| {
+            receive_message_response(&[(receipt_handle, body)])
+                .replace("</Body>", &format!("</Body><MD5OfBody>{}</MD5OfBody>", md5))
+        };
+        let intact_body = intake_task_body("batch-1");
+        let intact_md5 = format!("{:x}", md5::compute(&intact_body));
+        let mut queue = queue_with_options(
+            vec![
+                MockRequestDispatcher::with_status(200)
+                    .with_body(&with_md5("receipt-1", &intact_body, &intact_md5))
+                    .with_request_checker(is_receive_message_request),
+                // The digest is of a different body than the one delivered
+                MockRequestDispatcher::with_status(200)
+                    .with_body(&with_md5(
+                        "receipt-2",
+                        &intake_task_body("batch-2"),
+                        &intact_md5,
+                    ))
+                    .with_request_checker(is_receive_message_request),
+                MockRequestDispatcher::with_status(200)
+                    .with_body(CHANGE_MESSAGE_VISIBILITY_RESPONSE)
+                    .with_request_checker(is_nacknowledge_request("receipt-2")),
+            ],
+            AwsSqsTaskQueueOptions::default(),
+        );
+
+        let handle = queue.dequeue().unwrap().unwrap();
+        assert_eq!(handle.task, intake_task("batch-1"));
+        let err = queue.dequeue().unwrap_err();
+        assert_matches!(
+            err.downcast_ref(),
+            Some(Error::MessageCorrupted(receipt_handle, queue_url))
+                if receipt_handle == "receipt-2" && queue_url == TEST_QUEUE_URL
+        );
+    }
+
+    const RECEIPT_HANDLE_IS_INVALID_RESPONSE: &str = "<ErrorResponse><Error><Type>Sender</Type>\
+        <Code>ReceiptHandleIsInvalid</Code><Message>The receipt handle has expired.</Message>\
+        </Error><RequestId>request-id</RequestId></ErrorResponse>";
+
+    #[test]
+    fn expired_receipt_handle() {
+        log_init();
+        let mut queue = queue_with_responses(vec![
+            MockRequestDispatcher::with_status(400)
+                .with_body(RECEIPT_HANDLE_IS_INVALID_RESPONSE)
+                .with_request_checker(is_delete_message_request("receipt-1")),
+            MockRequestDispatcher::with_status(400)
+                .with_body(RECEIPT_HANDLE_IS_INVALID_RESPONSE)
+                .with_request_checker(is_nacknowledge_request("receipt-1")),
+        ]);
+        for result in &[
+            queue.acknowledge_raw("receipt-1"),
+            queue.nacknowledge_raw("receipt-1"),
+        ] {
+            assert_matches!(
+                result.as_ref().unwrap_err().downcast_ref(),
+                Some(Error::ReceiptHandleExpired(handle)) => {
+                    assert_eq!(handle, "receipt-1");
+                }
+            );
+        }
+
+        // The harness carries on with the next task
+        let receive = |receipt_handle: &str, batch_id: &str| {
+            MockRequestDispatcher::with_status(200)
+                .with_body(&receive_message_response(&[(
+                    receipt_handle,
+                    &intake_task_body(batch_id),
+                )]))
+                .with_request_checker(is_receive_message_request)
+        };
+        let queue = queue_with_responses(vec![
+            receive("receipt-2", "batch-2"),
+            MockRequestDispatcher::with_status(400)
+                .with_body(RECEIPT_HANDLE_IS_INVALID_RESPONSE)
+                .with_request_checker(is_delete_message_request("receipt-2")),
+            receive("receipt-3", "batch-3"),
+            MockRequestDispatcher::with_status(200)
+                .with_body(DELETE_MESSAGE_RESPONSE)
+                .with_request_checker(is_delete_message_request("receipt-3")),
+            MockRequestDispatcher::with_status(200)
+                .with_body(&receive_message_response(&[]))
+                .with_request_checker(is_receive_message_request),
+        ]);
+        let mut harness = WorkerHarness::new(Box::new(queue));
+        let processed = harness
+            .process_available(Arc::new(|_: &IntakeBatchTask| Ok(TaskOutcome::Ack)))
+            .unwrap();
+        assert_eq!(processed, 2);
+    }
+
+    #[test]
+    fn dequeue_fetches_externalized_task() {
+        log_init();
+        let payloads = InMemoryTransport::new();
+        let mut writer = payloads.clone().put("tasks/batch-1").unwrap();
+        writer
+            .write_all(intake_task_body("batch-1").as_bytes())
+            .unwrap();
+        writer.complete_upload().unwrap();
+
+        let pointer = serde_json::to_string(&ExternalizedTask {
+            key: "tasks/batch-1".to_owned(),
+        })
+        .unwrap();
+        assert_eq!(pointer, r#"{"externalized-task-key":"tasks/batch-1"}"#);
+        let mut queue = queue_with_responses(vec![
+            MockRequestDispatcher::with_status(200)
+                .with_body(&receive_message_response(&[("receipt-1", &pointer)]))
+                .with_request_checker(is_receive_message_request),
+            MockRequestDispatcher::with_status(200)
+                .with_body(DELETE_MESSAGE_RESPONSE)
+                .with_request_checker(is_delete_message_request("receipt-1")),
+            // A pointer to an object that doesn't exist is retried
+            MockRequestDispatcher::with_status(200)
+                .with_body(&receive_message_response(&[(
+                    "receipt-2",
+                    r#"{"externalized-task-key":"tasks/missing"}"#,
+                )]))
+                .with_request_checker(is_receive_message_request),
+            MockRequestDispatcher::with_status(200)
+                .with_body(CHANGE_MESSAGE_VISIBILITY_RESPONSE)
+                .with_request_checker(is_nacknowledge_request("receipt-2")),
+        ]);
+        queue.set_payload_transport(Box::new(payloads.clone()), true);
+
+        let handle = queue.dequeue().unwrap().unwrap();
+        assert_eq!(handle.task, intake_task("batch-1"));
+        TaskQueue::acknowledge_task(&mut queue, handle).unwrap();
+        assert_eq!(payloads.object("tasks/batch-1"), None);
+
+        queue.dequeue().unwrap_err();
+    }
+
+    #[test]
+    fn middleware_queue_decodes_through_sqs() {
+        log_init();
+        let body = intake_task_body("batch-1");
+        let mut gzip = libflate::gzip::Encoder::new(Vec::new()).unwrap();
+        gzip.write_all(body.as_bytes()).unwrap();
+        let payloads = InMemoryTransport::new();
+        let mut writer = payloads.clone().put("tasks/batch-1").unwrap();
+        writer
+            .write_all(base64::encode(gzip.finish().into_result().unwrap()).as_bytes())
+            .unwrap();
+        writer.complete_upload().unwrap();
+
+        let mut queue = queue_with_responses(vec![
+            // Truncated messages are still returned to the queue rather than
+            // dead lettered.
+            MockRequestDispatcher::with_status(200)
+                .with_body(&receive_message_response(&[(
+                    "receipt-1",
+                    &body[..body.len() - 10],
+                )]))
+                .with_request_checker(is_receive_message_request),
+            MockRequestDispatcher::with_status(200)
+                .with_body(CHANGE_MESSAGE_VISIBILITY_RESPONSE)
+                .with_request_checker(is_nacknowledge_request("receipt-1")),
+            // Externalized tasks are fetched before the middlewares see them.
+            MockRequestDispatcher::with_status(200)
+                .with_body(&receive_message_response(&[(
+                    "receipt-2",
+                    r#"{"externalized-task-key":"tasks/batch-1"}"#,
+                )]))
+                .with_request_checker(is_receive_message_request),
+        ]);
+        queue.set_payload_transport(Box::new(payloads), true);
+        let mut queue = MiddlewareQueue::new(Box::new(queue)).with(GzipDecode);
+
+        let handle = queue.dequeue().unwrap().unwrap();
+        assert_eq!(handle.acknowledgment_id, "receipt-2");
+        assert_eq!(handle.task, intake_task("batch-1"));
+    }
+
+    #[test]
+    fn dequeue_moves_undecodable_externalized_task_to_dead_letter_queue() {
+        log_init();
+        let invalid_body = r#"{"aggregation-id":"fake-aggregation","batch-id":12}"#;
+        let payloads = InMemoryTransport::new();
+        let mut writer = payloads.clone().put("tasks/invalid").unwrap();
+        writer.write_all(invalid_body.as_bytes()).unwrap();
+        writer.complete_upload().unwrap();
+
+        let mut queue = queue_with_options(
+            vec![
+                MockRequestDispatcher::with_status(200)
+                    .with_body(&receive_message_response(&[(
+                        "receipt-1",
+                        r#"{"externalized-task-key":"tasks/invalid"}"#,
+                    )]))
+                    .with_request_checker(is_receive_message_request),
+                MockRequestDispatcher::with_status(200)
+                    .with_body(SEND_MESSAGE_RESPONSE)
+                    .with_request_checker(is_send_message_request(
+                        TEST_DEAD_LETTER_QUEUE_URL,
+                        invalid_body.to_owned(),
+                    )),
+                MockRequestDispatcher::with_status(200)
+                    .with_body(DELETE_MESSAGE_RESPONSE)
+                    .with_request_checker(is_delete_message_request("receipt-1")),
+                MockRequestDispatcher::with_status(200)
+                    .with_body(&receive_message_response(&[]))
+                    .with_request_checker(is_receive_message_request),
+            ],
+            AwsSqsTaskQueueOptions {
+                dead_letter_queue_url: Some(TEST_DEAD_LETTER_QUEUE_URL.to_owned()),
+                ..Default::default()
+            },
+        );
+        queue.set_payload_transport(Box::new(payloads.clone()), true);
+
+        assert!(queue.dequeue().unwrap().is_none());
+        assert!(queue.payload_keys.is_empty());
+        assert_eq!(payloads.object("tasks/invalid"), None);
     }
 }
@@ -0,0 +1,67 @@
+use futures::future::{AbortHandle, Abortable, Aborted};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+/// A signal, such as one raised when a worker is asked to shut down, that
+/// aborts whatever waits are registered with it and keeps new ones from
+/// starting. Clones share the same signal, so one can be handed to whatever
+/// handles the shutdown request while others are given to task queues.
+#[derive(Clone, Debug, Default)]
+pub struct StopSignal {
+    state: Arc<Mutex<StopSignalState>>,
+}
+
+#[derive(Debug, Default)]
+struct StopSignalState {
+    stopped: bool,
+    next_wait_id: u64,
+    waits: HashMap<u64, AbortHandle>,
+}
+
+impl StopSignal {
+    pub fn new() -> StopSignal {
+        StopSignal::default()
+    }
+
+    /// Raises the signal, aborting every wait in progress. Raising it again
+    /// does nothing.
+    pub fn stop(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.stopped = true;
+        for (_, wait) in state.waits.drain() {
+            wait.abort();
+        }
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.state.lock().unwrap().stopped
+    }
+
+    /// Runs the provided future to completion with the provided function,
+    /// typically a runtime's block_on, unless the signal is raised first, in
+    /// which case the future is dropped wherever it is and Err(Aborted) is
+    /// returned.
+    pub(crate) fn run_until_stopped<F: Future>(
+        &self,
+        future: F,
+        block_on: impl FnOnce(Abortable<F>) -> Result<F::Output, Aborted>,
+    ) -> Result<F::Output, Aborted> {
+        let (handle, registration) = AbortHandle::new_pair();
+        let wait_id = {
+            let mut state = self.state.lock().unwrap();
+            if state.stopped {
+                return Err(Aborted);
+            }
+            let wait_id = state.next_wait_id;
+            state.next_wait_id += 1;
+            state.waits.insert(wait_id, handle);
+            wait_id
+        };
+        let output = block_on(Abortable::new(future, registration));
+        self.state.lock().unwrap().waits.remove(&wait_id);
+        output
+    }
+}
@@ -0,0 +1,74 @@
+use crate::task::{AsyncTaskQueue, Task, TaskHandle};
+use anyhow::Result;
+use futures::{lock::Mutex, stream, Stream};
+use std::rc::Rc;
+
+/// Returns a Stream of the tasks dequeued from the provided queue, so that
+/// async consumers can process tasks with stream combinators. The next task is
+/// only dequeued once the consumer polls for it, so a slow consumer pauses
+/// dequeuing rather than leaving dequeued tasks to wait in memory. Consumers
+/// acknowledge or nacknowledge each TaskHandle through the same queue. An error
+/// from the queue is yielded as an item and does not end the stream, which
+/// ends once the queue has no task available. AsyncTaskQueue's futures needn't
+/// be Send, so neither is the stream, and the queue is shared through an Rc.
+pub fn task_stream<T, Q>(queue: Rc<Mutex<Q>>) -> impl Stream<Item = Result<TaskHandle<T>>>
+where
+    T: Task,
+    Q: AsyncTaskQueue<T>,
+{
+    stream::unfold(queue, |queue| async move {
+        // The lock is released before the task is yielded, so that the
+        // consumer can acknowledge it while the stream is idle.
+        let dequeued = queue.lock().await.dequeue().await;
+        match dequeued {
+            Ok(Some(handle)) => Some((Ok(handle), queue)),
+            Ok(None) => None,
+            Err(err) => Some((Err(err), queue)),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::{InMemoryTaskQueue, IntakeBatchTask};
+    use futures::{executor::block_on, pin_mut, StreamExt};
+
+    #[test]
+    fn collect_tasks_from_stream() {
+        let mut queue = InMemoryTaskQueue::new();
+        for index in 0..5 {
+            queue
+                .enqueue(&IntakeBatchTask {
+                    aggregation_id: "fake-aggregation".to_owned(),
+                    batch_id: format!("batch-{}", index),
+                    date: "2020/10/31/20/29".to_owned(),
+                })
+                .unwrap();
+        }
+        let shared_queue = Rc::new(Mutex::new(queue.clone()));
+
+        let batch_ids = block_on(async {
+            let tasks = task_stream(shared_queue.clone()).take(3);
+            pin_mut!(tasks);
+            let mut batch_ids = Vec::new();
+            while let Some(handle) = tasks.next().await {
+                let handle = handle.unwrap();
+                batch_ids.push(handle.task.batch_id.clone());
+                // Only the task being handled has been dequeued
+                assert_eq!(queue.in_flight_count(), 1);
+                shared_queue
+                    .lock()
+                    .await
+                    .acknowledge_task(handle)
+                    .await
+                    .unwrap();
+            }
+            batch_ids
+        });
+
+        assert_eq!(batch_ids, vec!["batch-0", "batch-1", "batch-2"]);
+        assert_eq!(queue.acknowledged_tasks().unwrap().len(), 3);
+        assert_eq!(queue.queued_count(), 2);
+    }
+}
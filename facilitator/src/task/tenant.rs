@@ -0,0 +1,292 @@
+use crate::{
+    task::{Middleware, MultiQueue, Task, TaskHandle, TaskHandler, TaskOutcome, TaskQueue},
+    transport::Transport,
+};
+use anyhow::{anyhow, Context, Result};
+use log::error;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// A transport that tasks processed concurrently on different threads can
+/// take turns using.
+pub type SharedTransport = Arc<Mutex<Box<dyn Transport + Send>>>;
+
+/// The transports of a TenantRegistry, by storage path.
+pub(super) type TenantTransports = Arc<HashMap<String, SharedTransport>>;
+
+/// Function that processes a task against the transport of the tenant the
+/// task belongs to, returning what should become of it.
+pub type TenantTaskHandler<T> =
+    Arc<dyn Fn(&T, &mut dyn Transport) -> Result<TaskOutcome> + Send + Sync>;
+
+/// The queues and transports of the tenants served by a worker, so that a
+/// WorkerHarness created with WorkerHarness::new_multi_tenant can dequeue
+/// tasks from every tenant's queue and process each against the transport
+/// for the storage path named by Task::tenant_storage_path.
+#[derive(Debug)]
+pub struct TenantRegistry<T: Task> {
+    queues: Vec<(String, Box<dyn TaskQueue<T>>)>,
+    transports: HashMap<String, SharedTransport>,
+}
+
+impl<T: Task> Default for TenantRegistry<T> {
+    fn default() -> Self {
+        TenantRegistry {
+            queues: Vec::new(),
+            transports: HashMap::new(),
+        }
+    }
+}
+
+impl<T: Task> TenantRegistry<T> {
+    pub fn new() -> TenantRegistry<T> {
+        TenantRegistry::default()
+    }
+
+    /// Registers the queue with the provided identifier. Tasks whose
+    /// Task::tenant_queue names a different queue than the one they were
+    /// dequeued from are dead lettered rather than processed. The queues are
+    /// dequeued from in turn by a MultiQueue, so each should wait only
+    /// briefly for tasks to arrive.
+    pub fn add_queue(&mut self, queue_id: &str, queue: Box<dyn TaskQueue<T>>) -> Result<()> {
+        if self.queues.iter().any(|(id, _)| id == queue_id) {
+            return Err(anyhow!("queue {} is already registered", queue_id));
+        }
+        self.queues.push((queue_id.to_owned(), queue));
+        Ok(())
+    }
+
+    /// Registers the transport that tasks naming the provided storage path
+    /// are processed against.
+    pub fn add_transport(
+        &mut self,
+        storage_path: &str,
+        transport: Box<dyn Transport + Send>,
+    ) -> Result<()> {
+        if self.transports.contains_key(storage_path) {
+            return Err(anyhow!(
+                "storage path {} is already registered",
+                storage_path
+            ));
+        }
+        self.transports
+            .insert(storage_path.to_owned(), Arc::new(Mutex::new(transport)));
+        Ok(())
+    }
+
+    /// Splits the registry into a queue that dequeues from every registered
+    /// queue in turn and the registered transports.
+    pub(super) fn into_parts(self) -> Result<(MultiQueue<T>, TenantTransports)>
+    where
+        T: 'static,
+    {
+        let queues = self
+            .queues
+            .into_iter()
+            .map(|(queue_id, queue)| {
+                Box::new(TenantQueue { queue_id, queue }) as Box<dyn TaskQueue<T>>
+            })
+            .collect();
+        let queue = MultiQueue::new(queues).context("tenant registry has no queues")?;
+        Ok((queue, Arc::new(self.transports)))
+    }
+}
+
+/// Wraps the provided handler in a TaskHandler that runs it against the
+/// transport, among the provided ones, for each task's storage path. Tasks
+/// naming no storage path, or one that isn't registered, are dead lettered,
+/// since processing them against some other tenant's storage would be worse
+/// than not processing them at all.
+pub(super) fn route_to_transports<T: Task + 'static>(
+    transports: TenantTransports,
+    handler: TenantTaskHandler<T>,
+) -> TaskHandler<T> {
+    Arc::new(move |task: &T| {
+        let transport = match task
+            .tenant_storage_path()
+            .and_then(|storage_path| transports.get(&storage_path))
+        {
+            Some(transport) => transport,
+            None => {
+                error!(
+                    "task {} names no registered storage path ({:?})",
+                    task,
+                    task.tenant_storage_path()
+                );
+                return Ok(TaskOutcome::DeadLetter);
+            }
+        };
+        let mut transport = transport.lock().unwrap();
+        handler(task, transport.as_mut())
+    })
+}
+
+/// A registered tenant queue, which dead letters tasks that say they belong
+/// in another queue.
+#[derive(Debug)]
+struct TenantQueue<T: Task> {
+    queue_id: String,
+    queue: Box<dyn TaskQueue<T>>,
+}
+
+impl<T: Task> TenantQueue<T> {
+    /// Dequeues from the underlying queue, through the provided middleware if
+    /// any, dead lettering tasks that belong in another queue.
+    fn dequeue_own(
+        &mut self,
+        mut middleware: Option<&mut dyn Middleware>,
+    ) -> Result<Option<TaskHandle<T>>> {
+        loop {
+            let handle = match &mut middleware {
+                Some(middleware) => self.queue.dequeue_through(*middleware)?,
+                None => self.queue.dequeue()?,
+            };
+            let handle = match handle {
+                Some(handle) => handle,
+                None => return Ok(None),
+            };
+            match handle.task.tenant_queue() {
+                Some(queue_id) if queue_id != self.queue_id => {
+                    let reason =
+                        format!("task belongs in queue {}, not {}", queue_id, self.queue_id);
+                    error!("dead lettering task {}: {}", handle, reason);
+                    if let Some(middleware) = &mut middleware {
+                        middleware.on_ack(&handle.acknowledgment_id)?;
+                    }
+                    self.queue.dead_letter_task(handle, &reason)?;
+                }
+                _ => return Ok(Some(handle)),
+            }
+        }
+    }
+}
+
+impl<T: Task> TaskQueue<T> for TenantQueue<T> {
+    fn dequeue(&mut self) -> Result<Option<TaskHandle<T>>> {
+        self.dequeue_own(None)
+    }
+
+    fn dequeue_through(
+        &mut self,
+        middleware: &mut dyn Middleware,
+    ) -> Result<Option<TaskHandle<T>>> {
+        self.dequeue_own(Some(middleware))
+    }
+
+    fn dequeue_raw(&mut self) -> Result<Option<(String, String)>> {
+        self.queue.dequeue_raw()
+    }
+
+    fn acknowledge_raw(&mut self, acknowledgment_id: &str) -> Result<()> {
+        self.queue.acknowledge_raw(acknowledgment_id)
+    }
+
+    fn nacknowledge_raw(&mut self, acknowledgment_id: &str) -> Result<()> {
+        self.queue.nacknowledge_raw(acknowledgment_id)
+    }
+
+    fn retry_raw_after(&mut self, acknowledgment_id: &str, delay: Duration) -> Result<()> {
+        self.queue.retry_raw_after(acknowledgment_id, delay)
+    }
+
+    fn dead_letter_raw(&mut self, acknowledgment_id: &str, body: &str, reason: &str) -> Result<()> {
+        self.queue.dead_letter_raw(acknowledgment_id, body, reason)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        task::{InMemoryTaskQueue, WorkerHarness},
+        transport::InMemoryTransport,
+    };
+    use serde::{Deserialize, Serialize};
+    use std::{fmt, io::Write};
+
+    #[derive(Debug, Deserialize, Serialize)]
+    struct TenantTask {
+        tenant: String,
+        batch_id: String,
+    }
+
+    impl Task for TenantTask {
+        fn tenant_storage_path(&self) -> Option<String> {
+            Some(format!("gs://{}-bucket", self.tenant))
+        }
+
+        fn tenant_queue(&self) -> Option<String> {
+            Some(format!("{}-queue", self.tenant))
+        }
+    }
+
+    impl fmt::Display for TenantTask {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{} for {}", self.batch_id, self.tenant)
+        }
+    }
+
+    fn tenant_task(tenant: &str, batch_id: &str) -> TenantTask {
+        TenantTask {
+            tenant: tenant.to_owned(),
+            batch_id: batch_id.to_owned(),
+        }
+    }
+
+    #[test]
+    fn tasks_are_processed_against_their_tenants_transport() {
+        let mut registry = TenantRegistry::new();
+        let mut queues = Vec::new();
+        let mut transports = Vec::new();
+        for tenant in &["a", "b"] {
+            let mut queue = InMemoryTaskQueue::new();
+            queue.enqueue(&tenant_task(tenant, "batch-1")).unwrap();
+            queue
+                .enqueue(&tenant_task(tenant, &format!("{}-only", tenant)))
+                .unwrap();
+            let transport = InMemoryTransport::new();
+            registry
+                .add_queue(&format!("{}-queue", tenant), Box::new(queue.clone()))
+                .unwrap();
+            registry
+                .add_transport(
+                    &format!("gs://{}-bucket", tenant),
+                    Box::new(transport.clone()),
+                )
+                .unwrap();
+            queues.push(queue);
+            transports.push(transport);
+        }
+        // Found in tenant a's queue, but belongs to tenant b
+        queues[0].enqueue(&tenant_task("b", "misrouted")).unwrap();
+
+        let mut harness = WorkerHarness::new_multi_tenant(registry).unwrap();
+        let handler: TenantTaskHandler<TenantTask> =
+            Arc::new(|task: &TenantTask, transport: &mut dyn Transport| {
+                let mut writer = transport.put(&task.batch_id)?;
+                writer.write_all(task.tenant.as_bytes())?;
+                writer.complete_upload()?;
+                Ok(TaskOutcome::Ack)
+            });
+
+        assert_eq!(harness.process_available_for_tenants(handler).unwrap(), 4);
+
+        for (tenant, other, transport) in &[("a", "b", &transports[0]), ("b", "a", &transports[1])]
+        {
+            assert_eq!(transport.object("batch-1").unwrap(), tenant.as_bytes());
+            assert!(transport.object(&format!("{}-only", tenant)).is_some());
+            assert!(transport.object(&format!("{}-only", other)).is_none());
+            assert!(transport.object("misrouted").is_none());
+        }
+        let dead_lettered = queues[0].dead_lettered_tasks().unwrap();
+        assert_eq!(dead_lettered.len(), 1);
+        assert_eq!(dead_lettered[0].0.batch_id, "misrouted");
+        for queue in &queues {
+            assert_eq!(queue.queued_count(), 0);
+            assert_eq!(queue.in_flight_count(), 0);
+        }
+    }
+}
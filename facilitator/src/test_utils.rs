@@ -1,9 +1,11 @@
 use crate::BatchSigningKey;
-use log::LevelFilter;
+use log::{LevelFilter, Log, Metadata, Record};
+use once_cell::sync::Lazy;
 use ring::signature::{
     EcdsaKeyPair, KeyPair, UnparsedPublicKey, ECDSA_P256_SHA256_ASN1,
     ECDSA_P256_SHA256_ASN1_SIGNING,
 };
+use std::sync::Mutex;
 
 /// Default keys used in testing and for sample data generation. These are
 /// stored in base64 to make it convenient to copy/paste them into other tools
@@ -119,8 +121,53 @@ pub fn default_pha_signing_public_key() -> UnparsedPublicKey<Vec<u8>> {
 // the top of any test we want logs from.
 // https://docs.rs/env_logger/0.8.2/env_logger/#capturing-logs-in-tests
 pub fn log_init() {
-    let _ = env_logger::builder()
+    let logger = env_logger::builder()
         .filter_level(LevelFilter::Info)
         .is_test(true)
-        .try_init();
+        .build();
+    let max_level = logger.filter();
+    if log::set_boxed_logger(Box::new(CapturingLogger { logger })).is_ok() {
+        log::set_max_level(max_level);
+    }
+}
+
+/// Messages logged by any thread since log_init was first called.
+static LOGGED_MESSAGES: Lazy<Mutex<Vec<String>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// A logger that keeps the messages it logs so that tests can check them.
+struct CapturingLogger {
+    logger: env_logger::Logger,
+}
+
+impl Log for CapturingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.logger.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.logger.matches(record) {
+            LOGGED_MESSAGES
+                .lock()
+                .unwrap()
+                .push(record.args().to_string());
+        }
+        self.logger.log(record);
+    }
+
+    fn flush(&self) {
+        self.logger.flush();
+    }
+}
+
+/// Returns the messages logged since log_init was first called that contain
+/// the provided string. Tests run concurrently, so callers should look for
+/// something unique to their test.
+pub fn logged_messages_containing(needle: &str) -> Vec<String> {
+    LOGGED_MESSAGES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|message| message.contains(needle))
+        .cloned()
+        .collect()
 }
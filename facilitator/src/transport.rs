@@ -1,20 +1,44 @@
+mod batch_put;
+mod content_addressed;
+mod envelope;
 mod gcs;
 mod local;
+mod memory;
+mod recording;
 mod s3;
+mod sharded;
+mod write_once;
 
-use crate::{manifest::BatchSigningPublicKeys, BatchSigningKey};
-use anyhow::Result;
+use crate::{manifest::BatchSigningPublicKeys, BatchSigningKey, Error};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
 use derivative::Derivative;
 use prio::encrypt::PrivateKey;
 use std::{
     boxed::Box,
     fmt::Debug,
-    io::{Read, Write},
+    io::{ErrorKind, Read, Write},
+    time::SystemTime,
 };
 
-pub use gcs::GCSTransport;
+/// Size of the buffer through which stream_copy moves object contents.
+const STREAM_COPY_BUFFER_SIZE: usize = 1_048_576;
+
+pub use batch_put::{BatchManifest, BatchManifestEntry, BatchPutSession};
+pub use content_addressed::ContentAddressedTransport;
+pub use envelope::{EnvelopeTransport, GcpKmsKeyWrapper, KeyWrapper};
+pub use gcs::{
+    estimate_upload_operations, CancellationToken, ContentHashes, EnvironmentNamespace,
+    GCSTransport, HashHandle, KeyLocks, ManifestEntry, MemoryBudget, ObjectMetadata, ObjectPolicy,
+    PartialObjectMetadata, PolicyBinding, PutOptions, StreamingTransferWriter, TransportStats,
+    TransportTimeouts, UploadEstimate, UploadProgress, UploadSessionState, UsageSummary,
+};
 pub use local::LocalFileTransport;
+pub use memory::InMemoryTransport;
+pub use recording::{RecordingTransport, ReplayTransport};
 pub use s3::S3Transport;
+pub use sharded::{ShardEntry, ShardManifest, ShardedReader};
+pub use write_once::WriteOnceTransport;
 
 /// A transport along with the public keys that can be used to verify signatures
 /// on the batches read from the transport.
@@ -62,15 +86,114 @@ impl<T: TransportWriter + ?Sized> TransportWriter for Box<T> {
     }
 }
 
+/// Formats the provided time as an HTTP-date, suitable for use in headers like
+/// If-Modified-Since.
+/// https://tools.ietf.org/html/rfc7231#section-7.1.1.1
+pub(crate) fn http_date(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
 /// A transport moves object in and out of some data store, such as a cloud
 /// object store like Amazon S3, or local files, or buffers in memory.
 pub trait Transport: Debug {
     /// Returns an std::io::Read instance from which the contents of the value
     /// of the provided key may be read.
     fn get(&mut self, key: &str) -> Result<Box<dyn Read>>;
+    /// Like get, but if the value of the provided key has not been modified
+    /// since the provided time, returns crate::Error::NotModified instead of
+    /// the object's contents.
+    fn get_if_modified_since(&mut self, key: &str, since: SystemTime) -> Result<Box<dyn Read>>;
     /// Returns an std::io::Write instance into which the contents of the value
     /// may be written.
     fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>>;
+    /// Deletes the value of the provided key. Deleting a key that has no value
+    /// succeeds, so that cleanup can safely be retried.
+    fn delete(&mut self, key: &str) -> Result<()>;
+    /// Returns the first n bytes of the value of the provided key, or all of
+    /// it if it is shorter, for sniffing its format without reading all of it.
+    /// By default the value is read from get until n bytes have been read, so
+    /// transports that can fetch part of a value should override this.
+    fn get_prefix(&mut self, key: &str, n: usize) -> Result<Vec<u8>> {
+        let mut prefix = Vec::new();
+        self.get(key)?
+            .take(n as u64)
+            .read_to_end(&mut prefix)
+            .with_context(|| format!("failed to read prefix of {}", key))?;
+        Ok(prefix)
+    }
+
+    /// Returns an std::io::Read instance from which at most length bytes of
+    /// the value of the provided key may be read, starting offset bytes into
+    /// it, such as the header of a large Avro batch. A range that runs past
+    /// the end of the value yields what there is of it, possibly nothing.
+    /// Transports that can't read from an offset return
+    /// crate::Error::Unsupported, which is the default, and callers should
+    /// then fall back to get.
+    fn get_range(&mut self, _key: &str, _offset: u64, _length: u64) -> Result<Box<dyn Read>> {
+        Err(Error::Unsupported(format!("range reads from {}", self.path())).into())
+    }
+
+    /// Returns the keys of every value whose key begins with the provided
+    /// prefix, in sorted order, for discovering what a previous run left
+    /// behind. Keys are relative to the transport's path, like those given to
+    /// get, and an empty prefix lists every value. Transports that can't
+    /// enumerate their values return crate::Error::Unsupported, which is the
+    /// default.
+    fn list(&mut self, _prefix: &str) -> Result<Vec<String>> {
+        Err(Error::Unsupported(format!("listing {}", self.path())).into())
+    }
+
+    /// Returns whether the provided key has a value, without fetching any of
+    /// it, e.g. to check that every expected batch is present before starting
+    /// an aggregation. Transports that can't check for a value without
+    /// fetching it return crate::Error::Unsupported, which is the default.
+    fn exists(&mut self, _key: &str) -> Result<bool> {
+        Err(Error::Unsupported(format!("checking existence in {}", self.path())).into())
+    }
 
     fn path(&self) -> String;
 }
+
+/// Copies the object at src_key in src to dst_key in dst by streaming it
+/// through a buffer of bounded size, so that objects of any size may be copied
+/// between any two transports, including ones backed by different providers,
+/// where a server-side copy is impossible. If reading from src or writing to dst
+/// fails, the upload to dst is cancelled.
+pub fn stream_copy(
+    src: &mut dyn Transport,
+    src_key: &str,
+    dst: &mut dyn Transport,
+    dst_key: &str,
+) -> Result<()> {
+    let mut reader = src
+        .get(src_key)
+        .with_context(|| format!("failed to get {} from {}", src_key, src.path()))?;
+    let mut writer = dst
+        .put(dst_key)
+        .with_context(|| format!("failed to put {} to {}", dst_key, dst.path()))?;
+
+    if let Err(err) = copy_buffered(&mut reader, &mut writer) {
+        if let Err(cancel) = writer.cancel_upload() {
+            return Err(cancel.context(err));
+        }
+        return Err(err);
+    }
+    writer.complete_upload()
+}
+
+fn copy_buffered(reader: &mut dyn Read, writer: &mut dyn Write) -> Result<()> {
+    let mut buffer = vec![0; STREAM_COPY_BUFFER_SIZE];
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(read) => read,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err).context("failed to read from source"),
+        };
+        writer
+            .write_all(&buffer[..read])
+            .context("failed to write to destination")?;
+    }
+}
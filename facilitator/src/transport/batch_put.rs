@@ -0,0 +1,378 @@
+use crate::{
+    hex_dump,
+    transport::{Transport, TransportWriter},
+    Error,
+};
+use anyhow::{anyhow, Context, Result};
+use log::info;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::{
+    boxed::Box,
+    io,
+    io::Write,
+    sync::{Arc, Mutex},
+};
+
+/// Describes one object written during a BatchPutSession.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BatchManifestEntry {
+    /// Key of the object, relative to the transport it was written to.
+    pub key: String,
+    /// Size of the object in bytes.
+    pub size: u64,
+    /// Hex encoding of the SHA256 digest of the object's contents.
+    pub sha256: String,
+}
+
+/// A manifest listing every object written during a BatchPutSession, in the
+/// order in which their uploads were completed.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BatchManifest {
+    /// Format version of the manifest. Versions besides the currently supported
+    /// one are rejected.
+    pub format: u32,
+    pub objects: Vec<BatchManifestEntry>,
+}
+
+impl BatchManifest {
+    /// Loads the manifest from the provided slice. Returns an error if the
+    /// manifest could not be parsed.
+    pub fn from_slice(json: &[u8]) -> Result<Self> {
+        let manifest: Self =
+            serde_json::from_slice(json).context("failed to decode JSON batch manifest")?;
+        if manifest.format != 0 {
+            return Err(anyhow!("unsupported manifest format {}", manifest.format));
+        }
+        Ok(manifest)
+    }
+}
+
+/// BatchPutSession tracks the objects written to a transport through it so
+/// that, once they have all been written, a manifest listing them can be
+/// written with finish(). The manifest is written with a single put, so readers
+/// see either no manifest or one listing every object in the batch, and its
+/// presence can serve as the batch's completion marker. Only uploads completed
+/// through writers obtained from this session are listed.
+///
+/// Writing a key twice during a session most likely means two parts of the
+/// batch were mistakenly given the same key, so unless collision detection is
+/// turned off, completing an upload to a key that was already written fails
+/// with crate::Error::ConflictingWrite if the contents differ. If they are
+/// identical, as when a write is retried, the upload is ignored. Either way,
+/// nothing is written to the transport for a key whose upload was already
+/// completed during the session, leaving the object as first written, so
+/// that the manifest matches it whatever the transport.
+pub struct BatchPutSession<'a> {
+    transport: &'a mut dyn Transport,
+    entries: Arc<Mutex<Vec<BatchManifestEntry>>>,
+    detect_collisions: bool,
+}
+
+impl<'a> BatchPutSession<'a> {
+    pub fn new(transport: &'a mut dyn Transport) -> BatchPutSession<'a> {
+        BatchPutSession {
+            transport,
+            entries: Arc::new(Mutex::new(Vec::new())),
+            detect_collisions: true,
+        }
+    }
+
+    /// Turns detection of keys written more than once on or off. With it
+    /// off, every completed upload replaces whatever was written before it
+    /// and is listed in the manifest. It is on by default.
+    pub fn set_collision_detection(&mut self, detect_collisions: bool) {
+        self.detect_collisions = detect_collisions;
+    }
+
+    /// Returns a writer for the value of the provided key, which will be listed
+    /// in the manifest once its upload is completed.
+    pub fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>> {
+        // Some transports, like LocalFileTransport, replace the object as soon
+        // as it is written to, so a rewrite mustn't reach the transport before
+        // it can be compared with what was first written.
+        let rewrite = self.detect_collisions
+            && self
+                .entries
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|existing| existing.key == key);
+        let writer = if rewrite {
+            None
+        } else {
+            Some(self.transport.put(key)?)
+        };
+        Ok(Box::new(RecordingWriter {
+            writer,
+            key: key.to_owned(),
+            size: 0,
+            digest: digest::Context::new(&digest::SHA256),
+            entries: self.entries.clone(),
+            detect_collisions: self.detect_collisions,
+        }))
+    }
+
+    /// Returns the objects written so far.
+    pub fn entries(&self) -> Vec<BatchManifestEntry> {
+        self.entries.lock().unwrap().clone()
+    }
+
+    /// Writes a manifest listing every object written during the session to the
+    /// provided key and returns it.
+    pub fn finish(self, manifest_key: &str) -> Result<BatchManifest> {
+        let manifest = BatchManifest {
+            format: 0,
+            objects: self.entries(),
+        };
+        let json = serde_json::to_vec(&manifest).context("failed to encode batch manifest")?;
+
+        let mut writer = self
+            .transport
+            .put(manifest_key)
+            .with_context(|| format!("failed to put batch manifest {}", manifest_key))?;
+        if let Err(err) = writer.write_all(&json) {
+            let err = anyhow::Error::new(err)
+                .context(format!("failed to write batch manifest {}", manifest_key));
+            if let Err(cancel) = writer.cancel_upload() {
+                return Err(cancel.context(err));
+            }
+            return Err(err);
+        }
+        writer
+            .complete_upload()
+            .with_context(|| format!("failed to complete batch manifest {}", manifest_key))?;
+        Ok(manifest)
+    }
+
+    /// Deletes every object written during the session. All deletions are
+    /// attempted even if some fail, in which case the first error is returned.
+    pub fn cancel(self) -> Result<()> {
+        let mut result = Ok(());
+        for entry in self.entries.lock().unwrap().drain(..) {
+            if let Err(err) = self.transport.delete(&entry.key) {
+                if result.is_ok() {
+                    result = Err(err.context(format!("failed to delete {}", entry.key)));
+                }
+            }
+        }
+        result
+    }
+}
+
+/// A TransportWriter that computes the size and digest of the content written
+/// to it, adding an entry to its session once the upload is completed.
+struct RecordingWriter {
+    /// The writer to the transport, or None for a rewrite of a key already
+    /// written during the session, whose content is only compared with what
+    /// was first written.
+    writer: Option<Box<dyn TransportWriter>>,
+    key: String,
+    size: u64,
+    digest: digest::Context,
+    entries: Arc<Mutex<Vec<BatchManifestEntry>>>,
+    detect_collisions: bool,
+}
+
+impl Write for RecordingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = match &mut self.writer {
+            Some(writer) => writer.write(buf)?,
+            None => buf.len(),
+        };
+        self.size += written as u64;
+        self.digest.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.writer {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl TransportWriter for RecordingWriter {
+    fn complete_upload(&mut self) -> Result<()> {
+        let entry = BatchManifestEntry {
+            key: self.key.clone(),
+            size: self.size,
+            sha256: hex_dump(self.digest.clone().finish().as_ref()),
+        };
+        if self.detect_collisions {
+            let identical = self
+                .entries
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|existing| existing.key == entry.key)
+                .map(|existing| *existing == entry);
+            if let Some(identical) = identical {
+                // The upload was already underway when the first one to the
+                // same key was completed.
+                if let Some(writer) = &mut self.writer {
+                    writer
+                        .cancel_upload()
+                        .with_context(|| format!("failed to cancel rewrite of {}", self.key))?;
+                }
+                if !identical {
+                    return Err(Error::ConflictingWrite(self.key.clone()).into());
+                }
+                info!(
+                    "ignoring rewrite of {} with identical contents during batch",
+                    self.key
+                );
+                return Ok(());
+            }
+        }
+        match &mut self.writer {
+            Some(writer) => writer.complete_upload()?,
+            // Entries are never removed while the session lasts, so a rewrite
+            // always finds the upload it repeats above.
+            None => return Err(anyhow!("no earlier upload of {} to compare with", self.key)),
+        }
+        self.entries.lock().unwrap().push(entry);
+        Ok(())
+    }
+
+    fn cancel_upload(&mut self) -> Result<()> {
+        match &mut self.writer {
+            Some(writer) => writer.cancel_upload(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{InMemoryTransport, LocalFileTransport};
+    use assert_matches::assert_matches;
+    use std::io::Read;
+
+    const OBJECTS: [(&str, &str); 3] = [
+        ("batch/first", "first object"),
+        ("batch/second", "second object"),
+        ("batch/third", ""),
+    ];
+
+    fn put_objects(session: &mut BatchPutSession) {
+        for (key, content) in OBJECTS.iter() {
+            let mut writer = session.put(key).unwrap();
+            writer.write_all(content.as_bytes()).unwrap();
+            writer.complete_upload().unwrap();
+        }
+    }
+
+    #[test]
+    fn finish_writes_manifest() {
+        let mut transport = InMemoryTransport::new();
+        let mut session_transport = transport.clone();
+        let mut session = BatchPutSession::new(&mut session_transport);
+        put_objects(&mut session);
+
+        // Cancelled uploads are not listed
+        let mut writer = session.put("batch/cancelled").unwrap();
+        writer.write_all(b"cancelled").unwrap();
+        writer.cancel_upload().unwrap();
+
+        let returned_manifest = session.finish("batch/manifest.json").unwrap();
+
+        let manifest =
+            BatchManifest::from_slice(&transport.object("batch/manifest.json").unwrap()).unwrap();
+        assert_eq!(manifest, returned_manifest);
+        let expected: Vec<BatchManifestEntry> = OBJECTS
+            .iter()
+            .map(|(key, content)| BatchManifestEntry {
+                key: key.to_string(),
+                size: content.len() as u64,
+                sha256: hex_dump(digest::digest(&digest::SHA256, content.as_bytes()).as_ref()),
+            })
+            .collect();
+        assert_eq!(manifest.objects, expected);
+        for (key, content) in OBJECTS.iter() {
+            assert_eq!(transport.object(key).unwrap(), content.as_bytes());
+        }
+        assert!(transport.get("batch/cancelled").is_err());
+    }
+
+    #[test]
+    fn rewrites_are_detected() {
+        let transport = InMemoryTransport::new();
+        let mut session_transport = transport.clone();
+        let mut session = BatchPutSession::new(&mut session_transport);
+        let put = |session: &mut BatchPutSession, content: &[u8]| {
+            let mut writer = session.put("batch/object").unwrap();
+            writer.write_all(content).unwrap();
+            writer.complete_upload()
+        };
+
+        put(&mut session, b"first contents").unwrap();
+        put(&mut session, b"first contents").unwrap();
+        let err = put(&mut session, b"other contents").unwrap_err();
+        assert_matches!(
+            err.downcast_ref(),
+            Some(Error::ConflictingWrite(key)) if key == "batch/object"
+        );
+
+        assert_eq!(session.entries().len(), 1);
+        assert_eq!(transport.object("batch/object").unwrap(), b"first contents");
+        // The rewrites never reached the transport
+        assert!(transport.cancelled_uploads().is_empty());
+
+        session.set_collision_detection(false);
+        put(&mut session, b"other contents").unwrap();
+        assert_eq!(session.entries().len(), 2);
+        assert_eq!(transport.object("batch/object").unwrap(), b"other contents");
+    }
+
+    #[test]
+    fn rewrites_leave_local_files_as_first_written() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+        let mut session = BatchPutSession::new(&mut transport);
+        let mut put = |content: &[u8]| {
+            let mut writer = session.put("batch/object").unwrap();
+            writer.write_all(content).unwrap();
+            writer.complete_upload()
+        };
+        put(b"first contents").unwrap();
+        assert_matches!(
+            put(b"other contents").unwrap_err().downcast_ref(),
+            Some(Error::ConflictingWrite(_))
+        );
+        let manifest = session.finish("batch/manifest.json").unwrap();
+
+        let mut content = Vec::new();
+        transport
+            .get("batch/object")
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, b"first contents");
+        assert_eq!(
+            manifest.objects,
+            vec![BatchManifestEntry {
+                key: "batch/object".to_owned(),
+                size: content.len() as u64,
+                sha256: hex_dump(digest::digest(&digest::SHA256, &content).as_ref()),
+            }]
+        );
+    }
+
+    #[test]
+    fn cancel_deletes_objects() {
+        let transport = InMemoryTransport::new();
+        let mut session_transport = transport.clone();
+        let mut session = BatchPutSession::new(&mut session_transport);
+        put_objects(&mut session);
+        session.cancel().unwrap();
+
+        for (key, _) in OBJECTS.iter() {
+            assert_eq!(transport.object(key), None);
+        }
+    }
+}
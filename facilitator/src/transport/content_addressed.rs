@@ -0,0 +1,169 @@
+use crate::{hex_dump, transport::Transport};
+use anyhow::{anyhow, Context, Result};
+use log::info;
+use ring::digest;
+use std::io::Read;
+
+/// Prefix of the keys under which ContentAddressedTransport stores objects,
+/// naming the hash the rest of the key is made of.
+const ADDRESS_PREFIX: &str = "sha256/";
+
+/// A layer over a transport that stores each object under a key derived from
+/// the SHA-256 digest of its content, its address, so that identical content
+/// put any number of times, from any batch, is stored once. Content is
+/// buffered in memory to compute its address before it is written, so this
+/// is only suited to objects that comfortably fit in memory.
+#[derive(Debug)]
+pub struct ContentAddressedTransport<T: Transport> {
+    transport: T,
+}
+
+impl<T: Transport> ContentAddressedTransport<T> {
+    pub fn new(transport: T) -> ContentAddressedTransport<T> {
+        ContentAddressedTransport { transport }
+    }
+
+    /// Returns the underlying transport.
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    /// Returns the address under which the provided content is stored.
+    pub fn address(content: &[u8]) -> String {
+        format!(
+            "{}{}",
+            ADDRESS_PREFIX,
+            hex_dump(digest::digest(&digest::SHA256, content).as_ref())
+        )
+    }
+
+    /// Stores the content read from the provided reader, unless an object
+    /// with the same content is already stored, and returns its address. If
+    /// checking for an existing object fails, or the transport can't tell
+    /// whether objects exist, the content is written anyway, which at worst
+    /// replaces an object with an identical one.
+    pub fn put(&mut self, content: &mut dyn Read) -> Result<String> {
+        let mut buffer = Vec::new();
+        content
+            .read_to_end(&mut buffer)
+            .context("failed to read content to store")?;
+        let address = Self::address(&buffer);
+        if let Ok(true) = self.transport.exists(&address) {
+            info!(
+                "{}{} is already stored, skipping write",
+                self.transport.path(),
+                address
+            );
+            return Ok(address);
+        }
+
+        let mut writer = self.transport.put(&address)?;
+        if let Err(err) = writer.write_all(&buffer) {
+            let err = anyhow::Error::new(err).context(format!("failed to write {}", address));
+            if let Err(cancel) = writer.cancel_upload() {
+                return Err(cancel.context(err));
+            }
+            return Err(err);
+        }
+        writer.complete_upload()?;
+        Ok(address)
+    }
+
+    /// Returns the content stored at the provided address, after checking
+    /// that it still hashes to the address.
+    pub fn get(&mut self, address: &str) -> Result<Vec<u8>> {
+        if !address.starts_with(ADDRESS_PREFIX) {
+            return Err(anyhow!("{} is not a content address", address));
+        }
+        let mut content = Vec::new();
+        self.transport
+            .get(address)?
+            .read_to_end(&mut content)
+            .with_context(|| format!("failed to read {}", address))?;
+        if Self::address(&content) != address {
+            return Err(anyhow!(
+                "content stored at {}{} does not match its address",
+                self.transport.path(),
+                address
+            ));
+        }
+        Ok(content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{InMemoryTransport, TransportWriter};
+    use std::{io::Write, time::SystemTime};
+
+    /// Counts the puts made through it to an InMemoryTransport.
+    #[derive(Debug, Default)]
+    struct CountingTransport {
+        transport: InMemoryTransport,
+        puts: usize,
+    }
+
+    impl Transport for CountingTransport {
+        fn path(&self) -> String {
+            self.transport.path()
+        }
+
+        fn get(&mut self, key: &str) -> Result<Box<dyn Read>> {
+            self.transport.get(key)
+        }
+
+        fn get_if_modified_since(&mut self, key: &str, since: SystemTime) -> Result<Box<dyn Read>> {
+            self.transport.get_if_modified_since(key, since)
+        }
+
+        fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>> {
+            self.puts += 1;
+            self.transport.put(key)
+        }
+
+        fn delete(&mut self, key: &str) -> Result<()> {
+            self.transport.delete(key)
+        }
+
+        fn exists(&mut self, key: &str) -> Result<bool> {
+            self.transport.exists(key)
+        }
+    }
+
+    #[test]
+    fn identical_content_is_stored_once() {
+        let mut transport = ContentAddressedTransport::new(CountingTransport::default());
+
+        let first = transport.put(&mut &b"some content"[..]).unwrap();
+        let second = transport.put(&mut &b"some content"[..]).unwrap();
+        let other = transport.put(&mut &b"other content"[..]).unwrap();
+
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+        assert_eq!(
+            first,
+            "sha256/290f493c44f5d63d06b374d0a5abd292fae38b92cab2fae5efefe1b0e9347f56"
+        );
+        assert_eq!(transport.get(&first).unwrap(), b"some content");
+        assert_eq!(transport.get(&other).unwrap(), b"other content");
+
+        let transport = transport.into_inner();
+        assert_eq!(transport.puts, 2);
+        assert_eq!(transport.transport.keys(), vec![first, other]);
+    }
+
+    #[test]
+    fn corrupt_content_is_detected() {
+        let memory = InMemoryTransport::new();
+        let mut transport = ContentAddressedTransport::new(memory.clone());
+        let address = transport.put(&mut &b"some content"[..]).unwrap();
+
+        let mut writer = memory.clone().put(&address).unwrap();
+        writer.write_all(b"tampered content").unwrap();
+        writer.complete_upload().unwrap();
+
+        assert!(transport.get(&address).is_err());
+        assert!(transport.get("not-an-address").is_err());
+    }
+}
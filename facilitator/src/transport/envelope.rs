@@ -0,0 +1,639 @@
+use crate::{
+    config::Identity,
+    gcp_oauth::OauthTokenProvider,
+    http::{send_json_request, JsonRequestParameters},
+    transport::{Transport, TransportWriter},
+};
+use anyhow::{anyhow, Context, Result};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM},
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::Deserialize;
+use std::{
+    boxed::Box,
+    fmt::Debug,
+    io::{self, Read, Write},
+    mem,
+    time::SystemTime,
+};
+
+const KMS_API_BASE_URL: &str = "https://cloudkms.googleapis.com";
+
+/// Identifies an object written by EnvelopeTransport, and the version of the
+/// format it was written in.
+const ENVELOPE_MAGIC: &[u8] = b"PRIOENV1";
+
+/// Size of the plaintext segments that are individually sealed with AES-GCM, so
+/// that objects can be encrypted and decrypted as they are streamed without
+/// holding an entire object in memory.
+const SEGMENT_SIZE: usize = 65_536;
+
+/// Length of the authentication tag AES-GCM appends to each segment.
+const TAG_LENGTH: usize = 16;
+
+/// Length in bytes of the AES-256 data keys generated for each object.
+const DATA_KEY_LENGTH: usize = 32;
+
+/// Longest wrapped data key read from an envelope header. Cloud KMS wraps a
+/// data key into a ciphertext of a couple hundred bytes, so anything much
+/// longer means the header is corrupt, and its length must not be trusted
+/// with an allocation before the key is unwrapped.
+const MAX_WRAPPED_KEY_LENGTH: usize = 4096;
+
+/// A KeyWrapper encrypts ("wraps") and decrypts data keys with a key that it
+/// holds, normally in a key management service, so that data keys can be
+/// stored alongside the data they encrypt.
+pub trait KeyWrapper: Debug {
+    /// Returns the provided data key, encrypted.
+    fn wrap_key(&mut self, data_key: &[u8]) -> Result<Vec<u8>>;
+    /// Returns the data key that wrap_key encrypted into wrapped_key.
+    fn unwrap_key(&mut self, wrapped_key: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// A KeyWrapper that wraps keys with a symmetric key in GCP Cloud KMS.
+/// https://cloud.google.com/kms/docs/envelope-encryption
+#[derive(Debug)]
+pub struct GcpKmsKeyWrapper {
+    /// Resource name of the key, like
+    /// projects/p/locations/l/keyRings/r/cryptoKeys/k.
+    key_name: String,
+    oauth_token_provider: OauthTokenProvider,
+    kms_api_base_url: String,
+}
+
+/// Represents the response to a request to the GCP KMS encrypt endpoint.
+/// https://cloud.google.com/kms/docs/reference/rest/v1/projects.locations.keyRings.cryptoKeys/encrypt
+#[derive(Deserialize)]
+struct EncryptResponse {
+    ciphertext: String,
+}
+
+/// Represents the response to a request to the GCP KMS decrypt endpoint.
+/// https://cloud.google.com/kms/docs/reference/rest/v1/projects.locations.keyRings.cryptoKeys/decrypt
+#[derive(Deserialize)]
+struct DecryptResponse {
+    plaintext: String,
+}
+
+impl GcpKmsKeyWrapper {
+    /// Creates a key wrapper using the provided KMS key. Authentication works
+    /// as for GCSTransport::new.
+    pub fn new(
+        key_name: &str,
+        identity: Identity,
+        key_file_reader: Option<Box<dyn Read>>,
+    ) -> Result<GcpKmsKeyWrapper> {
+        Ok(GcpKmsKeyWrapper::new_with_api_url(
+            key_name,
+            OauthTokenProvider::new(
+                // https://developers.google.com/identity/protocols/oauth2/scopes#cloudkms
+                "https://www.googleapis.com/auth/cloudkms",
+                identity.map(|x| x.to_string()),
+                key_file_reader,
+            )?,
+            KMS_API_BASE_URL,
+        ))
+    }
+
+    /// Creates a key wrapper which uses the provided token provider and sends
+    /// requests to the provided KMS API endpoint, allowing tests to use fake
+    /// tokens and a mock server.
+    fn new_with_api_url(
+        key_name: &str,
+        oauth_token_provider: OauthTokenProvider,
+        kms_api_base_url: &str,
+    ) -> GcpKmsKeyWrapper {
+        GcpKmsKeyWrapper {
+            key_name: key_name.to_owned(),
+            oauth_token_provider,
+            kms_api_base_url: kms_api_base_url.to_owned(),
+        }
+    }
+
+    /// Sends the provided JSON body to the provided KMS method on the key.
+    fn call(&mut self, method: &str, body: serde_json::Value) -> Result<ureq::Response> {
+        let url = format!("{}/v1/{}:{}", self.kms_api_base_url, self.key_name, method);
+        let http_response = send_json_request(JsonRequestParameters {
+            request: ureq::post(&url).build(),
+            token_provider: Some(&mut self.oauth_token_provider),
+            body,
+            ..Default::default()
+        })?;
+        if http_response.error() {
+            return Err(anyhow!(
+                "failed to {} with KMS key {}: {:?}",
+                method,
+                self.key_name,
+                http_response
+            ));
+        }
+        Ok(http_response)
+    }
+}
+
+impl KeyWrapper for GcpKmsKeyWrapper {
+    fn wrap_key(&mut self, data_key: &[u8]) -> Result<Vec<u8>> {
+        let response: EncryptResponse = self
+            .call(
+                "encrypt",
+                ureq::json!({ "plaintext": base64::encode(data_key) }),
+            )?
+            .into_json_deserialize()
+            .context("failed to decode KMS encrypt response")?;
+        base64::decode(&response.ciphertext).context("failed to decode wrapped key")
+    }
+
+    fn unwrap_key(&mut self, wrapped_key: &[u8]) -> Result<Vec<u8>> {
+        let response: DecryptResponse = self
+            .call(
+                "decrypt",
+                ureq::json!({ "ciphertext": base64::encode(wrapped_key) }),
+            )?
+            .into_json_deserialize()
+            .context("failed to decode KMS decrypt response")?;
+        base64::decode(&response.plaintext).context("failed to decode unwrapped key")
+    }
+}
+
+/// A transport that wraps another and encrypts the objects it puts with AES-GCM
+/// under a fresh data key per object, decrypting them again as they are read
+/// by get. Each data key is wrapped by a KeyWrapper and stored in a header at
+/// the start of the object it encrypts, since the Transport interface has no
+/// notion of object metadata. The header is followed by the ciphertext, which
+/// is sealed in segments of SEGMENT_SIZE bytes so that objects can be streamed.
+/// Each segment's nonce is its index along with a flag marking the last
+/// segment, so reordered, dropped or truncated segments fail decryption just
+/// like tampered ones. The layout is:
+///
+///   ENVELOPE_MAGIC
+///   length of the wrapped key, as a big-endian u32
+///   the wrapped key
+///   the sealed segments, of which only the last may be shorter than
+///   SEGMENT_SIZE + TAG_LENGTH bytes
+#[derive(Debug)]
+pub struct EnvelopeTransport {
+    transport: Box<dyn Transport>,
+    key_wrapper: Box<dyn KeyWrapper>,
+}
+
+impl EnvelopeTransport {
+    pub fn new(
+        transport: Box<dyn Transport>,
+        key_wrapper: Box<dyn KeyWrapper>,
+    ) -> EnvelopeTransport {
+        EnvelopeTransport {
+            transport,
+            key_wrapper,
+        }
+    }
+
+    /// Reads the envelope header from the provided reader, returning the
+    /// unwrapped data key.
+    fn read_header(&mut self, reader: &mut dyn Read) -> Result<LessSafeKey> {
+        let mut magic = [0; ENVELOPE_MAGIC.len()];
+        reader
+            .read_exact(&mut magic)
+            .context("failed to read envelope header")?;
+        if magic != ENVELOPE_MAGIC {
+            return Err(anyhow!("object is not an encrypted envelope"));
+        }
+        let mut wrapped_key_length = [0; 4];
+        reader
+            .read_exact(&mut wrapped_key_length)
+            .context("failed to read wrapped key length")?;
+        let wrapped_key_length = u32::from_be_bytes(wrapped_key_length) as usize;
+        if wrapped_key_length > MAX_WRAPPED_KEY_LENGTH {
+            return Err(anyhow!(
+                "wrapped key length {} in envelope header exceeds the maximum of {}",
+                wrapped_key_length,
+                MAX_WRAPPED_KEY_LENGTH
+            ));
+        }
+        let mut wrapped_key = vec![0; wrapped_key_length];
+        reader
+            .read_exact(&mut wrapped_key)
+            .context("failed to read wrapped key")?;
+        let data_key = self
+            .key_wrapper
+            .unwrap_key(&wrapped_key)
+            .context("failed to unwrap data key")?;
+        data_key_from_bytes(&data_key)
+    }
+
+    fn open(&mut self, mut reader: Box<dyn Read>) -> Result<Box<dyn Read>> {
+        let key = self.read_header(&mut reader)?;
+        Ok(Box::new(EnvelopeReader {
+            reader,
+            key,
+            segment: 0,
+            ciphertext: Vec::with_capacity(SEGMENT_SIZE + TAG_LENGTH + 1),
+            plaintext: Vec::new(),
+            position: 0,
+            done: false,
+        }))
+    }
+}
+
+impl Transport for EnvelopeTransport {
+    fn path(&self) -> String {
+        self.transport.path()
+    }
+
+    fn get(&mut self, key: &str) -> Result<Box<dyn Read>> {
+        let reader = self.transport.get(key)?;
+        self.open(reader)
+            .with_context(|| format!("failed to open envelope {}{}", self.path(), key))
+    }
+
+    fn get_if_modified_since(&mut self, key: &str, since: SystemTime) -> Result<Box<dyn Read>> {
+        let reader = self.transport.get_if_modified_since(key, since)?;
+        self.open(reader)
+            .with_context(|| format!("failed to open envelope {}{}", self.path(), key))
+    }
+
+    fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>> {
+        let mut data_key = [0; DATA_KEY_LENGTH];
+        SystemRandom::new()
+            .fill(&mut data_key)
+            .map_err(|_| anyhow!("failed to generate data key"))?;
+        let wrapped_key = self
+            .key_wrapper
+            .wrap_key(&data_key)
+            .context("failed to wrap data key")?;
+
+        let mut header = ENVELOPE_MAGIC.to_vec();
+        header.extend_from_slice(&(wrapped_key.len() as u32).to_be_bytes());
+        header.extend_from_slice(&wrapped_key);
+
+        let mut writer = self.transport.put(key)?;
+        if let Err(err) = writer.write_all(&header) {
+            let err = anyhow::Error::new(err).context("failed to write envelope header");
+            if let Err(cancel) = writer.cancel_upload() {
+                return Err(cancel.context(err));
+            }
+            return Err(err);
+        }
+        Ok(Box::new(EnvelopeWriter {
+            writer,
+            key: data_key_from_bytes(&data_key)?,
+            segment: 0,
+            buffer: Vec::with_capacity(SEGMENT_SIZE),
+        }))
+    }
+
+    fn delete(&mut self, key: &str) -> Result<()> {
+        self.transport.delete(key)
+    }
+
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>> {
+        self.transport.list(prefix)
+    }
+
+    fn exists(&mut self, key: &str) -> Result<bool> {
+        self.transport.exists(key)
+    }
+}
+
+fn data_key_from_bytes(data_key: &[u8]) -> Result<LessSafeKey> {
+    if data_key.len() != DATA_KEY_LENGTH {
+        return Err(anyhow!(
+            "data key is {} bytes long, expected {}",
+            data_key.len(),
+            DATA_KEY_LENGTH
+        ));
+    }
+    let key = UnboundKey::new(&AES_256_GCM, data_key).map_err(|_| anyhow!("invalid data key"))?;
+    Ok(LessSafeKey::new(key))
+}
+
+/// Returns the nonce for the segment with the provided index. Every object has
+/// its own data key, so nonces only need to be unique within an object.
+fn segment_nonce(segment: u64, last: bool) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[3] = last as u8;
+    nonce[4..].copy_from_slice(&segment.to_be_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
+
+/// Buffers content written to it into segments, sealing each full segment and
+/// writing it to the underlying writer. The last segment, which may be empty,
+/// is sealed when the upload is completed.
+struct EnvelopeWriter {
+    writer: Box<dyn TransportWriter>,
+    key: LessSafeKey,
+    segment: u64,
+    buffer: Vec<u8>,
+}
+
+impl EnvelopeWriter {
+    fn seal_segment(&mut self, mut segment: Vec<u8>, last: bool) -> io::Result<()> {
+        self.key
+            .seal_in_place_append_tag(
+                segment_nonce(self.segment, last),
+                Aad::empty(),
+                &mut segment,
+            )
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "failed to seal segment"))?;
+        self.writer.write_all(&segment)?;
+        self.segment += 1;
+        Ok(())
+    }
+}
+
+impl Write for EnvelopeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut remaining = buf;
+        while !remaining.is_empty() {
+            // A full segment is only sealed once more content arrives, since
+            // until then it might turn out to be the last one.
+            if self.buffer.len() == SEGMENT_SIZE {
+                let segment = mem::replace(&mut self.buffer, Vec::with_capacity(SEGMENT_SIZE));
+                self.seal_segment(segment, false)?;
+            }
+            let room = SEGMENT_SIZE - self.buffer.len();
+            let (taken, rest) = remaining.split_at(room.min(remaining.len()));
+            self.buffer.extend_from_slice(taken);
+            remaining = rest;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Content in a partial segment can't be written until the segment is
+        // sealed.
+        self.writer.flush()
+    }
+}
+
+impl TransportWriter for EnvelopeWriter {
+    fn complete_upload(&mut self) -> Result<()> {
+        let segment = mem::take(&mut self.buffer);
+        self.seal_segment(segment, true)
+            .context("failed to write last segment")?;
+        self.writer.complete_upload()
+    }
+
+    fn cancel_upload(&mut self) -> Result<()> {
+        self.writer.cancel_upload()
+    }
+}
+
+/// Reads sealed segments from the underlying reader, yielding their contents
+/// once each has been authenticated.
+struct EnvelopeReader {
+    reader: Box<dyn Read>,
+    key: LessSafeKey,
+    segment: u64,
+    /// Ciphertext read but not yet opened. One byte beyond a full segment is
+    /// read ahead to find out whether a segment is the last.
+    ciphertext: Vec<u8>,
+    plaintext: Vec<u8>,
+    /// How much of plaintext has been returned by read.
+    position: usize,
+    /// Whether the last segment has been opened.
+    done: bool,
+}
+
+impl EnvelopeReader {
+    fn open_next_segment(&mut self) -> io::Result<()> {
+        let record_length = SEGMENT_SIZE + TAG_LENGTH;
+        let mut chunk = [0; 8192];
+        while self.ciphertext.len() <= record_length {
+            let wanted = (record_length + 1 - self.ciphertext.len()).min(chunk.len());
+            let read = self.reader.read(&mut chunk[..wanted])?;
+            if read == 0 {
+                break;
+            }
+            self.ciphertext.extend_from_slice(&chunk[..read]);
+        }
+        let last = self.ciphertext.len() <= record_length;
+        let mut record = if last {
+            mem::take(&mut self.ciphertext)
+        } else {
+            let rest = self.ciphertext.split_off(record_length);
+            mem::replace(&mut self.ciphertext, rest)
+        };
+        let plaintext_length = self
+            .key
+            .open_in_place(segment_nonce(self.segment, last), Aad::empty(), &mut record)
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("failed to decrypt segment {}", self.segment),
+                )
+            })?
+            .len();
+        record.truncate(plaintext_length);
+        self.plaintext = record;
+        self.position = 0;
+        self.segment += 1;
+        self.done = last;
+        Ok(())
+    }
+}
+
+impl Read for EnvelopeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.plaintext.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.open_next_segment()?;
+        }
+        let read = (self.plaintext.len() - self.position).min(buf.len());
+        buf[..read].copy_from_slice(&self.plaintext[self.position..self.position + read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::InMemoryTransport;
+    use mockito::{mock, Matcher};
+
+    /// A KeyWrapper that "wraps" keys by reversing them.
+    #[derive(Debug)]
+    struct FakeKeyWrapper;
+
+    impl KeyWrapper for FakeKeyWrapper {
+        fn wrap_key(&mut self, data_key: &[u8]) -> Result<Vec<u8>> {
+            Ok(data_key.iter().rev().cloned().collect())
+        }
+
+        fn unwrap_key(&mut self, wrapped_key: &[u8]) -> Result<Vec<u8>> {
+            Ok(wrapped_key.iter().rev().cloned().collect())
+        }
+    }
+
+    fn put(transport: &mut EnvelopeTransport, key: &str, content: &[u8]) {
+        let mut writer = transport.put(key).unwrap();
+        // Write in uneven pieces to exercise segment boundaries
+        for piece in content.chunks(10_000) {
+            writer.write_all(piece).unwrap();
+        }
+        writer.complete_upload().unwrap();
+    }
+
+    #[test]
+    fn round_trip() {
+        let inner = InMemoryTransport::new();
+        let mut transport =
+            EnvelopeTransport::new(Box::new(inner.clone()), Box::new(FakeKeyWrapper));
+
+        for size in &[0, 1, SEGMENT_SIZE - 1, SEGMENT_SIZE, 3 * SEGMENT_SIZE + 17] {
+            let content: Vec<u8> = (0..*size).map(|i| (i % 251) as u8).collect();
+            let key = format!("object-{}", size);
+            put(&mut transport, &key, &content);
+
+            let stored = inner.object(&key).unwrap();
+            assert!(stored.starts_with(ENVELOPE_MAGIC));
+            // The plaintext is nowhere to be found in what was stored
+            if *size >= 64 {
+                assert!(!stored.windows(64).any(|window| window == &content[..64]));
+            }
+
+            let mut read_back = Vec::new();
+            transport
+                .get(&key)
+                .unwrap()
+                .read_to_end(&mut read_back)
+                .unwrap();
+            assert_eq!(read_back, content, "size {}", size);
+        }
+    }
+
+    #[test]
+    fn list_and_exists_are_forwarded() {
+        let mut transport =
+            EnvelopeTransport::new(Box::new(InMemoryTransport::new()), Box::new(FakeKeyWrapper));
+        put(&mut transport, "batch/first", b"first");
+        put(&mut transport, "batch/second", b"second");
+        put(&mut transport, "other", b"other");
+
+        assert_eq!(
+            transport.list("batch/").unwrap(),
+            vec!["batch/first".to_owned(), "batch/second".to_owned()]
+        );
+        assert!(transport.exists("batch/first").unwrap());
+        assert!(!transport.exists("batch/third").unwrap());
+    }
+
+    #[test]
+    fn oversized_wrapped_key_length_is_rejected() {
+        let mut inner = InMemoryTransport::new();
+        let mut transport =
+            EnvelopeTransport::new(Box::new(inner.clone()), Box::new(FakeKeyWrapper));
+        let mut header = ENVELOPE_MAGIC.to_vec();
+        header.extend_from_slice(&u32::MAX.to_be_bytes());
+        header.extend_from_slice(&[0; DATA_KEY_LENGTH]);
+        let mut writer = inner.put("object").unwrap();
+        writer.write_all(&header).unwrap();
+        writer.complete_upload().unwrap();
+
+        let err = transport.get("object").err().unwrap();
+        assert!(
+            format!("{:#}", err).contains("exceeds the maximum"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn tampered_ciphertext_fails_decryption() {
+        let mut inner = InMemoryTransport::new();
+        let mut transport =
+            EnvelopeTransport::new(Box::new(inner.clone()), Box::new(FakeKeyWrapper));
+        let content = vec![7; 2 * SEGMENT_SIZE + 100];
+        put(&mut transport, "object", &content);
+        let stored = inner.object("object").unwrap();
+        let header_length = ENVELOPE_MAGIC.len() + 4 + DATA_KEY_LENGTH;
+
+        let mut store = |stored: Vec<u8>| {
+            let mut writer = inner.put("object").unwrap();
+            writer.write_all(&stored).unwrap();
+            writer.complete_upload().unwrap();
+        };
+        let read = |transport: &mut EnvelopeTransport| {
+            let mut read_back = Vec::new();
+            transport
+                .get("object")
+                .unwrap()
+                .read_to_end(&mut read_back)
+                .map(|_| read_back)
+        };
+
+        // A flipped bit in the second segment
+        let mut flipped = stored.clone();
+        flipped[header_length + SEGMENT_SIZE + TAG_LENGTH + 5] ^= 1;
+        store(flipped);
+        assert_eq!(
+            read(&mut transport).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        // The last segment dropped, so that the object ends on a full segment
+        let mut truncated = stored.clone();
+        truncated.truncate(header_length + 2 * (SEGMENT_SIZE + TAG_LENGTH));
+        store(truncated);
+        assert!(read(&mut transport).is_err());
+
+        // Segments swapped
+        let mut swapped = stored[..header_length].to_vec();
+        let first = &stored[header_length..header_length + SEGMENT_SIZE + TAG_LENGTH];
+        let second = &stored[header_length + SEGMENT_SIZE + TAG_LENGTH
+            ..header_length + 2 * (SEGMENT_SIZE + TAG_LENGTH)];
+        swapped.extend_from_slice(second);
+        swapped.extend_from_slice(first);
+        swapped.extend_from_slice(&stored[header_length + 2 * (SEGMENT_SIZE + TAG_LENGTH)..]);
+        store(swapped);
+        assert!(read(&mut transport).is_err());
+
+        // The untampered object still decrypts
+        store(stored);
+        assert_eq!(read(&mut transport).unwrap(), content);
+    }
+
+    #[test]
+    fn gcp_kms_key_wrapper() {
+        let key_name = "projects/p/locations/global/keyRings/r/cryptoKeys/k";
+        let mut key_wrapper = GcpKmsKeyWrapper::new_with_api_url(
+            key_name,
+            OauthTokenProvider::new_with_token("fake-token"),
+            &mockito::server_url(),
+        );
+        let mocked_encrypt = mock("POST", format!("/v1/{}:encrypt", key_name).as_str())
+            .match_header("Authorization", "Bearer fake-token")
+            .match_body(Matcher::Json(
+                ureq::json!({ "plaintext": base64::encode(b"data key") }),
+            ))
+            .with_status(200)
+            .with_body(format!(
+                r#"{{"name":"{}","ciphertext":"{}"}}"#,
+                key_name,
+                base64::encode(b"wrapped key")
+            ))
+            .expect(1)
+            .create();
+        let mocked_decrypt = mock("POST", format!("/v1/{}:decrypt", key_name).as_str())
+            .match_header("Authorization", "Bearer fake-token")
+            .match_body(Matcher::Json(
+                ureq::json!({ "ciphertext": base64::encode(b"wrapped key") }),
+            ))
+            .with_status(200)
+            .with_body(format!(
+                r#"{{"plaintext":"{}"}}"#,
+                base64::encode(b"data key")
+            ))
+            .expect(1)
+            .create();
+
+        let wrapped_key = key_wrapper.wrap_key(b"data key").unwrap();
+        assert_eq!(wrapped_key, b"wrapped key");
+        assert_eq!(key_wrapper.unwrap_key(&wrapped_key).unwrap(), b"data key");
+        mocked_encrypt.assert();
+        mocked_decrypt.assert();
+    }
+}
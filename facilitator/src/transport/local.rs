@@ -1,11 +1,15 @@
-use crate::transport::{Transport, TransportWriter};
+use crate::{
+    transport::{Transport, TransportWriter},
+    Error,
+};
 use anyhow::{Context, Result};
 
 use std::{
     boxed::Box,
-    fs::{create_dir_all, File},
-    io::Read,
-    path::{PathBuf, MAIN_SEPARATOR},
+    fs::{create_dir_all, metadata, read_dir, remove_file, File},
+    io::{ErrorKind, Read, Seek, SeekFrom},
+    path::{Path, PathBuf, MAIN_SEPARATOR},
+    time::SystemTime,
 };
 
 /// A transport implementation backed by the local filesystem.
@@ -27,6 +31,12 @@ impl LocalFileTransport {
     fn relative_path(key: &str) -> PathBuf {
         PathBuf::from(key.replace("/", &MAIN_SEPARATOR.to_string()))
     }
+
+    /// Converts a path relative to the transport's directory back into the
+    /// key it was created from.
+    fn key(relative_path: &Path) -> String {
+        relative_path.to_string_lossy().replace(MAIN_SEPARATOR, "/")
+    }
 }
 
 impl Transport for LocalFileTransport {
@@ -41,6 +51,69 @@ impl Transport for LocalFileTransport {
         Ok(Box::new(f))
     }
 
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut directories = vec![PathBuf::new()];
+        while let Some(relative) = directories.pop() {
+            let directory = self.directory.join(&relative);
+            let entries = match read_dir(&directory) {
+                // Nothing has been put yet
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                result => result.with_context(|| format!("listing {}", directory.display()))?,
+            };
+            for entry in entries {
+                let entry = entry.with_context(|| format!("listing {}", directory.display()))?;
+                let path = relative.join(entry.file_name());
+                if entry
+                    .file_type()
+                    .with_context(|| format!("inspecting {}", path.display()))?
+                    .is_dir()
+                {
+                    directories.push(path);
+                    continue;
+                }
+                let key = LocalFileTransport::key(&path);
+                if key.starts_with(prefix) {
+                    keys.push(key);
+                }
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn exists(&mut self, key: &str) -> Result<bool> {
+        let path = self.directory.join(LocalFileTransport::relative_path(key));
+        match metadata(path.as_path()) {
+            Ok(metadata) => Ok(metadata.is_file()),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err).with_context(|| format!("inspecting {}", path.display())),
+        }
+    }
+
+    fn get_range(&mut self, key: &str, offset: u64, length: u64) -> Result<Box<dyn Read>> {
+        let path = self.directory.join(LocalFileTransport::relative_path(key));
+        let mut f =
+            File::open(path.as_path()).with_context(|| format!("opening {}", path.display()))?;
+        f.seek(SeekFrom::Start(offset))
+            .with_context(|| format!("seeking to {} in {}", offset, path.display()))?;
+        Ok(Box::new(f.take(length)))
+    }
+
+    fn get_if_modified_since(&mut self, key: &str, since: SystemTime) -> Result<Box<dyn Read>> {
+        let path = self.directory.join(LocalFileTransport::relative_path(key));
+        let f =
+            File::open(path.as_path()).with_context(|| format!("opening {}", path.display()))?;
+        let modified = f
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .with_context(|| format!("getting modification time of {}", path.display()))?;
+        if modified <= since {
+            return Err(Error::NotModified(path.display().to_string()).into());
+        }
+        Ok(Box::new(f))
+    }
+
     fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>> {
         let path = self.directory.join(LocalFileTransport::relative_path(key));
         if let Some(parent) = path.parent() {
@@ -51,6 +124,14 @@ impl Transport for LocalFileTransport {
             File::create(path.as_path()).with_context(|| format!("creating {}", path.display()))?;
         Ok(Box::new(f))
     }
+
+    fn delete(&mut self, key: &str) -> Result<()> {
+        let path = self.directory.join(LocalFileTransport::relative_path(key));
+        match remove_file(path.as_path()) {
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            result => result.with_context(|| format!("removing {}", path.display())),
+        }
+    }
 }
 
 impl TransportWriter for File {
@@ -100,4 +181,72 @@ mod tests {
             assert_eq!(content_again, content);
         }
     }
+
+    #[test]
+    fn get_range() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut file_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+        let mut writer = file_transport.put("path").unwrap();
+        writer.write_all(&[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        writer.complete_upload().unwrap();
+
+        for (offset, length, expected) in
+            &[(2, 3, vec![3, 4, 5]), (6, 10, vec![7, 8]), (20, 4, vec![])]
+        {
+            let mut range = Vec::new();
+            file_transport
+                .get_range("path", *offset, *length)
+                .unwrap()
+                .read_to_end(&mut range)
+                .unwrap();
+            assert_eq!(&range, expected);
+        }
+    }
+
+    #[test]
+    fn delete_missing_file() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut file_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+        file_transport.put("path").unwrap();
+
+        file_transport.delete("path").unwrap();
+        file_transport.delete("path").unwrap();
+        assert!(file_transport.get("path").is_err());
+    }
+
+    #[test]
+    fn list() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut file_transport = LocalFileTransport::new(tempdir.path().join("missing"));
+        assert!(file_transport.list("").unwrap().is_empty());
+
+        let mut file_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+        for path in &["batch-2", "batch-1/header", "batch-1/packets/0", "other"] {
+            file_transport.put(path).unwrap();
+        }
+        assert_eq!(
+            file_transport.list("").unwrap(),
+            vec!["batch-1/header", "batch-1/packets/0", "batch-2", "other"]
+        );
+        assert_eq!(
+            file_transport.list("batch-").unwrap(),
+            vec!["batch-1/header", "batch-1/packets/0", "batch-2"]
+        );
+        assert_eq!(
+            file_transport.list("batch-1/packets/").unwrap(),
+            vec!["batch-1/packets/0"]
+        );
+    }
+
+    #[test]
+    fn exists() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut file_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+        file_transport.put("batch-1/header").unwrap();
+
+        assert!(file_transport.exists("batch-1/header").unwrap());
+        assert!(!file_transport.exists("batch-1/packets").unwrap());
+        // Directories hold values but aren't values themselves.
+        assert!(!file_transport.exists("batch-1").unwrap());
+    }
 }
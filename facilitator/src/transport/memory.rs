@@ -0,0 +1,171 @@
+use crate::{
+    transport::{Transport, TransportWriter},
+    Error,
+};
+use anyhow::{anyhow, Result};
+use std::{
+    boxed::Box,
+    collections::HashMap,
+    io,
+    io::{Cursor, Read, Write},
+    mem,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+#[derive(Debug)]
+struct Object {
+    content: Vec<u8>,
+    modified: SystemTime,
+}
+
+#[derive(Debug, Default)]
+struct Objects {
+    objects: HashMap<String, Object>,
+    cancelled_uploads: Vec<String>,
+}
+
+/// A transport implementation that keeps objects in memory. Objects written
+/// with put become visible to get once the upload is completed. Clones of an
+/// InMemoryTransport share the same objects, so a clone may be handed to code
+/// under test while the original is used to inspect what was written.
+#[derive(Clone, Debug, Default)]
+pub struct InMemoryTransport {
+    objects: Arc<Mutex<Objects>>,
+}
+
+impl InMemoryTransport {
+    pub fn new() -> InMemoryTransport {
+        InMemoryTransport::default()
+    }
+
+    /// Returns a copy of the contents of the object at the provided key, if
+    /// there is one.
+    pub fn object(&self, key: &str) -> Option<Vec<u8>> {
+        self.objects
+            .lock()
+            .unwrap()
+            .objects
+            .get(key)
+            .map(|object| object.content.clone())
+    }
+
+    /// Returns the keys of all the objects, in sorted order.
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self
+            .objects
+            .lock()
+            .unwrap()
+            .objects
+            .keys()
+            .cloned()
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    /// Returns the keys of any uploads that were cancelled, in the order they
+    /// were cancelled.
+    pub fn cancelled_uploads(&self) -> Vec<String> {
+        self.objects.lock().unwrap().cancelled_uploads.clone()
+    }
+}
+
+impl Transport for InMemoryTransport {
+    fn path(&self) -> String {
+        "memory://".to_owned()
+    }
+
+    fn get(&mut self, key: &str) -> Result<Box<dyn Read>> {
+        match self.object(key) {
+            Some(content) => Ok(Box::new(Cursor::new(content))),
+            None => Err(anyhow!("no object {} in memory", key)),
+        }
+    }
+
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = self.keys();
+        keys.retain(|key| key.starts_with(prefix));
+        Ok(keys)
+    }
+
+    fn exists(&mut self, key: &str) -> Result<bool> {
+        Ok(self.objects.lock().unwrap().objects.contains_key(key))
+    }
+
+    fn get_range(&mut self, key: &str, offset: u64, length: u64) -> Result<Box<dyn Read>> {
+        match self.object(key) {
+            Some(content) => {
+                let start = content.len().min(offset as usize);
+                let end = content.len().min(start.saturating_add(length as usize));
+                Ok(Box::new(Cursor::new(content[start..end].to_vec())))
+            }
+            None => Err(anyhow!("no object {} in memory", key)),
+        }
+    }
+
+    fn get_if_modified_since(&mut self, key: &str, since: SystemTime) -> Result<Box<dyn Read>> {
+        let objects = self.objects.lock().unwrap();
+        match objects.objects.get(key) {
+            Some(object) if object.modified <= since => {
+                Err(Error::NotModified(key.to_owned()).into())
+            }
+            Some(object) => Ok(Box::new(Cursor::new(object.content.clone()))),
+            None => Err(anyhow!("no object {} in memory", key)),
+        }
+    }
+
+    fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>> {
+        Ok(Box::new(InMemoryWriter {
+            key: key.to_owned(),
+            buffer: Vec::new(),
+            objects: self.objects.clone(),
+        }))
+    }
+
+    fn delete(&mut self, key: &str) -> Result<()> {
+        self.objects.lock().unwrap().objects.remove(key);
+        Ok(())
+    }
+}
+
+/// A TransportWriter that buffers its content in memory until the upload is
+/// completed.
+struct InMemoryWriter {
+    key: String,
+    buffer: Vec<u8>,
+    objects: Arc<Mutex<Objects>>,
+}
+
+impl Write for InMemoryWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl TransportWriter for InMemoryWriter {
+    fn complete_upload(&mut self) -> Result<()> {
+        self.objects.lock().unwrap().objects.insert(
+            self.key.clone(),
+            Object {
+                content: mem::take(&mut self.buffer),
+                modified: SystemTime::now(),
+            },
+        );
+        Ok(())
+    }
+
+    fn cancel_upload(&mut self) -> Result<()> {
+        self.buffer.clear();
+        self.objects
+            .lock()
+            .unwrap()
+            .cancelled_uploads
+            .push(self.key.clone());
+        Ok(())
+    }
+}
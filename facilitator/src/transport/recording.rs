@@ -0,0 +1,559 @@
+use crate::{
+    transport::{http_date, Transport, TransportWriter},
+    Error,
+};
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    boxed::Box,
+    collections::VecDeque,
+    fs::{self, File},
+    io::{self, Cursor, Read, Write},
+    mem,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+/// An operation performed on a transport, as recorded by RecordingTransport.
+/// Each is stored as one line of JSON in the recording file.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "operation", rename_all = "snake_case")]
+enum Operation {
+    Get {
+        key: String,
+        outcome: Outcome,
+    },
+    GetIfModifiedSince {
+        key: String,
+        /// The time, as an HTTP-date, which is precise to a second.
+        since: String,
+        outcome: Outcome,
+    },
+    /// A put whose upload was completed. Cancelled uploads aren't recorded.
+    Put {
+        key: String,
+        /// The object's contents, encoded in Base64.
+        content: String,
+        outcome: Outcome,
+    },
+    Delete {
+        key: String,
+        outcome: Outcome,
+    },
+    List {
+        prefix: String,
+        outcome: Outcome,
+    },
+    Exists {
+        key: String,
+        outcome: Outcome,
+    },
+}
+
+impl Operation {
+    fn outcome_mut(&mut self) -> &mut Outcome {
+        match self {
+            Operation::Get { outcome, .. }
+            | Operation::GetIfModifiedSince { outcome, .. }
+            | Operation::Put { outcome, .. }
+            | Operation::Delete { outcome, .. }
+            | Operation::List { outcome, .. }
+            | Operation::Exists { outcome, .. } => outcome,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Outcome {
+    /// The operation succeeded.
+    Ok,
+    /// The get succeeded, returning the provided contents, encoded in Base64.
+    Content(String),
+    /// The list succeeded, returning the provided keys.
+    Keys(Vec<String>),
+    /// The existence check succeeded, returning whether the key has a value.
+    Exists(bool),
+    /// The get failed with crate::Error::NotModified.
+    NotModified,
+    /// The operation failed with an error with the provided description.
+    Error(String),
+}
+
+impl Outcome {
+    fn of<T>(result: &Result<T>) -> Outcome {
+        match result {
+            Ok(_) => Outcome::Ok,
+            Err(err) => match err.downcast_ref() {
+                Some(Error::NotModified(_)) => Outcome::NotModified,
+                _ => Outcome::Error(format!("{:?}", err)),
+            },
+        }
+    }
+
+    /// Reproduces the outcome of a recorded operation on key.
+    fn replay(self, key: &str) -> Result<Vec<u8>> {
+        match self {
+            Outcome::Ok => Ok(Vec::new()),
+            Outcome::Content(content) => {
+                base64::decode(&content).context("invalid content in recording")
+            }
+            Outcome::Keys(_) | Outcome::Exists(_) => {
+                Err(anyhow!("recorded outcome for {} is not an object", key))
+            }
+            Outcome::NotModified => Err(Error::NotModified(key.to_owned()).into()),
+            Outcome::Error(description) => Err(anyhow!("recorded error: {}", description)),
+        }
+    }
+
+    /// Reproduces the outcome of a recorded list of prefix.
+    fn replay_keys(self, prefix: &str) -> Result<Vec<String>> {
+        match self {
+            Outcome::Keys(keys) => Ok(keys),
+            Outcome::Error(description) => Err(anyhow!("recorded error: {}", description)),
+            _ => Err(anyhow!("recorded outcome for {} is not a list", prefix)),
+        }
+    }
+
+    /// Reproduces the outcome of a recorded existence check of key.
+    fn replay_exists(self, key: &str) -> Result<bool> {
+        match self {
+            Outcome::Exists(exists) => Ok(exists),
+            Outcome::Error(description) => Err(anyhow!("recorded error: {}", description)),
+            _ => Err(anyhow!(
+                "recorded outcome for {} is not an existence check",
+                key
+            )),
+        }
+    }
+}
+
+/// Appends operations to a recording file.
+#[derive(Debug)]
+struct Recorder {
+    path: PathBuf,
+    file: File,
+}
+
+impl Recorder {
+    fn record(&mut self, operation: &Operation) -> Result<()> {
+        let mut line = serde_json::to_vec(operation).context("failed to encode operation")?;
+        line.push(b'\n');
+        self.file
+            .write_all(&line)
+            .with_context(|| format!("failed to write to {}", self.path.display()))
+    }
+}
+
+/// A transport that wraps another and records every get, put, delete, list
+/// and existence check made through it, along with the contents of the objects read and written and
+/// whether each operation succeeded, to a file that ReplayTransport can later
+/// serve back. Objects are read in their entirety when they are fetched, so
+/// that their contents can be recorded, which makes this only suitable for
+/// tests. Puts are recorded when their upload is completed.
+#[derive(Debug)]
+pub struct RecordingTransport {
+    transport: Box<dyn Transport>,
+    recorder: Arc<Mutex<Recorder>>,
+}
+
+impl RecordingTransport {
+    /// Creates a RecordingTransport that records operations on transport to
+    /// the file at the provided path, replacing anything already there.
+    pub fn new(transport: Box<dyn Transport>, path: &Path) -> Result<RecordingTransport> {
+        let file = File::create(path)
+            .with_context(|| format!("failed to create recording {}", path.display()))?;
+        Ok(RecordingTransport {
+            transport,
+            recorder: Arc::new(Mutex::new(Recorder {
+                path: path.to_owned(),
+                file,
+            })),
+        })
+    }
+
+    /// Reads the whole object returned by get, so that the outcome of the get
+    /// can be recorded with its contents.
+    fn read_all(result: Result<Box<dyn Read>>) -> Result<Vec<u8>> {
+        let mut content = Vec::new();
+        result?
+            .read_to_end(&mut content)
+            .context("failed to read object")?;
+        Ok(content)
+    }
+
+    fn record_get(
+        &mut self,
+        result: Result<Vec<u8>>,
+        operation: impl FnOnce(Outcome) -> Operation,
+    ) -> Result<Box<dyn Read>> {
+        let outcome = match &result {
+            Ok(content) => Outcome::Content(base64::encode(content)),
+            Err(_) => Outcome::of(&result),
+        };
+        self.recorder.lock().unwrap().record(&operation(outcome))?;
+        Ok(Box::new(Cursor::new(result?)))
+    }
+}
+
+impl Transport for RecordingTransport {
+    fn path(&self) -> String {
+        self.transport.path()
+    }
+
+    fn get(&mut self, key: &str) -> Result<Box<dyn Read>> {
+        let result = Self::read_all(self.transport.get(key));
+        self.record_get(result, |outcome| Operation::Get {
+            key: key.to_owned(),
+            outcome,
+        })
+    }
+
+    fn get_if_modified_since(&mut self, key: &str, since: SystemTime) -> Result<Box<dyn Read>> {
+        let result = Self::read_all(self.transport.get_if_modified_since(key, since));
+        self.record_get(result, |outcome| Operation::GetIfModifiedSince {
+            key: key.to_owned(),
+            since: http_date(since),
+            outcome,
+        })
+    }
+
+    fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>> {
+        Ok(Box::new(RecordingWriter {
+            key: key.to_owned(),
+            writer: self.transport.put(key)?,
+            content: Vec::new(),
+            recorder: self.recorder.clone(),
+        }))
+    }
+
+    fn delete(&mut self, key: &str) -> Result<()> {
+        let result = self.transport.delete(key);
+        self.recorder.lock().unwrap().record(&Operation::Delete {
+            key: key.to_owned(),
+            outcome: Outcome::of(&result),
+        })?;
+        result
+    }
+
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>> {
+        let result = self.transport.list(prefix);
+        let outcome = match &result {
+            Ok(keys) => Outcome::Keys(keys.clone()),
+            Err(_) => Outcome::of(&result),
+        };
+        self.recorder.lock().unwrap().record(&Operation::List {
+            prefix: prefix.to_owned(),
+            outcome,
+        })?;
+        result
+    }
+
+    fn exists(&mut self, key: &str) -> Result<bool> {
+        let result = self.transport.exists(key);
+        let outcome = match &result {
+            Ok(exists) => Outcome::Exists(*exists),
+            Err(_) => Outcome::of(&result),
+        };
+        self.recorder.lock().unwrap().record(&Operation::Exists {
+            key: key.to_owned(),
+            outcome,
+        })?;
+        result
+    }
+}
+
+/// The writer returned by RecordingTransport::put, which keeps a copy of
+/// everything written to it so that it can be recorded.
+struct RecordingWriter {
+    key: String,
+    writer: Box<dyn TransportWriter>,
+    content: Vec<u8>,
+    recorder: Arc<Mutex<Recorder>>,
+}
+
+impl Write for RecordingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.content.extend_from_slice(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl TransportWriter for RecordingWriter {
+    fn complete_upload(&mut self) -> Result<()> {
+        let result = self.writer.complete_upload();
+        self.recorder.lock().unwrap().record(&Operation::Put {
+            key: self.key.clone(),
+            content: base64::encode(mem::take(&mut self.content)),
+            outcome: Outcome::of(&result),
+        })?;
+        result
+    }
+
+    fn cancel_upload(&mut self) -> Result<()> {
+        self.content.clear();
+        self.writer.cancel_upload()
+    }
+}
+
+/// A transport that serves back the operations recorded by a
+/// RecordingTransport, without any data store behind it. Each operation made
+/// through it must be the same as the next one in the recording, down to the
+/// contents of objects put, and gets the same outcome as the recorded one,
+/// including any error. An operation that differs from the recording fails,
+/// as does every operation after the recording is exhausted.
+#[derive(Debug)]
+pub struct ReplayTransport {
+    path: PathBuf,
+    operations: Arc<Mutex<VecDeque<Operation>>>,
+}
+
+impl ReplayTransport {
+    /// Loads the recording at the provided path.
+    pub fn new(path: &Path) -> Result<ReplayTransport> {
+        let recording = fs::read_to_string(path)
+            .with_context(|| format!("failed to read recording {}", path.display()))?;
+        let operations = recording
+            .lines()
+            .enumerate()
+            .map(|(index, line)| {
+                serde_json::from_str(line).with_context(|| {
+                    format!(
+                        "invalid operation on line {} of {}",
+                        index + 1,
+                        path.display()
+                    )
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(ReplayTransport {
+            path: path.to_owned(),
+            operations: Arc::new(Mutex::new(operations)),
+        })
+    }
+
+    /// Returns an error unless every recorded operation has been replayed.
+    pub fn check_finished(&self) -> Result<()> {
+        match self.operations.lock().unwrap().front() {
+            Some(operation) => Err(anyhow!(
+                "recorded operation {:?} was never replayed",
+                operation
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Checks that the provided operation, with its outcome left out, is the next
+/// one in the recording and if so returns the recorded outcome.
+fn replay(operations: &Mutex<VecDeque<Operation>>, operation: Operation) -> Result<Outcome> {
+    let mut operations = operations.lock().unwrap();
+    let recorded = operations
+        .front()
+        .with_context(|| format!("unexpected {:?}: recording is exhausted", operation))?;
+    let mut expected = recorded.clone();
+    let recorded_outcome = mem::replace(expected.outcome_mut(), Outcome::Ok);
+    if expected != operation {
+        return Err(anyhow!(
+            "operation {:?} diverges from recording, which expected {:?}",
+            operation,
+            recorded
+        ));
+    }
+    operations.pop_front();
+    Ok(recorded_outcome)
+}
+
+impl Transport for ReplayTransport {
+    fn path(&self) -> String {
+        format!("replay://{}", self.path.display())
+    }
+
+    fn get(&mut self, key: &str) -> Result<Box<dyn Read>> {
+        let outcome = replay(
+            &self.operations,
+            Operation::Get {
+                key: key.to_owned(),
+                outcome: Outcome::Ok,
+            },
+        )?;
+        Ok(Box::new(Cursor::new(outcome.replay(key)?)))
+    }
+
+    fn get_if_modified_since(&mut self, key: &str, since: SystemTime) -> Result<Box<dyn Read>> {
+        let outcome = replay(
+            &self.operations,
+            Operation::GetIfModifiedSince {
+                key: key.to_owned(),
+                since: http_date(since),
+                outcome: Outcome::Ok,
+            },
+        )?;
+        Ok(Box::new(Cursor::new(outcome.replay(key)?)))
+    }
+
+    fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>> {
+        Ok(Box::new(ReplayWriter {
+            key: key.to_owned(),
+            content: Vec::new(),
+            operations: self.operations.clone(),
+        }))
+    }
+
+    fn delete(&mut self, key: &str) -> Result<()> {
+        replay(
+            &self.operations,
+            Operation::Delete {
+                key: key.to_owned(),
+                outcome: Outcome::Ok,
+            },
+        )?
+        .replay(key)
+        .map(|_| ())
+    }
+
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>> {
+        replay(
+            &self.operations,
+            Operation::List {
+                prefix: prefix.to_owned(),
+                outcome: Outcome::Ok,
+            },
+        )?
+        .replay_keys(prefix)
+    }
+
+    fn exists(&mut self, key: &str) -> Result<bool> {
+        replay(
+            &self.operations,
+            Operation::Exists {
+                key: key.to_owned(),
+                outcome: Outcome::Ok,
+            },
+        )?
+        .replay_exists(key)
+    }
+}
+
+/// The writer returned by ReplayTransport::put, which checks what was written
+/// against the recording when the upload is completed.
+struct ReplayWriter {
+    key: String,
+    content: Vec<u8>,
+    operations: Arc<Mutex<VecDeque<Operation>>>,
+}
+
+impl Write for ReplayWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.content.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl TransportWriter for ReplayWriter {
+    fn complete_upload(&mut self) -> Result<()> {
+        replay(
+            &self.operations,
+            Operation::Put {
+                key: self.key.clone(),
+                content: base64::encode(mem::take(&mut self.content)),
+                outcome: Outcome::Ok,
+            },
+        )?
+        .replay(&self.key)
+        .map(|_| ())
+    }
+
+    fn cancel_upload(&mut self) -> Result<()> {
+        self.content.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::InMemoryTransport;
+
+    fn put(transport: &mut dyn Transport, key: &str, content: &[u8]) -> Result<()> {
+        let mut writer = transport.put(key)?;
+        writer.write_all(content)?;
+        writer.complete_upload()
+    }
+
+    fn get(transport: &mut dyn Transport, key: &str) -> Result<Vec<u8>> {
+        let mut content = Vec::new();
+        transport.get(key)?.read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let recording = tempfile::NamedTempFile::new().unwrap();
+        let mut transport =
+            RecordingTransport::new(Box::new(InMemoryTransport::new()), recording.path()).unwrap();
+        put(&mut transport, "object", b"recorded content").unwrap();
+        assert_eq!(get(&mut transport, "object").unwrap(), b"recorded content");
+        assert!(get(&mut transport, "missing").is_err());
+
+        let mut replay = ReplayTransport::new(recording.path()).unwrap();
+        put(&mut replay, "object", b"recorded content").unwrap();
+        replay.check_finished().unwrap_err();
+        assert_eq!(get(&mut replay, "object").unwrap(), b"recorded content");
+        let err = get(&mut replay, "missing").unwrap_err();
+        assert!(
+            err.to_string().contains("no object missing in memory"),
+            "unexpected error {:?}",
+            err
+        );
+        replay.check_finished().unwrap();
+        // Nothing more was recorded
+        assert!(get(&mut replay, "object").is_err());
+
+        // Operations out of order, or with different content, diverge
+        let mut replay = ReplayTransport::new(recording.path()).unwrap();
+        let err = get(&mut replay, "object").unwrap_err();
+        assert!(
+            err.to_string().contains("diverges from recording"),
+            "unexpected error {:?}",
+            err
+        );
+        assert!(put(&mut replay, "object", b"other content").is_err());
+        put(&mut replay, "object", b"recorded content").unwrap();
+    }
+
+    #[test]
+    fn record_and_replay_list_and_exists() {
+        let recording = tempfile::NamedTempFile::new().unwrap();
+        let mut transport =
+            RecordingTransport::new(Box::new(InMemoryTransport::new()), recording.path()).unwrap();
+        put(&mut transport, "batch/object", b"recorded content").unwrap();
+        assert_eq!(
+            transport.list("batch/").unwrap(),
+            vec!["batch/object".to_owned()]
+        );
+        assert!(transport.exists("batch/object").unwrap());
+        assert!(!transport.exists("batch/missing").unwrap());
+
+        let mut replay = ReplayTransport::new(recording.path()).unwrap();
+        put(&mut replay, "batch/object", b"recorded content").unwrap();
+        assert_eq!(
+            replay.list("batch/").unwrap(),
+            vec!["batch/object".to_owned()]
+        );
+        assert!(replay.exists("batch/object").unwrap());
+        // Checking a different key than was recorded diverges
+        assert!(replay.exists("batch/other").is_err());
+        assert!(!replay.exists("batch/missing").unwrap());
+        replay.check_finished().unwrap();
+    }
+}
@@ -1,20 +1,26 @@
+// rusoto's error types are large, and every S3 request made here returns one,
+// so boxing them in each closure passed to retry_request would buy nothing.
+#![allow(clippy::result_large_err)]
+
 use crate::{
     aws_credentials::{basic_runtime, DefaultCredentialsProvider},
     config::{Identity, S3Path},
-    transport::{Transport, TransportWriter},
+    retries::{self, BackoffStrategy, ExponentialWithJitter},
+    transport::{http_date, Transport, TransportWriter},
     Error,
 };
 use anyhow::{Context, Result};
 use derivative::Derivative;
 use hyper_rustls::HttpsConnector;
-use log::{debug, info};
+use log::info;
 use rusoto_core::{
     credential::{AutoRefreshingProvider, CredentialsError, Secret, Variable},
     ByteStream, Region, RusotoError, RusotoResult,
 };
 use rusoto_s3::{
     AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
-    CompletedPart, CreateMultipartUploadRequest, GetObjectRequest, S3Client, UploadPartRequest, S3,
+    CompletedPart, CreateMultipartUploadRequest, DeleteObjectRequest, GetObjectRequest, S3Client,
+    UploadPartRequest, S3,
 };
 use rusoto_sts::WebIdentityProvider;
 use std::{
@@ -23,7 +29,8 @@ use std::{
     io::{Read, Write},
     mem,
     pin::Pin,
-    time::Duration,
+    sync::Arc,
+    time::{Duration, SystemTime},
 };
 use tokio::{
     io::{AsyncRead, AsyncReadExt},
@@ -43,41 +50,31 @@ const METADATA_SERVICE_TOKEN_URL: &str = "http://metadata.google.internal:80/com
 const AWS_ACCOUNT_ID_ENVIRONMENT_VARIABLE: &str = "AWS_ACCOUNT_ID";
 
 /// We attempt AWS API requests up to three times (i.e., two retries)
-const MAX_ATTEMPT_COUNT: i32 = 3;
+const MAX_ATTEMPT_COUNT: u32 = 3;
 
 /// ClientProvider allows mocking out a client for testing.
 type ClientProvider = Box<dyn Fn(&Region, Option<String>) -> Result<S3Client>>;
 
-/// Calls the provided closure, retrying up to MAX_ATTEMPT_COUNT times if it
-/// fails with RusotoError::HttpDispatch, which indicates a problem sending the
-/// request such as the connection getting closed under us.
-fn retry_request<F, T, E>(action: &str, mut f: F) -> RusotoResult<T, E>
+/// Calls the provided closure, retrying as permitted by the provided
+/// BackoffStrategy if it fails with RusotoError::HttpDispatch, which indicates
+/// a problem sending the request such as the connection getting closed under
+/// us.
+fn retry_request<F, T, E>(action: &str, backoff: &dyn BackoffStrategy, f: F) -> RusotoResult<T, E>
 where
     F: FnMut() -> RusotoResult<T, E>,
     E: std::fmt::Debug,
 {
-    let mut attempts = 0;
-    loop {
-        match f() {
-            Err(RusotoError::HttpDispatch(err)) => {
-                attempts += 1;
-                if attempts >= MAX_ATTEMPT_COUNT {
-                    break Err(RusotoError::HttpDispatch(err));
-                }
-                info!(
-                    "failed to {} (will retry {} more times): {}",
-                    action,
-                    MAX_ATTEMPT_COUNT - attempts,
-                    err
-                );
-            }
-            Err(err) => {
-                debug!("encountered non retryable error: {:?}", err);
-                break Err(err);
-            }
-            result => break result,
-        }
-    }
+    retries::retry_request(action, backoff, f, |err| {
+        matches!(err, RusotoError::HttpDispatch(_))
+    })
+}
+
+/// The BackoffStrategy used by S3Transport unless another is provided.
+fn default_backoff() -> Arc<dyn BackoffStrategy> {
+    Arc::new(ExponentialWithJitter {
+        max_attempts: MAX_ATTEMPT_COUNT,
+        ..Default::default()
+    })
 }
 
 /// Implementation of Transport that reads and writes objects from Amazon S3.
@@ -89,6 +86,7 @@ pub struct S3Transport {
     // client_provider allows injection of mock S3Client for testing purposes
     #[derivative(Debug = "ignore")]
     client_provider: ClientProvider,
+    backoff: Arc<dyn BackoffStrategy>,
 }
 
 impl S3Transport {
@@ -208,33 +206,70 @@ impl S3Transport {
             path: path.ensure_directory_prefix(),
             iam_role: identity.map(|x| x.to_string()),
             client_provider,
+            backoff: default_backoff(),
         }
     }
-}
 
-impl Transport for S3Transport {
-    fn path(&self) -> String {
-        self.path.to_string()
+    /// Configures the BackoffStrategy used when retrying failed requests to S3
+    /// made by this transport or the writers it creates.
+    pub fn set_backoff(&mut self, backoff: Arc<dyn BackoffStrategy>) {
+        self.backoff = backoff;
     }
 
-    fn get(&mut self, key: &str) -> Result<Box<dyn Read>> {
-        info!("get {}/{} as {:?}", self.path, key, self.iam_role);
+    /// Fetches the contents of the object at the provided key. If
+    /// if_modified_since is provided, S3 is asked to respond with 304 Not
+    /// Modified if the object has not changed since that time, in which case
+    /// Error::NotModified is returned.
+    fn get_object(
+        &mut self,
+        key: &str,
+        if_modified_since: Option<SystemTime>,
+    ) -> Result<Box<dyn Read>> {
         let mut runtime = basic_runtime()?;
         let client = (self.client_provider)(&self.path.region, self.iam_role.clone())?;
+        let key = [&self.path.key, key].concat();
 
-        let get_output = retry_request("get s3 object", || {
+        let get_output = match retry_request("get s3 object", &*self.backoff, || {
             runtime.block_on(client.get_object(GetObjectRequest {
                 bucket: self.path.bucket.to_owned(),
-                key: [&self.path.key, key].concat(),
+                key: key.clone(),
+                // https://docs.aws.amazon.com/AmazonS3/latest/API/API_GetObject.html#API_GetObject_RequestSyntax
+                if_modified_since: if_modified_since.map(http_date),
                 ..Default::default()
             }))
-        })
-        .context("error getting S3 object")?;
+        }) {
+            Err(RusotoError::Unknown(ref response)) if response.status.as_u16() == 304 => {
+                return Err(Error::NotModified(format!("{}/{}", self.path.bucket, key)).into());
+            }
+            result => result.context("error getting S3 object")?,
+        };
 
         let body = get_output.body.context("no body in GetObjectResponse")?;
 
         Ok(Box::new(StreamingBodyReader::new(body, runtime)))
     }
+}
+
+impl Transport for S3Transport {
+    fn path(&self) -> String {
+        self.path.to_string()
+    }
+
+    fn get(&mut self, key: &str) -> Result<Box<dyn Read>> {
+        info!("get {}/{} as {:?}", self.path, key, self.iam_role);
+        self.get_object(key, None)
+    }
+
+    fn get_if_modified_since(&mut self, key: &str, since: SystemTime) -> Result<Box<dyn Read>> {
+        info!(
+            "get {}/{} if modified since {} as {:?}",
+            self.path,
+            key,
+            http_date(since),
+            self.iam_role
+        );
+        self.get_object(key, Some(since))
+    }
 
     fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>> {
         info!("put {}/{} as {:?}", self.path, key, self.iam_role);
@@ -245,9 +280,26 @@ impl Transport for S3Transport {
             // https://docs.aws.amazon.com/AmazonS3/latest/dev/qfacts.html
             5_242_880,
             (self.client_provider)(&self.path.region, self.iam_role.clone())?,
+            self.backoff.clone(),
         )?;
         Ok(Box::new(writer))
     }
+
+    fn delete(&mut self, key: &str) -> Result<()> {
+        info!("delete {}/{} as {:?}", self.path, key, self.iam_role);
+        let mut runtime = basic_runtime()?;
+        let client = (self.client_provider)(&self.path.region, self.iam_role.clone())?;
+        let key = [&self.path.key, key].concat();
+        retry_request("delete s3 object", &*self.backoff, || {
+            runtime.block_on(client.delete_object(DeleteObjectRequest {
+                bucket: self.path.bucket.to_owned(),
+                key: key.clone(),
+                ..Default::default()
+            }))
+        })
+        .context("error deleting S3 object")?;
+        Ok(())
+    }
 }
 
 /// StreamingBodyReader is an std::io::Read implementation which reads from the
@@ -294,6 +346,7 @@ struct MultipartUploadWriter {
     completed_parts: Vec<CompletedPart>,
     minimum_upload_part_size: usize,
     buffer: Vec<u8>,
+    backoff: Arc<dyn BackoffStrategy>,
 }
 
 impl MultipartUploadWriter {
@@ -306,13 +359,14 @@ impl MultipartUploadWriter {
         key: String,
         minimum_upload_part_size: usize,
         client: S3Client,
+        backoff: Arc<dyn BackoffStrategy>,
     ) -> Result<MultipartUploadWriter> {
         let mut runtime = basic_runtime()?;
 
         // We use the "bucket-owner-full-control" canned ACL to ensure that
         // objects we send to peers will be owned by them.
         // https://docs.aws.amazon.com/AmazonS3/latest/dev/about-object-ownership.html
-        let create_output = retry_request("create multipart upload", || {
+        let create_output = retry_request("create multipart upload", &*backoff, || {
             runtime.block_on(
                 client.create_multipart_upload(CreateMultipartUploadRequest {
                     bucket: bucket.to_string(),
@@ -341,6 +395,7 @@ impl MultipartUploadWriter {
             // that the caller will overflow it.
             minimum_upload_part_size,
             buffer: Vec::with_capacity(minimum_upload_part_size * 2),
+            backoff,
         })
     }
 
@@ -360,7 +415,8 @@ impl MultipartUploadWriter {
             Vec::with_capacity(self.minimum_upload_part_size * 2),
         );
 
-        let upload_output = retry_request("upload part", || {
+        let backoff = self.backoff.clone();
+        let upload_output = retry_request("upload part", &*backoff, || {
             self.runtime
                 .block_on(self.client.upload_part(UploadPartRequest {
                     bucket: self.bucket.to_string(),
@@ -426,7 +482,8 @@ impl TransportWriter for MultipartUploadWriter {
         // Ignore output for now, but we might want the e_tag to check the
         // digest
         let completed_parts = mem::take(&mut self.completed_parts);
-        retry_request("complete upload", || {
+        let backoff = self.backoff.clone();
+        retry_request("complete upload", &*backoff, || {
             self.runtime.block_on(self.client.complete_multipart_upload(
                 CompleteMultipartUploadRequest {
                     bucket: self.bucket.to_string(),
@@ -582,6 +639,7 @@ mod tests {
                 MockCredentialsProvider,
                 Region::UsWest2,
             ),
+            default_backoff(),
         )
         .expect_err("expected error");
         assert!(
@@ -613,6 +671,7 @@ mod tests {
                 MockCredentialsProvider,
                 Region::UsWest2,
             ),
+            default_backoff(),
         )
         .expect_err("expected error");
     }
@@ -622,8 +681,11 @@ mod tests {
         log_init();
         // Response body format from
         // https://docs.aws.amazon.com/AmazonS3/latest/API/API_Operations_Amazon_Simple_Storage_Service.html
-        let mut writer =
-            MultipartUploadWriter::new(String::from(TEST_BUCKET), String::from(TEST_KEY), 50, {
+        let mut writer = MultipartUploadWriter::new(
+            String::from(TEST_BUCKET),
+            String::from(TEST_KEY),
+            50,
+            {
                 let requests = vec![
                     // Response to CreateMultipartUpload
                     MockRequestDispatcher::with_status(200)
@@ -699,8 +761,10 @@ mod tests {
                     MockCredentialsProvider,
                     Region::UsWest2,
                 )
-            })
-            .expect("failed to create multipart upload writer");
+            },
+            default_backoff(),
+        )
+        .expect("failed to create multipart upload writer");
 
         // First write will fail due to HTTP 401
         writer.write_all(&[0; 51]).unwrap_err();
@@ -0,0 +1,254 @@
+use crate::{hex_dump, transport::Transport};
+use anyhow::{anyhow, Context, Result};
+use ring::digest;
+use serde::Deserialize;
+use std::{
+    io,
+    io::{ErrorKind, Read},
+    vec,
+};
+
+/// Describes one shard of a sharded object.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ShardEntry {
+    /// Key of the shard, relative to the transport the manifest was read from.
+    pub key: String,
+    /// Size of the shard in bytes.
+    pub size: u64,
+    /// Hex encoding of the SHA256 digest of the shard's contents.
+    pub sha256: String,
+}
+
+/// A manifest describing a logical object that has been split into several
+/// shards, listing the shards in the order in which they must be concatenated.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ShardManifest {
+    /// Format version of the manifest. Versions besides the currently supported
+    /// one are rejected.
+    pub format: u32,
+    pub shards: Vec<ShardEntry>,
+}
+
+impl ShardManifest {
+    /// Loads the manifest from the provided slice. Returns an error if the
+    /// manifest could not be parsed.
+    pub fn from_slice(json: &[u8]) -> Result<Self> {
+        let manifest: Self =
+            serde_json::from_slice(json).context("failed to decode JSON shard manifest")?;
+        if manifest.format != 0 {
+            return Err(anyhow!("unsupported manifest format {}", manifest.format));
+        }
+        Ok(manifest)
+    }
+}
+
+/// The shard a ShardedReader is currently reading from.
+struct CurrentShard {
+    entry: ShardEntry,
+    reader: Box<dyn Read>,
+    digest: digest::Context,
+    bytes_read: u64,
+}
+
+/// ShardedReader presents the shards listed in a ShardManifest as a single
+/// std::io::Read. Shards are fetched from the transport one at a time, as the
+/// previous one is exhausted. Each shard's size and SHA256 digest are checked
+/// against the manifest as it is read, and reads fail with
+/// std::io::ErrorKind::InvalidData as soon as a mismatch is detected, before
+/// any content from subsequent shards is returned.
+pub struct ShardedReader<'a> {
+    transport: &'a mut dyn Transport,
+    shards: vec::IntoIter<ShardEntry>,
+    current: Option<CurrentShard>,
+}
+
+impl<'a> ShardedReader<'a> {
+    /// Creates a ShardedReader over the shards listed in the manifest at the
+    /// provided key in the transport.
+    pub fn new(transport: &'a mut dyn Transport, manifest_key: &str) -> Result<ShardedReader<'a>> {
+        let mut manifest = Vec::new();
+        transport
+            .get(manifest_key)
+            .with_context(|| format!("failed to get shard manifest {}", manifest_key))?
+            .read_to_end(&mut manifest)
+            .with_context(|| format!("failed to read shard manifest {}", manifest_key))?;
+        let manifest = ShardManifest::from_slice(&manifest)?;
+
+        Ok(ShardedReader::new_with_manifest(transport, manifest))
+    }
+
+    /// Creates a ShardedReader over the shards listed in the provided manifest,
+    /// which will be read from the transport.
+    pub fn new_with_manifest(
+        transport: &'a mut dyn Transport,
+        manifest: ShardManifest,
+    ) -> ShardedReader<'a> {
+        ShardedReader {
+            transport,
+            shards: manifest.shards.into_iter(),
+            current: None,
+        }
+    }
+}
+
+impl<'a> Read for ShardedReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Reading nothing from a shard would look like reaching its end.
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let current = match &mut self.current {
+                Some(current) => current,
+                None => {
+                    let entry = match self.shards.next() {
+                        Some(entry) => entry,
+                        None => return Ok(0),
+                    };
+                    let reader = self.transport.get(&entry.key).map_err(|e| {
+                        io::Error::new(
+                            ErrorKind::Other,
+                            e.context(format!("failed to get shard {}", entry.key)),
+                        )
+                    })?;
+                    self.current.get_or_insert(CurrentShard {
+                        entry,
+                        reader,
+                        digest: digest::Context::new(&digest::SHA256),
+                        bytes_read: 0,
+                    })
+                }
+            };
+
+            let read = current.reader.read(buf)?;
+            if read == 0 {
+                if current.bytes_read != current.entry.size {
+                    return Err(invalid_data(format!(
+                        "shard {} is {} bytes long but manifest says {}",
+                        current.entry.key, current.bytes_read, current.entry.size
+                    )));
+                }
+                let digest = hex_dump(current.digest.clone().finish().as_ref());
+                if !digest.eq_ignore_ascii_case(&current.entry.sha256) {
+                    return Err(invalid_data(format!(
+                        "shard {} has SHA256 digest {} but manifest says {}",
+                        current.entry.key, digest, current.entry.sha256
+                    )));
+                }
+                self.current = None;
+                continue;
+            }
+
+            current.bytes_read += read as u64;
+            if current.bytes_read > current.entry.size {
+                return Err(invalid_data(format!(
+                    "shard {} is longer than the {} bytes listed in manifest",
+                    current.entry.key, current.entry.size
+                )));
+            }
+            current.digest.update(&buf[..read]);
+            return Ok(read);
+        }
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, anyhow!(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{InMemoryTransport, TransportWriter};
+    use std::io::Write;
+
+    fn put_object(transport: &mut InMemoryTransport, key: &str, content: &[u8]) {
+        let mut writer = transport.put(key).unwrap();
+        writer.write_all(content).unwrap();
+        writer.complete_upload().unwrap();
+    }
+
+    fn sha256(content: &[u8]) -> String {
+        hex_dump(digest::digest(&digest::SHA256, content).as_ref())
+    }
+
+    /// Writes three shards and a manifest describing them to the transport.
+    fn put_sharded_object(transport: &mut InMemoryTransport) {
+        let shards: Vec<String> = ["first shard,", "second shard,", "third shard"]
+            .iter()
+            .enumerate()
+            .map(|(index, content)| {
+                let key = format!("shard-{}", index);
+                put_object(transport, &key, content.as_bytes());
+                format!(
+                    r#"{{"key":"{}","size":{},"sha256":"{}"}}"#,
+                    key,
+                    content.len(),
+                    sha256(content.as_bytes())
+                )
+            })
+            .collect();
+        put_object(
+            transport,
+            "manifest.json",
+            format!(r#"{{"format":0,"shards":[{}]}}"#, shards.join(",")).as_bytes(),
+        );
+    }
+
+    #[test]
+    fn read_sharded_object() {
+        let mut transport = InMemoryTransport::new();
+        put_sharded_object(&mut transport);
+
+        let mut content = String::new();
+        ShardedReader::new(&mut transport, "manifest.json")
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "first shard,second shard,third shard");
+    }
+
+    #[test]
+    fn empty_read_is_not_end_of_shard() {
+        let mut transport = InMemoryTransport::new();
+        put_sharded_object(&mut transport);
+
+        let mut reader = ShardedReader::new(&mut transport, "manifest.json").unwrap();
+        let mut first = [0; 5];
+        reader.read_exact(&mut first).unwrap();
+        assert_eq!(reader.read(&mut []).unwrap(), 0);
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, " shard,second shard,third shard");
+    }
+
+    #[test]
+    fn read_corrupted_shard() {
+        let mut transport = InMemoryTransport::new();
+        put_sharded_object(&mut transport);
+        // Same length as the original, but different content
+        put_object(&mut transport, "shard-1", b"SECOND SHARD,");
+
+        let mut reader = ShardedReader::new(&mut transport, "manifest.json").unwrap();
+        let mut content = Vec::new();
+        let err = reader.read_to_end(&mut content).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        // Nothing from the third shard should have been read
+        assert_eq!(content, b"first shard,SECOND SHARD,");
+    }
+
+    #[test]
+    fn read_shard_with_wrong_size() {
+        let mut transport = InMemoryTransport::new();
+        put_sharded_object(&mut transport);
+
+        for wrong_size in &["first shard", "first shard,,"] {
+            put_object(&mut transport, "shard-0", wrong_size.as_bytes());
+            let mut reader = ShardedReader::new(&mut transport, "manifest.json").unwrap();
+            let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use crate::{
+    transport::{Transport, TransportWriter},
+    Error,
+};
+use anyhow::{Context, Result};
+use std::{
+    boxed::Box,
+    collections::HashSet,
+    io::{self, Read, Write},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+/// A transport that wraps another and refuses to put any key more than once
+/// during its lifetime, returning crate::Error::DuplicateWriteInRun without
+/// contacting the underlying transport's data store. This catches pipeline
+/// bugs that write the same object twice within one run. It only knows about
+/// writes made through this instance, so it is not a substitute for
+/// create-only writes to the data store itself. A key only counts as written
+/// once its upload is completed, so an upload that was cancelled or failed
+/// may be retried.
+#[derive(Debug)]
+pub struct WriteOnceTransport {
+    transport: Box<dyn Transport>,
+    written_keys: Arc<Mutex<HashSet<String>>>,
+}
+
+impl WriteOnceTransport {
+    pub fn new(transport: Box<dyn Transport>) -> WriteOnceTransport {
+        WriteOnceTransport {
+            transport,
+            written_keys: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+}
+
+impl Transport for WriteOnceTransport {
+    fn path(&self) -> String {
+        self.transport.path()
+    }
+
+    fn get(&mut self, key: &str) -> Result<Box<dyn Read>> {
+        self.transport.get(key)
+    }
+
+    fn get_if_modified_since(&mut self, key: &str, since: SystemTime) -> Result<Box<dyn Read>> {
+        self.transport.get_if_modified_since(key, since)
+    }
+
+    fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>> {
+        let name = format!("{}{}", self.path(), key);
+        if self.written_keys.lock().unwrap().contains(key) {
+            return Err(Error::DuplicateWriteInRun(name).into());
+        }
+        Ok(Box::new(WriteOnceWriter {
+            writer: self.transport.put(key)?,
+            key: key.to_owned(),
+            name,
+            written_keys: self.written_keys.clone(),
+        }))
+    }
+
+    fn delete(&mut self, key: &str) -> Result<()> {
+        self.transport.delete(key)
+    }
+
+    fn get_prefix(&mut self, key: &str, n: usize) -> Result<Vec<u8>> {
+        self.transport.get_prefix(key, n)
+    }
+
+    fn get_range(&mut self, key: &str, offset: u64, length: u64) -> Result<Box<dyn Read>> {
+        self.transport.get_range(key, offset, length)
+    }
+
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>> {
+        self.transport.list(prefix)
+    }
+
+    fn exists(&mut self, key: &str) -> Result<bool> {
+        self.transport.exists(key)
+    }
+}
+
+/// A TransportWriter that records its key as written once its upload is
+/// completed.
+struct WriteOnceWriter {
+    writer: Box<dyn TransportWriter>,
+    key: String,
+    /// The key with the transport's path, as reported in errors.
+    name: String,
+    written_keys: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Write for WriteOnceWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl TransportWriter for WriteOnceWriter {
+    fn complete_upload(&mut self) -> Result<()> {
+        // Another upload to the same key may have been completed since this
+        // one was started.
+        if self.written_keys.lock().unwrap().contains(&self.key) {
+            self.writer
+                .cancel_upload()
+                .with_context(|| format!("failed to cancel rewrite of {}", self.name))?;
+            return Err(Error::DuplicateWriteInRun(self.name.clone()).into());
+        }
+        self.writer.complete_upload()?;
+        self.written_keys.lock().unwrap().insert(self.key.clone());
+        Ok(())
+    }
+
+    fn cancel_upload(&mut self) -> Result<()> {
+        self.writer.cancel_upload()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::InMemoryTransport;
+    use assert_matches::assert_matches;
+
+    #[test]
+    fn cancelled_put_may_be_retried() {
+        let memory = InMemoryTransport::new();
+        let mut transport = WriteOnceTransport::new(Box::new(memory.clone()));
+
+        let mut writer = transport.put("fake-object").unwrap();
+        writer.write_all(b"abandoned").unwrap();
+        writer.cancel_upload().unwrap();
+
+        let mut writer = transport.put("fake-object").unwrap();
+        writer.write_all(b"retried").unwrap();
+        writer.complete_upload().unwrap();
+        assert_eq!(memory.object("fake-object").unwrap(), b"retried");
+
+        assert_matches!(
+            transport.put("fake-object").err().unwrap().downcast_ref(),
+            Some(Error::DuplicateWriteInRun(_))
+        );
+    }
+
+    #[test]
+    fn range_reads_are_forwarded() {
+        let mut memory = InMemoryTransport::new();
+        let mut writer = memory.put("fake-object").unwrap();
+        writer.write_all(b"0123456789").unwrap();
+        writer.complete_upload().unwrap();
+        let mut transport = WriteOnceTransport::new(Box::new(memory));
+
+        let mut range = Vec::new();
+        transport
+            .get_range("fake-object", 2, 3)
+            .unwrap()
+            .read_to_end(&mut range)
+            .unwrap();
+        assert_eq!(range, b"234");
+        assert_eq!(transport.get_prefix("fake-object", 4).unwrap(), b"0123");
+    }
+
+    #[test]
+    fn concurrent_puts_complete_once() {
+        let memory = InMemoryTransport::new();
+        let mut transport = WriteOnceTransport::new(Box::new(memory.clone()));
+
+        let mut first = transport.put("fake-object").unwrap();
+        let mut second = transport.put("fake-object").unwrap();
+        first.write_all(b"first").unwrap();
+        second.write_all(b"second").unwrap();
+        first.complete_upload().unwrap();
+        assert_matches!(
+            second.complete_upload().err().unwrap().downcast_ref(),
+            Some(Error::DuplicateWriteInRun(_))
+        );
+        assert_eq!(memory.object("fake-object").unwrap(), b"first");
+    }
+}